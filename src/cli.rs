@@ -0,0 +1,751 @@
+use std::collections::HashSet;
+
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
+
+use crate::config;
+use crate::device;
+use crate::image_utils::metadata::value_name;
+use crate::prompt::{negative_prompt_builder, prompt_builder, prompt_entities};
+use crate::stable_diffusion::{schedulers, stable_diffusion_files};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Cli {
+    /// Preset of the configuration files filling the default arguments, see `fantacat.toml`
+    #[arg(long="preset", global = true)]
+    pub preset: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Generate images of cats from a prompt
+    Generate(GenerateArgs),
+    /// Generate images starting from an existing picture
+    Img2img(Img2ImgArgs),
+    /// Repaint the white areas of a mask over an existing picture
+    Inpaint(InpaintArgs),
+    /// Generate the images of every job of a JSONL or TOML job file, loading the models once
+    Batch(BatchArgs),
+    /// Show how a prompt is split in tokens by the text encoders
+    Tokenize(TokenizeArgs),
+    /// Fetch the model files of a version ahead of a generation
+    Download(DownloadArgs),
+    /// List or prune the model files in the HF hub cache
+    Models(ModelsArgs),
+    /// Print the generation settings recorded in an image
+    Info {
+        image: String,
+    },
+    /// Generate an image again from the settings recorded in it.
+    /// Arguments after `--` override the recorded ones, e.g. `rerun cat.png -- --n_steps 50 --width 768`
+    Rerun {
+        image: String,
+
+        /// Defaults to the name of the image with a `-rerun` suffix
+        #[arg(short='o', long="output")]
+        output: Option<String>,
+
+        #[arg(last = true)]
+        overrides: Vec<String>,
+    },
+    /// Serve generations over a JSON HTTP API, keeping the models loaded between requests
+    Serve(ServeArgs),
+}
+
+/// Options selecting the model and where it runs, shared by every subcommand loading one
+#[derive(Args, Debug, Clone)]
+pub struct ModelArgs {
+    #[arg(long, value_enum, default_value = "v2-1")]
+    pub sd_version: stable_diffusion_files::StableDiffusionVersion,
+
+    /// Model of the registry, see `fantacat-models.toml`, used instead of `--sd_version`
+    #[arg(long="model")]
+    pub model_name: Option<String>,
+
+    /// Device to run on: cpu, cuda, cuda:N or auto (CUDA if available, else CPU)
+    #[arg(long="device", default_value = "auto")]
+    pub device: device::DeviceSelection,
+
+    #[arg(long="use_flash_attn", default_value_t = false, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub use_flash_attn: bool,
+
+    /// Local directory with the layout of the model repositories, e.g. `unet/diffusion_pytorch_model.safetensors`,
+    /// files missing from it come from the HF hub. A diffusers folder with a `model_index.json` must hold every weight file
+    #[arg(long="model_dir")]
+    pub model_dir: Option<String>,
+
+    /// Single `.safetensors` file with the UNet, VAE and text encoder weights under their original names,
+    /// converted once for the `--sd_version` it fine-tunes
+    #[arg(long="checkpoint")]
+    pub checkpoint: Option<String>,
+
+    #[arg(long="unet_weights")]
+    pub unet_weights: Option<String>,
+
+    #[arg(long="vae_weights")]
+    pub vae_weights: Option<String>,
+
+    #[arg(long="clip_weights")]
+    pub clip_weights: Option<String>,
+
+    /// Weights of the second text encoder of SDXL based versions
+    #[arg(long="clip2_weights")]
+    pub clip2_weights: Option<String>,
+
+    /// tokenizer.json of the text encoder
+    #[arg(long="tokenizer")]
+    pub tokenizer: Option<String>,
+
+    #[arg(long="tokenizer2")]
+    pub tokenizer2: Option<String>,
+
+    /// HF hub cache directory, defaults to HF_HOME or ~/.cache/huggingface
+    #[arg(long="hf_cache_dir")]
+    pub hf_cache_dir: Option<String>,
+
+    /// HF hub endpoint, e.g. a mirror, defaults to HF_ENDPOINT or https://huggingface.co
+    #[arg(long="hf_endpoint")]
+    pub hf_endpoint: Option<String>,
+
+    /// Never touch the network, only use local and already cached files
+    #[arg(long="offline", default_value_t = false, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub offline: bool,
+
+    /// Check the SHA-256 of the weights before loading them, against the hub or `fantacat.lock`
+    #[arg(long="verify_weights", default_value_t = false, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub verify_weights: bool,
+}
+
+impl ModelArgs {
+    pub fn model_source(&self) -> stable_diffusion_files::ModelSource {
+        stable_diffusion_files::ModelSource {
+            overrides: stable_diffusion_files::ModelFileOverrides {
+                tokenizer: self.tokenizer.clone(),
+                tokenizer2: self.tokenizer2.clone(),
+                clip: self.clip_weights.clone(),
+                clip2: self.clip2_weights.clone(),
+                unet: self.unet_weights.clone(),
+                vae: self.vae_weights.clone(),
+            },
+            model_dir: self.model_dir.as_ref().map(std::path::PathBuf::from),
+            cache_dir: self.hf_cache_dir.as_ref().map(std::path::PathBuf::from),
+            endpoint: self.hf_endpoint.clone(),
+            offline: self.offline,
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct PromptArgs {
+    #[arg(long="medium")]
+    pub medium: Option<prompt_entities::Medium>,
+
+    #[arg(long="breed")]
+    pub breed: Option<prompt_entities::Breed>,
+
+    #[arg(long="style")]
+    pub style: Option<prompt_entities::Style>,
+
+    #[arg(long="color")]
+    pub color: Option<prompt_entities::Color>,
+
+    #[arg(long="details")]
+    pub details: Option<String>,
+
+    /// Emphasis of the medium in the prompt, e.g. 1.3 to strengthen it or 0.8 to soften it
+    #[arg(long="medium_weight")]
+    pub medium_weight: Option<f64>,
+
+    #[arg(long="breed_weight")]
+    pub breed_weight: Option<f64>,
+
+    #[arg(long="style_weight")]
+    pub style_weight: Option<f64>,
+
+    #[arg(long="color_weight")]
+    pub color_weight: Option<f64>,
+
+    /// Free text the generation is pushed away from, e.g. "extra legs, blurry, text"
+    #[arg(long="negative")]
+    pub negative: Option<String>,
+
+    #[arg(long="exclude_medium")]
+    pub exclude_medium: Vec<prompt_entities::Medium>,
+
+    #[arg(long="exclude_breed")]
+    pub exclude_breed: Vec<prompt_entities::Breed>,
+
+    #[arg(long="exclude_style")]
+    pub exclude_style: Vec<prompt_entities::Style>,
+
+    #[arg(long="exclude_color")]
+    pub exclude_color: Vec<prompt_entities::Color>,
+
+    /// Fail on prompts longer than the text encoder context (77 tokens) instead of encoding them in chunks.
+    /// `--strict_prompt_length=false` turns off a configured strict mode
+    #[arg(long="strict_prompt_length", default_value_t = false, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub strict_prompt_length: bool,
+}
+
+impl PromptArgs {
+    pub fn build_prompt(&self) -> prompt_builder::Prompt {
+        prompt_builder::Prompt::builder()
+            .set_breed(self.breed.clone())
+            .set_color(self.color.clone())
+            .set_details(self.details.clone())
+            .set_medium(self.medium.clone())
+            .set_style(self.style.clone())
+            .set_weights(prompt_builder::EntityWeights {
+                medium: self.medium_weight,
+                style: self.style_weight,
+                color: self.color_weight,
+                breed: self.breed_weight,
+            })
+            .build()
+    }
+
+    pub fn build_negative_prompt(&self) -> negative_prompt_builder::NegativePrompt {
+        negative_prompt_builder::NegativePrompt::builder()
+            .set_breeds(self.exclude_breed.clone())
+            .set_colors(self.exclude_color.clone())
+            .set_details(self.negative.clone())
+            .set_mediums(self.exclude_medium.clone())
+            .set_styles(self.exclude_style.clone())
+            .build()
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SamplingArgs {
+    /// Number of images to generate
+    #[arg(long="n_images", default_value_t = 1)]
+    pub n_images: usize,
+
+    /// Number of images denoised together in a single UNet forward pass
+    #[arg(long="batch_size", default_value_t = 1)]
+    pub batch_size: usize,
+
+    /// Number of diffusion steps
+    #[arg(long="n_steps", default_value_t = 5)]
+    pub n_steps: usize,
+
+    #[arg(long="width", default_value_t = 480)]
+    pub width: usize,
+
+    #[arg(long="height", default_value_t = 480)]
+    pub height: usize,
+
+    #[arg(long="intermediary_images", default_value_t = true, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub intermediary_images: bool,
+
+    #[arg(short='o', long="output")]
+    pub final_image: String,
+
+    /// Sampler, the default one of the stable diffusion version when missing
+    #[arg(long="scheduler", value_enum)]
+    pub scheduler: Option<schedulers::SchedulerKind>,
+
+    #[arg(short='g', long="guidance_scale")]
+    pub guidance_scale: Option<f64>,
+
+    /// JSON manifest of the run, defaults to the output path with a .json extension
+    #[arg(long="manifest")]
+    pub manifest: Option<String>,
+
+    /// Seed of the initial latents and of the noise drawn while sampling, picked at random when missing.
+    /// With several images, image i uses seed + i whatever the batch size
+    #[arg(long="seed")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Args, Debug, Clone)]
+#[command(args_override_self = true)]
+pub struct GenerateArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+
+    #[command(flatten)]
+    pub prompt: PromptArgs,
+
+    #[command(flatten)]
+    pub sampling: SamplingArgs,
+}
+
+#[derive(Args, Debug, Clone)]
+#[command(args_override_self = true)]
+pub struct Img2ImgArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+
+    #[command(flatten)]
+    pub prompt: PromptArgs,
+
+    #[command(flatten)]
+    pub sampling: SamplingArgs,
+
+    /// Input image, the generation starts from a noised version of it
+    #[arg(long="init_image")]
+    pub init_image: String,
+
+    /// How much the init image gets transformed: 0 keeps it untouched, 1 ignores it entirely
+    #[arg(long="strength", default_value_t = 0.8)]
+    pub strength: f64,
+}
+
+#[derive(Args, Debug, Clone)]
+#[command(args_override_self = true)]
+pub struct InpaintArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+
+    #[command(flatten)]
+    pub prompt: PromptArgs,
+
+    #[command(flatten)]
+    pub sampling: SamplingArgs,
+
+    /// Image to repaint
+    #[arg(long="init_image")]
+    pub init_image: String,
+
+    /// Black and white mask, white areas of the init image get repainted
+    #[arg(long="mask_image")]
+    pub mask_image: String,
+
+    /// Noise added to the masked area before repainting it, 1 repaints it from scratch
+    #[arg(long="strength", default_value_t = 1.0)]
+    pub strength: f64,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct BatchArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+
+    /// Job file, `.toml` with `[[job]]` tables or JSONL with a JSON object per line
+    pub jobs: String,
+
+    /// JSON report with the images or the error of every job
+    #[arg(long="report")]
+    pub report: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TokenizeArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+
+    #[command(flatten)]
+    pub prompt: PromptArgs,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum, PartialEq, Eq)]
+pub enum DownloadPrecision {
+    /// Half precision on CUDA and full precision on CPU, as generations load
+    Device,
+    F16,
+    F32,
+    Both,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DownloadArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+
+    #[arg(long="precision", value_enum, default_value = "device")]
+    pub precision: DownloadPrecision,
+
+    /// Record the SHA-256 of the files in `fantacat.lock`, later runs check them against it
+    #[arg(long="write_lock", default_value_t = false, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub write_lock: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ModelsArgs {
+    /// HF hub cache directory, defaults to HF_HOME or ~/.cache/huggingface
+    #[arg(long="hf_cache_dir", global = true)]
+    pub hf_cache_dir: Option<String>,
+
+    #[command(subcommand)]
+    pub command: ModelsCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ModelsCommand {
+    /// Show the cached files of every version and registered model with their size
+    List,
+    /// Remove the cached files none of the kept models loads
+    Prune {
+        /// Built-in version, e.g. `v2-1`, or model of the registry
+        #[arg(long="keep", required = true)]
+        keep: Vec<String>,
+
+        /// Only show what would be removed
+        #[arg(long="dry_run", default_value_t = false)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ServeArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+
+    #[arg(long="host", default_value = "127.0.0.1")]
+    pub host: String,
+
+    #[arg(long="port", default_value_t = 8080)]
+    pub port: u16,
+
+    /// Directory of the generated images, a `fantacat-server` temporary directory by default
+    #[arg(long="output_dir")]
+    pub output_dir: Option<String>,
+
+    /// Most images a single request can ask for
+    #[arg(long="max_images", default_value_t = 10)]
+    pub max_images: usize,
+}
+
+/// Everything a generation needs, gathered from the generate, img2img and inpaint subcommands
+#[derive(Debug, Clone)]
+pub struct DiffusionArgs {
+    pub model: ModelArgs,
+    pub prompt: PromptArgs,
+    pub sampling: SamplingArgs,
+    pub init_image: Option<String>,
+    pub strength: f64,
+    pub mask_image: Option<String>,
+}
+
+impl From<GenerateArgs> for DiffusionArgs {
+    fn from(args: GenerateArgs) -> Self {
+        Self { model: args.model, prompt: args.prompt, sampling: args.sampling, init_image: None, strength: 1.0, mask_image: None }
+    }
+}
+
+impl From<Img2ImgArgs> for DiffusionArgs {
+    fn from(args: Img2ImgArgs) -> Self {
+        Self { model: args.model, prompt: args.prompt, sampling: args.sampling, init_image: Some(args.init_image), strength: args.strength, mask_image: None }
+    }
+}
+
+impl From<InpaintArgs> for DiffusionArgs {
+    fn from(args: InpaintArgs) -> Self {
+        Self { model: args.model, prompt: args.prompt, sampling: args.sampling, init_image: Some(args.init_image), strength: args.strength, mask_image: Some(args.mask_image) }
+    }
+}
+
+impl DiffusionArgs {
+    pub fn subcommand(&self) -> &'static str {
+        match (&self.init_image, &self.mask_image) {
+            (Some(_), Some(_)) => "inpaint",
+            (Some(_), None) => "img2img",
+            _ => "generate"
+        }
+    }
+
+    /// Arguments reproducing a generation, recorded in the images so that `rerun` can parse them back
+    pub fn recorded_arguments(&self, guidance_scale: f64) -> Vec<String> {
+        let mut arguments: Vec<String> = vec![self.subcommand().to_string()];
+        let mut push = |name: &str, value: String| {
+            arguments.push(format!("--{}", name));
+            arguments.push(value);
+        };
+        let prompt = &self.prompt;
+        push("sd_version", value_name(&self.model.sd_version));
+        if let Some(model_name) = &self.model.model_name { push("model", model_name.clone()); }
+        // the weights the image was generated with, a fine-tune gives other images for the same seed
+        let model = &self.model;
+        if let Some(model_dir) = &model.model_dir { push("model_dir", model_dir.clone()); }
+        if let Some(checkpoint) = &model.checkpoint { push("checkpoint", checkpoint.clone()); }
+        if let Some(weights) = &model.unet_weights { push("unet_weights", weights.clone()); }
+        if let Some(weights) = &model.vae_weights { push("vae_weights", weights.clone()); }
+        if let Some(weights) = &model.clip_weights { push("clip_weights", weights.clone()); }
+        if let Some(weights) = &model.clip2_weights { push("clip2_weights", weights.clone()); }
+        push("n_steps", self.sampling.n_steps.to_string());
+        push("width", self.sampling.width.to_string());
+        push("height", self.sampling.height.to_string());
+        push("guidance_scale", guidance_scale.to_string());
+        if let Some(scheduler) = &self.sampling.scheduler { push("scheduler", value_name(scheduler)); }
+        if let Some(medium) = &prompt.medium { push("medium", value_name(medium)); }
+        if let Some(breed) = &prompt.breed { push("breed", value_name(breed)); }
+        if let Some(style) = &prompt.style { push("style", value_name(style)); }
+        if let Some(color) = &prompt.color { push("color", value_name(color)); }
+        if let Some(details) = &prompt.details { push("details", details.clone()); }
+        if let Some(weight) = prompt.medium_weight { push("medium_weight", weight.to_string()); }
+        if let Some(weight) = prompt.breed_weight { push("breed_weight", weight.to_string()); }
+        if let Some(weight) = prompt.style_weight { push("style_weight", weight.to_string()); }
+        if let Some(weight) = prompt.color_weight { push("color_weight", weight.to_string()); }
+        if let Some(negative) = &prompt.negative { push("negative", negative.clone()); }
+        for medium in &prompt.exclude_medium { push("exclude_medium", value_name(medium)); }
+        for breed in &prompt.exclude_breed { push("exclude_breed", value_name(breed)); }
+        for style in &prompt.exclude_style { push("exclude_style", value_name(style)); }
+        for color in &prompt.exclude_color { push("exclude_color", value_name(color)); }
+        if let Some(init_image) = &self.init_image {
+            push("init_image", init_image.clone());
+            push("strength", self.strength.to_string());
+        }
+        if let Some(mask_image) = &self.mask_image { push("mask_image", mask_image.clone()); }
+        if prompt.strict_prompt_length {
+            arguments.push("--strict_prompt_length".to_string());
+        }
+        arguments
+    }
+}
+
+/// Images recorded before the subcommands existed start straight with the flags,
+/// the subcommand is guessed from the image arguments.
+pub fn with_subcommand(arguments: Vec<String>) -> Vec<String> {
+    match arguments.first() {
+        Some(first) if first.starts_with('-') => {
+            let subcommand = if arguments.iter().any(|argument| argument == "--mask_image") {
+                "inpaint"
+            } else if arguments.iter().any(|argument| argument == "--init_image") {
+                "img2img"
+            } else {
+                "generate"
+            };
+            std::iter::once(subcommand.to_string()).chain(arguments).collect()
+        },
+        _ => arguments
+    }
+}
+
+/// Preset selected on the command line, the last one when given several times
+fn selected_preset(arguments: &[String]) -> Option<String> {
+    let mut preset = None;
+    let mut arguments = arguments.iter().skip(1).take_while(|argument| *argument != "--");
+    while let Some(argument) = arguments.next() {
+        if argument == "--preset" {
+            preset = arguments.next().cloned();
+        } else if let Some(value) = argument.strip_prefix("--preset=") {
+            preset = Some(value.to_string());
+        }
+    }
+    preset
+}
+
+fn subcommand_position(arguments: &[String]) -> Option<usize> {
+    let mut idx = 1;
+    while idx < arguments.len() {
+        match arguments[idx].as_str() {
+            "--preset" => idx += 2,
+            argument if argument.starts_with('-') => idx += 1,
+            _ => return Some(idx)
+        }
+    }
+    None
+}
+
+/// Long names of the flags given on the command line after the subcommand, short flags included
+fn explicit_flags(arguments: &[String], subcommand: &clap::Command) -> HashSet<String> {
+    let mut flags = HashSet::new();
+    for argument in arguments.iter().take_while(|argument| *argument != "--") {
+        if let Some(flag) = argument.strip_prefix("--") {
+            flags.insert(flag.split('=').next().unwrap_or(flag).to_string());
+        } else if let Some(short) = argument.strip_prefix('-').and_then(|flag| flag.chars().next()) {
+            let long = subcommand.get_arguments()
+                .find(|arg| arg.get_short() == Some(short))
+                .and_then(|arg| arg.get_long());
+            if let Some(long) = long {
+                flags.insert(long.to_string());
+            }
+        }
+    }
+    flags
+}
+
+/// Inserts the settings of the configuration files right after the subcommand.
+/// Settings of the flags given on the command line are left out: list flags would otherwise
+/// accumulate both values, and a configured boolean flag could not be turned off
+pub fn with_config(arguments: Vec<String>, config: &config::Config) -> anyhow::Result<Vec<String>> {
+    let settings = config.settings(selected_preset(&arguments).as_deref())?;
+    let command = Cli::command();
+    let subcommand = match subcommand_position(&arguments) {
+        Some(idx) => command.find_subcommand(&arguments[idx]).map(|subcommand| (idx, subcommand)),
+        None => None
+    };
+    let (idx, subcommand) = match subcommand {
+        // rerun replays the settings recorded in the image instead
+        Some((_, subcommand)) if subcommand.get_name() == "rerun" => return Ok(arguments),
+        Some(subcommand) => subcommand,
+        None => return Ok(arguments)
+    };
+
+    let long_names = |command: &clap::Command| -> HashSet<String> {
+        command.get_arguments().filter_map(|arg| arg.get_long()).map(str::to_string).collect()
+    };
+    let known: HashSet<String> = command.get_subcommands().flat_map(long_names).collect();
+    let accepted = long_names(subcommand);
+    let explicit = explicit_flags(&arguments[idx + 1..], subcommand);
+
+    let mut config_arguments = vec![];
+    for (name, value) in &settings {
+        if !known.contains(name) {
+            anyhow::bail!("Unknown setting {} in the configuration files", name)
+        }
+        // settings of the other subcommands, e.g. strength for generate, are left out
+        if accepted.contains(name) && !explicit.contains(name) {
+            config_arguments.extend(config::setting_arguments(name, value)?);
+        }
+    }
+
+    let mut arguments = arguments;
+    arguments.splice(idx + 1..idx + 1, config_arguments);
+    Ok(arguments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(arguments: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("fantacat-cli").chain(arguments.iter().copied()))
+    }
+
+    #[test]
+    fn cli_generate() -> anyhow::Result<()> {
+        let cli = parse(&["generate", "-o", "cat.png", "--breed", "maine-coon", "--n_steps", "10", "--n_steps", "20"])?;
+
+        match cli.command {
+            Command::Generate(args) => {
+                assert_eq!(args.sampling.final_image, "cat.png");
+                assert_eq!(args.prompt.breed, Some(prompt_entities::Breed::MaineCoon));
+                // later occurrences override the earlier ones, as rerun relies on
+                assert_eq!(args.sampling.n_steps, 20);
+                assert_eq!(args.model.sd_version, stable_diffusion_files::StableDiffusionVersion::V2_1);
+            },
+            command => panic!("unexpected command {:?}", command)
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cli_inpaint_requires_mask() {
+        assert!(parse(&["inpaint", "-o", "cat.png", "--init_image", "cat.jpg"]).is_err());
+        assert!(parse(&["inpaint", "-o", "cat.png", "--init_image", "cat.jpg", "--mask_image", "mask.png"]).is_ok());
+    }
+
+    #[test]
+    fn cli_recorded_arguments_parse_back() -> anyhow::Result<()> {
+        let cli = parse(&["img2img", "-o", "cat.png", "--init_image", "cat.jpg", "--details", "a (fluffy:1.2) cat", "--exclude_color", "red",
+            "--checkpoint", "fluffy.safetensors", "--vae_weights", "vae.safetensors"])?;
+        let args: DiffusionArgs = match cli.command {
+            Command::Img2img(args) => args.into(),
+            command => panic!("unexpected command {:?}", command)
+        };
+        let mut arguments = args.recorded_arguments(7.5);
+        arguments.extend(["-o".to_string(), "rerun.png".to_string()]);
+
+        let reparsed = Cli::try_parse_from(std::iter::once("fantacat-cli".to_string()).chain(arguments))?;
+        match reparsed.command {
+            Command::Img2img(reparsed) => {
+                assert_eq!(reparsed.init_image, "cat.jpg");
+                assert_eq!(reparsed.prompt.details, args.prompt.details);
+                assert_eq!(reparsed.prompt.exclude_color, vec![prompt_entities::Color::Red]);
+                assert_eq!(reparsed.sampling.guidance_scale, Some(7.5));
+                assert_eq!(reparsed.model.checkpoint.as_deref(), Some("fluffy.safetensors"));
+                assert_eq!(reparsed.model.vae_weights.as_deref(), Some("vae.safetensors"));
+                assert_eq!(reparsed.model.unet_weights, None);
+            },
+            command => panic!("unexpected command {:?}", command)
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cli_with_config() -> anyhow::Result<()> {
+        let config: config::Config = toml::from_str(r#"
+[defaults]
+n_steps = 20
+
+[preset.pixel-kitten]
+medium = "pixel-art"
+strength = 0.5
+"#)?;
+        let arguments = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+
+        let cli = Cli::try_parse_from(with_config(arguments(&["fantacat-cli", "generate", "-o", "cat.png"]), &config)?)?;
+        match cli.command {
+            Command::Generate(args) => {
+                assert_eq!(args.sampling.n_steps, 20);
+                assert!(args.prompt.medium.is_none());
+            },
+            command => panic!("unexpected command {:?}", command)
+        }
+
+        // command line flags override the preset, which overrides the defaults
+        let with_preset = with_config(arguments(&["fantacat-cli", "--preset", "pixel-kitten", "img2img", "-o", "cat.png", "--init_image", "cat.jpg", "--strength", "0.7"]), &config)?;
+        match Cli::try_parse_from(with_preset)?.command {
+            Command::Img2img(args) => {
+                assert_eq!(args.prompt.medium, Some(prompt_entities::Medium::PixelArt));
+                assert_eq!(args.strength, 0.7);
+                assert_eq!(args.sampling.n_steps, 20);
+            },
+            command => panic!("unexpected command {:?}", command)
+        }
+
+        assert!(with_config(arguments(&["fantacat-cli", "generate", "--preset", "missing", "-o", "cat.png"]), &config).is_err());
+
+        let listed: config::Config = toml::from_str(r#"
+[defaults]
+exclude_color = ["red"]
+strict_prompt_length = true
+"#)?;
+        match Cli::try_parse_from(with_config(arguments(&["fantacat-cli", "generate", "-o", "cat.png"]), &listed)?)?.command {
+            Command::Generate(args) => {
+                assert_eq!(args.prompt.exclude_color, vec![prompt_entities::Color::Red]);
+                assert!(args.prompt.strict_prompt_length);
+            },
+            command => panic!("unexpected command {:?}", command)
+        }
+        // a list flag replaces the configured list, a boolean flag can be turned off
+        let overridden = with_config(arguments(&["fantacat-cli", "generate", "-o", "cat.png", "--exclude_color", "white", "--strict_prompt_length=false"]), &listed)?;
+        match Cli::try_parse_from(overridden)?.command {
+            Command::Generate(args) => {
+                assert_eq!(args.prompt.exclude_color, vec![prompt_entities::Color::White]);
+                assert!(!args.prompt.strict_prompt_length);
+            },
+            command => panic!("unexpected command {:?}", command)
+        }
+
+        let typo: config::Config = toml::from_str("[defaults]
+n_step = 20")?;
+        assert!(with_config(arguments(&["fantacat-cli", "generate", "-o", "cat.png"]), &typo).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cli_models() -> anyhow::Result<()> {
+        match parse(&["models", "--hf_cache_dir", "/models", "prune", "--keep", "v2-1", "--keep", "whiskers"])?.command {
+            Command::Models(args) => {
+                assert_eq!(args.hf_cache_dir.as_deref(), Some("/models"));
+                match args.command {
+                    ModelsCommand::Prune { keep, dry_run } => {
+                        assert_eq!(keep, vec!["v2-1", "whiskers"]);
+                        assert!(!dry_run);
+                    },
+                    command => panic!("unexpected command {:?}", command)
+                }
+            },
+            command => panic!("unexpected command {:?}", command)
+        }
+        // pruning everything takes an explicit choice of the models to keep
+        assert!(parse(&["models", "prune"]).is_err());
+        assert!(parse(&["models", "list", "--hf_cache_dir", "/models"]).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn cli_with_subcommand() {
+        let arguments = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(with_subcommand(arguments(&["--n_steps", "5"]))[0], "generate");
+        assert_eq!(with_subcommand(arguments(&["--init_image", "cat.jpg"]))[0], "img2img");
+        assert_eq!(with_subcommand(arguments(&["generate", "--n_steps", "5"])), arguments(&["generate", "--n_steps", "5"]));
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow;
+use serde::Deserialize;
+
+/// Name of the project level configuration file, looked up in the working directory
+pub const PROJECT_CONFIG_FILE: &str = "fantacat.toml";
+
+/// Argument values keyed by their long flag name, e.g. `n_steps = 30` or `exclude_color = ["red"]`
+pub type Settings = BTreeMap<String, toml::Value>;
+
+/// Content of a configuration file:
+///
+/// ```toml
+/// [defaults]
+/// sd_version = "v1-5"
+///
+/// [preset.pixel-kitten]
+/// medium = "pixel-art"
+/// n_steps = 30
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Settings applied to every run
+    #[serde(default)]
+    pub defaults: Settings,
+    /// Named settings applied on top of the defaults with `--preset`
+    #[serde(default)]
+    pub preset: BTreeMap<String, Settings>,
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(p: P) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(p.as_ref())?;
+        toml::from_str(&content)
+            .map_err(|error| anyhow::anyhow!("Invalid configuration file {}: {}", p.as_ref().display(), error))
+    }
+
+    /// Merges `other` on top of this configuration, its settings win key by key
+    pub fn merge(mut self, other: Config) -> Self {
+        self.defaults.extend(other.defaults);
+        for (name, settings) in other.preset {
+            self.preset.entry(name).or_default().extend(settings);
+        }
+        self
+    }
+
+    /// Defaults, overridden by the settings of the preset when one is selected
+    pub fn settings(&self, preset: Option<&str>) -> anyhow::Result<Settings> {
+        let mut settings = self.defaults.clone();
+        if let Some(preset) = preset {
+            match self.preset.get(preset) {
+                Some(preset_settings) => settings.extend(preset_settings.clone()),
+                None => anyhow::bail!(
+                    "Unknown preset {}, available presets: {}",
+                    preset,
+                    self.preset.keys().cloned().collect::<Vec<_>>().join(", ")
+                )
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// User level configuration file, `$XDG_CONFIG_HOME/fantacat/config.toml` or `~/.config/fantacat/config.toml`
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(config_dir) if !config_dir.is_empty() => PathBuf::from(config_dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("fantacat").join("config.toml"))
+}
+
+/// Merged configuration of the user and project files, the project one taking precedence
+pub fn load_config() -> anyhow::Result<Config> {
+    let paths = user_config_path()
+        .into_iter()
+        .chain(std::iter::once(PathBuf::from(PROJECT_CONFIG_FILE)));
+
+    let mut config = Config::default();
+    for path in paths {
+        if path.is_file() {
+            config = config.merge(Config::from_file(&path)?);
+        }
+    }
+    Ok(config)
+}
+
+/// Command line arguments equivalent to a setting, boolean flags take their value after `=`
+pub fn setting_arguments(name: &str, value: &toml::Value) -> anyhow::Result<Vec<String>> {
+    let flag = format!("--{}", name);
+    let arguments = match value {
+        toml::Value::String(value) => vec![flag, value.clone()],
+        toml::Value::Integer(value) => vec![flag, value.to_string()],
+        toml::Value::Float(value) => vec![flag, value.to_string()],
+        toml::Value::Boolean(true) => vec![flag],
+        toml::Value::Boolean(false) => vec![format!("{}=false", flag)],
+        toml::Value::Array(values) => {
+            let mut arguments = vec![];
+            for value in values {
+                if let toml::Value::Array(_) = value {
+                    anyhow::bail!("Setting {} cannot hold nested lists", name)
+                }
+                arguments.extend(setting_arguments(name, value)?);
+            }
+            arguments
+        },
+        _ => anyhow::bail!("Setting {} must be a string, a number, a boolean or a list", name)
+    };
+    Ok(arguments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER_CONFIG: &str = r#"
+[defaults]
+sd_version = "v1-5"
+n_steps = 20
+
+[preset.pixel-kitten]
+medium = "pixel-art"
+width = 512
+"#;
+
+    const PROJECT_CONFIG: &str = r#"
+[defaults]
+n_steps = 30
+
+[preset.pixel-kitten]
+width = 256
+exclude_color = ["red", "blue"]
+"#;
+
+    #[test]
+    fn config_merge_and_settings() -> anyhow::Result<()> {
+        let user: Config = toml::from_str(USER_CONFIG)?;
+        let project: Config = toml::from_str(PROJECT_CONFIG)?;
+        let config = user.merge(project);
+
+        let defaults = config.settings(None)?;
+        assert_eq!(defaults.get("sd_version"), Some(&toml::Value::String("v1-5".to_string())));
+        assert_eq!(defaults.get("n_steps"), Some(&toml::Value::Integer(30)));
+        assert!(defaults.get("medium").is_none());
+
+        let preset = config.settings(Some("pixel-kitten"))?;
+        assert_eq!(preset.get("medium"), Some(&toml::Value::String("pixel-art".to_string())));
+        assert_eq!(preset.get("width"), Some(&toml::Value::Integer(256)));
+        assert_eq!(preset.get("n_steps"), Some(&toml::Value::Integer(30)));
+
+        assert!(config.settings(Some("unknown")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn config_setting_arguments() -> anyhow::Result<()> {
+        assert_eq!(setting_arguments("n_steps", &toml::Value::Integer(30))?, vec!["--n_steps", "30"]);
+        assert_eq!(setting_arguments("guidance_scale", &toml::Value::Float(7.5))?, vec!["--guidance_scale", "7.5"]);
+        assert_eq!(setting_arguments("strict_prompt_length", &toml::Value::Boolean(true))?, vec!["--strict_prompt_length"]);
+        assert_eq!(setting_arguments("strict_prompt_length", &toml::Value::Boolean(false))?, vec!["--strict_prompt_length=false"]);
+
+        let colors = toml::Value::Array(vec![toml::Value::String("red".to_string()), toml::Value::String("blue".to_string())]);
+        assert_eq!(setting_arguments("exclude_color", &colors)?, vec!["--exclude_color", "red", "--exclude_color", "blue"]);
+        Ok(())
+    }
+
+    #[test]
+    fn config_rejects_unknown_sections() {
+        assert!(toml::from_str::<Config>("[presets.pixel-kitten]\nwidth = 256").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use anyhow;
+use candle_core::{Device, DType};
+
+/// Device requested on the command line: `cpu`, `cuda`, `cuda:N` or `auto`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceSelection {
+    Auto,
+    Cpu,
+    Cuda(usize),
+}
+
+impl FromStr for DeviceSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "auto" => Ok(DeviceSelection::Auto),
+            "cpu" => Ok(DeviceSelection::Cpu),
+            "cuda" => Ok(DeviceSelection::Cuda(0)),
+            other => match other.strip_prefix("cuda:") {
+                Some(ordinal) => {
+                    let ordinal = ordinal.parse::<usize>()
+                        .map_err(|_| anyhow::anyhow!("Invalid CUDA ordinal in device '{}'", s))?;
+                    Ok(DeviceSelection::Cuda(ordinal))
+                },
+                None => anyhow::bail!("Unknown device '{}', expected one of cpu, cuda, cuda:N, auto", s)
+            }
+        }
+    }
+}
+
+impl Display for DeviceSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            DeviceSelection::Auto => write!(f, "auto"),
+            DeviceSelection::Cpu => write!(f, "cpu"),
+            DeviceSelection::Cuda(ordinal) => write!(f, "cuda:{}", ordinal)
+        }
+    }
+}
+
+pub fn get_device(selection: &DeviceSelection) -> anyhow::Result<Device> {
+    match selection {
+        DeviceSelection::Cpu => Ok(Device::Cpu),
+        DeviceSelection::Cuda(ordinal) => Ok(Device::new_cuda(*ordinal)?),
+        DeviceSelection::Auto => {
+            // cuda_is_available is always false when built without the cuda feature
+            if !candle_core::utils::cuda_is_available() {
+                return Ok(Device::Cpu);
+            }
+            match Device::new_cuda(0) {
+                Ok(device) => Ok(device),
+                Err(err) => {
+                    println!("CUDA device not usable ({}), falling back to CPU", err);
+                    Ok(Device::Cpu)
+                }
+            }
+        }
+    }
+}
+
+/// Half precision is only worth it (and well supported) on CUDA.
+pub fn get_dtype(device: &Device) -> DType {
+    if device.is_cuda() { DType::F16 } else { DType::F32 }
+}
+
+/// Flash attention needs the `flash-attn` feature and a CUDA device, candle panics at the first UNet forward otherwise
+pub fn check_flash_attn(use_flash_attn: bool, device: &Device) -> anyhow::Result<()> {
+    if !use_flash_attn {
+        return Ok(());
+    }
+    if !cfg!(feature = "flash-attn") {
+        anyhow::bail!("--use_flash_attn requires a build with the flash-attn feature")
+    }
+    if !device.is_cuda() {
+        anyhow::bail!("--use_flash_attn requires a CUDA device, got {:?}", device)
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_check_flash_attn() {
+        assert!(check_flash_attn(false, &Device::Cpu).is_ok());
+        assert!(check_flash_attn(true, &Device::Cpu).is_err());
+    }
+
+    #[test]
+    fn device_selection_from_str() -> anyhow::Result<()> {
+        assert_eq!("cpu".parse::<DeviceSelection>()?, DeviceSelection::Cpu);
+        assert_eq!("auto".parse::<DeviceSelection>()?, DeviceSelection::Auto);
+        assert_eq!("cuda".parse::<DeviceSelection>()?, DeviceSelection::Cuda(0));
+        assert_eq!("CUDA:1".parse::<DeviceSelection>()?, DeviceSelection::Cuda(1));
+        assert!("cuda:x".parse::<DeviceSelection>().is_err());
+        assert!("metal".parse::<DeviceSelection>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn device_selection_display() {
+        assert_eq!(DeviceSelection::Cuda(2).to_string(), "cuda:2");
+        assert_eq!(DeviceSelection::Cpu.to_string(), "cpu");
+    }
+
+    #[test]
+    fn dtype_for_cpu() {
+        assert_eq!(get_dtype(&Device::Cpu), DType::F32);
+    }
+}
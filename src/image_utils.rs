@@ -1,3 +1,3 @@
-
 pub mod preprocessing;
-pub mod save;
\ No newline at end of file
+pub mod save;
+pub mod metadata;
\ No newline at end of file
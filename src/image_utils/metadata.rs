@@ -0,0 +1,231 @@
+use anyhow;
+
+/// Settings an image was generated with, written in the PNG text chunks of every output
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationMetadata {
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub seed: u64,
+    pub steps: usize,
+    pub guidance_scale: f64,
+    /// `default` when the default scheduler of the version was used
+    pub scheduler: String,
+    pub sd_version: String,
+    pub width: usize,
+    pub height: usize,
+    pub software: String,
+    /// Command line arguments that generate this very image again
+    pub arguments: Vec<String>,
+}
+
+pub const SOFTWARE: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// Name clap gives to a value, so that recorded settings can be parsed back as arguments
+pub fn value_name<T: clap::ValueEnum>(value: &T) -> String {
+    value.to_possible_value()
+        .map(|possible_value| possible_value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+impl GenerationMetadata {
+    /// Keyword and text of every chunk, `Software` being the standard PNG keyword
+    pub fn to_text_chunks(&self) -> Vec<(String, String)> {
+        vec![
+            ("prompt".to_string(), self.prompt.clone()),
+            ("negative_prompt".to_string(), self.negative_prompt.clone()),
+            ("seed".to_string(), self.seed.to_string()),
+            ("steps".to_string(), self.steps.to_string()),
+            ("guidance_scale".to_string(), self.guidance_scale.to_string()),
+            ("scheduler".to_string(), self.scheduler.clone()),
+            ("sd_version".to_string(), self.sd_version.clone()),
+            ("width".to_string(), self.width.to_string()),
+            ("height".to_string(), self.height.to_string()),
+            ("Software".to_string(), self.software.clone()),
+            // a JSON array, so that arguments can hold newlines
+            ("arguments".to_string(), serde_json::to_string(&self.arguments).expect("strings always serialize to JSON")),
+        ]
+    }
+
+    pub fn from_text_chunks(chunks: &[(String, String)]) -> anyhow::Result<Self> {
+        let get = |keyword: &str| -> anyhow::Result<String> {
+            chunks.iter()
+                .find(|(chunk_keyword, _)| chunk_keyword == keyword)
+                .map(|(_, text)| text.clone())
+                .ok_or_else(|| anyhow::anyhow!("No '{}' recorded in the image", keyword))
+        };
+        let arguments = get("arguments")?;
+
+        Ok(Self {
+            prompt: get("prompt")?,
+            negative_prompt: get("negative_prompt")?,
+            seed: get("seed")?.parse()?,
+            steps: get("steps")?.parse()?,
+            guidance_scale: get("guidance_scale")?.parse()?,
+            scheduler: get("scheduler")?,
+            sd_version: get("sd_version")?,
+            width: get("width")?.parse()?,
+            height: get("height")?.parse()?,
+            software: get("Software")?,
+            arguments: parse_arguments(&arguments),
+        })
+    }
+
+    /// Same metadata for another image of the run, the recorded arguments get its seed
+    pub fn with_seed(&self, seed: u64) -> Self {
+        let mut arguments = vec![];
+        let mut recorded = self.arguments.iter();
+        while let Some(argument) = recorded.next() {
+            if argument == "--seed" {
+                recorded.next();
+            } else {
+                arguments.push(argument.clone());
+            }
+        }
+        arguments.extend(["--seed".to_string(), seed.to_string()]);
+        Self { seed, arguments, ..self.clone() }
+    }
+
+    /// tEXt chunks only hold latin-1, anything else goes in an UTF-8 iTXt chunk
+    pub fn add_to_png_encoder<W: std::io::Write>(&self, encoder: &mut png::Encoder<W>) -> anyhow::Result<()> {
+        for (keyword, text) in self.to_text_chunks() {
+            if text.is_ascii() {
+                encoder.add_text_chunk(keyword, text)?;
+            } else {
+                encoder.add_itxt_chunk(keyword, text)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Arguments recorded as a JSON array, or one per line by the first versions
+fn parse_arguments(text: &str) -> Vec<String> {
+    match serde_json::from_str::<Vec<String>>(text) {
+        Ok(arguments) => arguments,
+        Err(_) => text.lines().map(|argument| argument.to_string()).collect()
+    }
+}
+
+/// Quotes an argument for a POSIX shell, when it holds anything but plain characters
+pub fn shell_quote(argument: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !argument.is_empty() && argument.chars().all(plain) {
+        argument.to_string()
+    } else {
+        format!("'{}'", argument.replace('\'', "'\\''"))
+    }
+}
+
+/// Every text chunk of a PNG file, whatever its kind
+pub fn read_text_chunks<P: AsRef<std::path::Path>>(p: P) -> anyhow::Result<Vec<(String, String)>> {
+    let file = std::io::BufReader::new(std::fs::File::open(p)?);
+    let reader = png::Decoder::new(file).read_info()?;
+    let info = reader.info();
+
+    let mut chunks = vec![];
+    for chunk in &info.uncompressed_latin1_text {
+        chunks.push((chunk.keyword.clone(), chunk.text.clone()));
+    }
+    for chunk in &info.compressed_latin1_text {
+        chunks.push((chunk.keyword.clone(), chunk.get_text()?));
+    }
+    for chunk in &info.utf8_text {
+        chunks.push((chunk.keyword.clone(), chunk.get_text()?));
+    }
+    Ok(chunks)
+}
+
+/// Generation settings recorded in an image written by fantacat-cli
+pub fn read_metadata<P: AsRef<std::path::Path>>(p: P) -> anyhow::Result<GenerationMetadata> {
+    let p = p.as_ref();
+    let chunks = read_text_chunks(p)?;
+    GenerationMetadata::from_text_chunks(&chunks)
+        .map_err(|err| anyhow::anyhow!("{} was not generated by fantacat-cli: {}", p.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_metadata() -> GenerationMetadata {
+        GenerationMetadata {
+            prompt: " red maine-coon cat  ".to_string(),
+            negative_prompt: "blurry".to_string(),
+            seed: 42,
+            steps: 30,
+            guidance_scale: 7.5,
+            scheduler: "default".to_string(),
+            sd_version: "v2-1".to_string(),
+            width: 512,
+            height: 768,
+            software: SOFTWARE.to_string(),
+            arguments: vec!["--seed".to_string(), "42".to_string(), "--details".to_string(), "sleeping, café".to_string()],
+        }
+    }
+
+    #[test]
+    fn metadata_text_chunks() {
+        let metadata = test_metadata();
+        let chunks = metadata.to_text_chunks();
+
+        assert!(chunks.contains(&("seed".to_string(), "42".to_string())));
+        assert!(chunks.contains(&("guidance_scale".to_string(), "7.5".to_string())));
+        assert!(chunks.contains(&("Software".to_string(), format!("fantacat-cli {}", env!("CARGO_PKG_VERSION")))));
+        let other_image = metadata.with_seed(43);
+        assert_eq!(other_image.seed, 43);
+        assert_eq!(other_image.arguments, vec!["--details", "sleeping, café", "--seed", "43"]);
+    }
+
+    #[test]
+    fn metadata_from_text_chunks() -> anyhow::Result<()> {
+        let metadata = test_metadata();
+        let parsed = GenerationMetadata::from_text_chunks(&metadata.to_text_chunks())?;
+
+        assert_eq!(parsed, metadata);
+        assert!(GenerationMetadata::from_text_chunks(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_multiline_arguments() -> anyhow::Result<()> {
+        let metadata = GenerationMetadata {
+            arguments: vec!["--details".to_string(), "sleeping\non a sofa".to_string(), "--negative".to_string(), "blurry\r\n".to_string()],
+            ..test_metadata()
+        };
+        let parsed = GenerationMetadata::from_text_chunks(&metadata.to_text_chunks())?;
+        assert_eq!(parsed, metadata);
+
+        let mut legacy = test_metadata().to_text_chunks();
+        legacy.retain(|(keyword, _)| keyword != "arguments");
+        legacy.push(("arguments".to_string(), "--seed\n42".to_string()));
+        assert_eq!(GenerationMetadata::from_text_chunks(&legacy)?.arguments, vec!["--seed", "42"]);
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_shell_quote() {
+        assert_eq!(shell_quote("--n_steps"), "--n_steps");
+        assert_eq!(shell_quote("a (fluffy:1.2) cat"), "'a (fluffy:1.2) cat'");
+        assert_eq!(shell_quote("cat's\nsofa"), "'cat'\\''s\nsofa'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn metadata_png_round_trip() -> anyhow::Result<()> {
+        let metadata = test_metadata();
+        let dir = crate::test_utils::TempDir::new("metadata")?;
+        let path = dir.join("cat.png");
+        {
+            let file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+            let mut encoder = png::Encoder::new(file, 2, 2);
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Eight);
+            metadata.add_to_png_encoder(&mut encoder)?;
+            encoder.write_header()?.write_image_data(&[0u8; 12])?;
+        }
+
+        let parsed = read_metadata(&path)?;
+        assert_eq!(parsed, metadata);
+        Ok(())
+    }
+}
@@ -10,6 +10,17 @@ pub fn image_preprocess<T: AsRef<std::path::Path>>(path: T) -> anyhow::Result<Te
     let (height, width) = (img.height() as usize, img.width() as usize);
     let height = height - height % 32;
     let width = width - width % 32;
+    image_to_tensor(img, width, height)
+}
+
+/// Same as `image_preprocess`, but resizes the image to the requested generation size
+/// so the encoded latents match the ones of the diffusion process.
+pub fn image_preprocess_to_size<T: AsRef<std::path::Path>>(path: T, width: usize, height: usize) -> anyhow::Result<Tensor> {
+    let img = image::ImageReader::open(path)?.decode()?;
+    image_to_tensor(img, width, height)
+}
+
+fn image_to_tensor(img: image::DynamicImage, width: usize, height: usize) -> anyhow::Result<Tensor> {
     let img = img.resize_to_fill(
         width as u32,
         height as u32,
@@ -24,3 +35,21 @@ pub fn image_preprocess<T: AsRef<std::path::Path>>(path: T) -> anyhow::Result<Te
         .unsqueeze(0)?;
     Ok(img)
 }
+
+/// Loads a black and white mask resized to the generation size as a (1, 1, height, width) tensor,
+/// with 1 where the image gets repainted (white pixels) and 0 where it is kept (black pixels).
+pub fn mask_preprocess_to_size<T: AsRef<std::path::Path>>(path: T, width: usize, height: usize) -> anyhow::Result<Tensor> {
+    let img = image::ImageReader::open(path)?.decode()?;
+    let img = img.resize_to_fill(
+        width as u32,
+        height as u32,
+        image::imageops::FilterType::Nearest,
+    );
+    let mask: Vec<f32> = img.to_luma8()
+        .into_raw()
+        .into_iter()
+        .map(|pixel| if pixel >= 128 { 1. } else { 0. })
+        .collect();
+    let mask = Tensor::from_vec(mask, (1, 1, height, width), &Device::Cpu)?;
+    Ok(mask)
+}
@@ -2,6 +2,8 @@ use candle_transformers::models::stable_diffusion as sd;
 use candle_core::{DType, Device, IndexOp, Tensor};
 use anyhow::Result;
 
+use crate::image_utils::metadata::GenerationMetadata;
+
 fn output_filename(
     basename: &str,
     sample_idx: usize,
@@ -32,6 +34,15 @@ fn output_filename(
 /// Saves an image to disk using the image crate, this expects an input with shape
 /// (c, height, width).
 pub fn save_image<P: AsRef<std::path::Path>>(img: &Tensor, p: P) -> Result<()> {
+    save_image_with_metadata(img, p, None)
+}
+
+fn is_png(p: &std::path::Path) -> bool {
+    p.extension().map_or(false, |extension| extension.eq_ignore_ascii_case("png"))
+}
+
+/// Same as `save_image`, PNG files also get the generation settings written as text chunks.
+pub fn save_image_with_metadata<P: AsRef<std::path::Path>>(img: &Tensor, p: P, metadata: Option<&GenerationMetadata>) -> Result<()> {
     let p = p.as_ref();
     let (channel, height, width) = img.dims3()?;
     if channel != 3 {
@@ -39,6 +50,16 @@ pub fn save_image<P: AsRef<std::path::Path>>(img: &Tensor, p: P) -> Result<()> {
     }
     let img = img.permute((1, 2, 0))?.flatten_all()?;
     let pixels = img.to_vec1::<u8>()?;
+    if let (Some(metadata), true) = (metadata, is_png(p)) {
+        let file = std::io::BufWriter::new(std::fs::File::create(p)?);
+        let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        metadata.add_to_png_encoder(&mut encoder)?;
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+        return Ok(());
+    }
     let image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>> =
         match image::ImageBuffer::from_raw(width as u32, height as u32, pixels) {
             Some(image) => image,
@@ -49,29 +70,55 @@ pub fn save_image<P: AsRef<std::path::Path>>(img: &Tensor, p: P) -> Result<()> {
 }
 
 
+/// Decodes a batch of latents and saves every image. Images are numbered across the whole run:
+/// the one at position `batch` in the latents is image `first_image_idx + batch + 1` out of `num_samples`.
+/// `metadata` holds the settings of every image of the batch. Returns the paths of the saved images.
+#[allow(clippy::too_many_arguments)]
 pub fn save_batch_encoded_images(
     vae: &sd::vae::AutoEncoderKL,
     latents: &candle_core::Tensor,
     vae_scale: f64,
-    batch_size: usize,
-    idx: usize,
+    first_image_idx: usize,
     final_image: &str,
     num_samples: usize,
     timestep_ids: Option<usize>,
-) -> Result<()> {
+    metadata: Option<&[GenerationMetadata]>,
+) -> Result<Vec<String>> {
+    let batch_size = latents.dim(0)?;
     let images = vae.decode(&(latents / vae_scale)?)?;
     let images = ((images / 2.)? + 0.5)?.to_device(&Device::Cpu)?;
     let images = (images.clamp(0f32, 1.)? * 255.)?.to_dtype(DType::U8)?;
+    let mut image_filenames = Vec::with_capacity(batch_size);
     for batch in 0..batch_size {
         let image = images.i(batch)?;
         let image_filename = output_filename(
             final_image,
-            (batch_size * idx) + batch + 1,
-            batch + num_samples,
+            first_image_idx + batch + 1,
+            num_samples,
             timestep_ids,
         );
         println!("Save image in {}", image_filename);
-        save_image(&image, image_filename)?;
+        let image_metadata = metadata.and_then(|metadata| metadata.get(batch));
+        save_image_with_metadata(&image, &image_filename, image_metadata)?;
+        image_filenames.push(image_filename);
+    }
+    Ok(image_filenames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_output_filename_single_image() {
+        assert_eq!(output_filename("cat.png", 1, 1, None), "cat.png");
+        assert_eq!(output_filename("cat.png", 1, 1, Some(3)), "cat-3.png");
+    }
+
+    #[test]
+    fn save_output_filename_several_images() {
+        assert_eq!(output_filename("cat.png", 2, 4, None), "cat.2.png");
+        assert_eq!(output_filename("cat", 4, 4, None), "cat.4.png");
+        assert_eq!(output_filename("cat.png", 3, 4, Some(5)), "cat.3-5.png");
     }
-    Ok(())
 }
@@ -0,0 +1,159 @@
+use anyhow;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+use crate::cli;
+use crate::config;
+
+/// A job of a batch file: settings keyed by flag names, e.g. `{"breed": "maine-coon", "seed": 42, "output": "cat.png"}`.
+/// The subcommand follows from the settings, `init_image` makes it img2img and `mask_image` inpaint.
+pub type Job = config::Settings;
+
+/// Settings selecting the models, shared by every job of a batch
+const MODEL_SETTINGS: [&str; 16] = [
+    "sd_version", "model", "device", "use_flash_attn", "model_dir", "checkpoint", "unet_weights", "vae_weights", "clip_weights",
+    "clip2_weights", "tokenizer", "tokenizer2", "hf_cache_dir", "hf_endpoint", "offline", "verify_weights",
+];
+
+/// TOML job files list their jobs as `[[job]]` tables
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobFile {
+    #[serde(default)]
+    job: Vec<Job>,
+}
+
+/// Reads a TOML job file, or a JSONL one with a JSON object per line
+pub fn read_jobs<P: AsRef<std::path::Path>>(p: P) -> anyhow::Result<Vec<Job>> {
+    let path = p.as_ref();
+    let content = std::fs::read_to_string(path)?;
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => {
+            let job_file: JobFile = toml::from_str(&content)
+                .map_err(|error| anyhow::anyhow!("Invalid job file {}: {}", path.display(), error))?;
+            Ok(job_file.job)
+        },
+        _ => {
+            let mut jobs = vec![];
+            for (line_idx, line) in content.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let job: Job = serde_json::from_str(line)
+                    .map_err(|error| anyhow::anyhow!("Invalid job at line {} of {}: {}", line_idx + 1, path.display(), error))?;
+                jobs.push(job);
+            }
+            Ok(jobs)
+        }
+    }
+}
+
+/// Arguments of a job, parsed the same way as a generate, img2img or inpaint command line
+/// on top of the configuration files defaults
+pub fn parse_job(job: &Job, config: &config::Config, preset: Option<&str>) -> anyhow::Result<cli::DiffusionArgs> {
+    let mut arguments = vec![];
+    for (name, value) in job {
+        if MODEL_SETTINGS.contains(&name.as_str()) {
+            anyhow::bail!("Jobs share the models of the batch, set {} on the batch command instead", name)
+        }
+        arguments.extend(config::setting_arguments(name, value)?);
+    }
+    let preset_arguments = match preset {
+        Some(preset) => vec!["--preset".to_string(), preset.to_string()],
+        None => vec![]
+    };
+    let arguments = std::iter::once(env!("CARGO_PKG_NAME").to_string())
+        .chain(preset_arguments)
+        .chain(cli::with_subcommand(arguments))
+        .collect();
+
+    match cli::Cli::try_parse_from(cli::with_config(arguments, config)?)?.command {
+        cli::Command::Generate(args) => Ok(args.into()),
+        cli::Command::Img2img(args) => Ok(args.into()),
+        cli::Command::Inpaint(args) => Ok(args.into()),
+        command => anyhow::bail!("Jobs can only generate images, not {:?}", command)
+    }
+}
+
+/// Outcome of a job of a batch
+#[derive(Debug, Clone, Serialize)]
+pub struct JobReport {
+    pub job: usize,
+    pub output: Option<String>,
+    pub images: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl JobReport {
+    pub fn new(job_idx: usize, job: &Job, images: anyhow::Result<Vec<String>>) -> Self {
+        let output = job.get("output").and_then(|output| output.as_str()).map(str::to_string);
+        match images {
+            Ok(images) => Self { job: job_idx, output, images, error: None },
+            Err(error) => Self { job: job_idx, output, images: vec![], error: Some(format!("{:#}", error)) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobs_read_jsonl_and_toml() -> anyhow::Result<()> {
+        let dir = crate::test_utils::TempDir::new("jobs")?;
+        let write_job_file = |name: &str, content: &str| -> anyhow::Result<std::path::PathBuf> {
+            let path = dir.join(name);
+            std::fs::write(&path, content)?;
+            Ok(path)
+        };
+        let jsonl = write_job_file("jobs.jsonl", "{\"breed\": \"maine-coon\", \"seed\": 42, \"output\": \"cat.png\"}\n\n{\"color\": \"red\", \"output\": \"red.png\"}\n")?;
+        let jobs = read_jobs(&jsonl)?;
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].get("seed"), Some(&toml::Value::Integer(42)));
+
+        let toml_jobs = write_job_file("jobs.toml", "[[job]]\nbreed = \"maine-coon\"\noutput = \"cat.png\"\n\n[[job]]\ncolor = \"red\"\noutput = \"red.png\"\n")?;
+        let jobs = read_jobs(&toml_jobs)?;
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[1].get("output"), Some(&toml::Value::String("red.png".to_string())));
+
+        let invalid = write_job_file("invalid.jsonl", "{\"breed\": \"maine-coon\"}\nnot json\n")?;
+        let error = read_jobs(&invalid).unwrap_err();
+        assert!(error.to_string().contains("line 2"));
+        Ok(())
+    }
+
+    #[test]
+    fn jobs_parse_job() -> anyhow::Result<()> {
+        let config: config::Config = toml::from_str("[defaults]\nn_steps = 20")?;
+        let job: Job = serde_json::from_str("{\"breed\": \"maine-coon\", \"seed\": 42, \"output\": \"cat.png\"}")?;
+
+        let args = parse_job(&job, &config, None)?;
+        assert_eq!(args.subcommand(), "generate");
+        assert_eq!(args.sampling.seed, Some(42));
+        assert_eq!(args.sampling.n_steps, 20);
+        assert_eq!(args.sampling.final_image, "cat.png");
+
+        let img2img: Job = serde_json::from_str("{\"init_image\": \"cat.jpg\", \"output\": \"cat.png\"}")?;
+        assert_eq!(parse_job(&img2img, &config, None)?.subcommand(), "img2img");
+
+        let model: Job = serde_json::from_str("{\"sd_version\": \"xl\", \"output\": \"cat.png\"}")?;
+        assert!(parse_job(&model, &config, None).is_err());
+
+        let missing_output: Job = serde_json::from_str("{\"breed\": \"maine-coon\"}")?;
+        assert!(parse_job(&missing_output, &config, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn jobs_report() -> anyhow::Result<()> {
+        let job: Job = serde_json::from_str("{\"output\": \"cat.png\"}")?;
+
+        let report = JobReport::new(3, &job, Err(anyhow::anyhow!("out of memory")));
+        assert_eq!(report.output.as_deref(), Some("cat.png"));
+        assert_eq!(report.error.as_deref(), Some("out of memory"));
+
+        let report = JobReport::new(4, &job, Ok(vec!["cat.png".to_string()]));
+        assert!(report.error.is_none());
+        Ok(())
+    }
+}
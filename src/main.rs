@@ -2,8 +2,7 @@
 use clap::Parser;
 use anyhow::Result;
 use candle_transformers::models::stable_diffusion as sd;
-use candle_core::{Tensor};
-use stable_diffusion::stable_diffusion_files;
+use stable_diffusion::stable_diffusion_files::{self, ModelFileBuild};
 
 
 // use hf_hub::api::tokio::Api;
@@ -11,223 +10,238 @@ use stable_diffusion::stable_diffusion_files;
 mod stable_diffusion;
 mod image_utils;
 mod prompt;
-
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    /// prompt request for model
-    
-
-    /// Number of images to generate
-    #[arg(long="n_images", default_value_t = 1)]
-    n_images: usize,
-
-    /// Number of diffusion steps
-    #[arg(long="n_steps", default_value_t = 5)]
-    n_steps: usize,
-
-    #[arg(long="width", default_value_t = 480)]
-    width: usize,
-
-    #[arg(long="height", default_value_t = 480)]
-    height: usize,
-
-    #[arg(long="intermediary_images", default_value_t = true)]
-    intermediary_images: bool,
-
-    #[arg(short='o', long="output")]
-    final_image: String,
-
-    #[arg(long="use_flash_attn", default_value_t = false)]
-    use_flash_attn: bool,
-
-    #[arg(short='g', long="guidance_scale")]
-    guidance_scale: Option<f64>,
-    
-    #[arg(long, value_enum, default_value = "v2_1")]
-    sd_version: stable_diffusion::stable_diffusion_files::StableDiffusionVersion,
-
-    #[arg(long="medium")]
-    medium: Option<prompt::prompt_entities::Medium>,
-
-    #[arg(long="breed")]
-    breed: Option<prompt::prompt_entities::Breed>,
-
-    #[arg(long="style")]
-    style: Option<prompt::prompt_entities::Style>,
-
-    #[arg(long="color")]
-    color: Option<prompt::prompt_entities::Color>,
-
-    #[arg(long="details")]
-    details: Option<String>,
-
+mod device;
+mod manifest;
+mod cli;
+mod config;
+mod registry;
+mod pipeline;
+mod jobs;
+mod server;
+#[cfg(test)]
+mod test_utils;
+
+fn info(image: &str) -> Result<()> {
+    let chunks = image_utils::metadata::read_text_chunks(image)?;
+    if chunks.is_empty() {
+        println!("No generation settings recorded in {}", image);
+    }
+    // the arguments print as a command line to paste back, whichever format recorded them
+    let metadata = image_utils::metadata::read_metadata(image).ok();
+    for (keyword, text) in chunks {
+        match (keyword.as_str(), &metadata) {
+            ("arguments", Some(metadata)) => {
+                let command_line = std::iter::once(env!("CARGO_PKG_NAME").to_string())
+                    .chain(cli::with_subcommand(metadata.arguments.clone()))
+                    .map(|argument| image_utils::metadata::shell_quote(&argument))
+                    .collect::<Vec<_>>();
+                println!("{}: {}", keyword, command_line.join(" "))
+            },
+            _ => println!("{}: {}", keyword, text)
+        }
+    }
+    Ok(())
 }
 
+fn rerun(image: &str, output: Option<String>, overrides: Vec<String>) -> Result<()> {
+    let metadata = image_utils::metadata::read_metadata(image)?;
+    let output = output.unwrap_or_else(|| {
+        let path = std::path::Path::new(image);
+        let stem = path.file_stem().map_or_else(|| "image".into(), |stem| stem.to_string_lossy());
+        path.with_file_name(format!("{}-rerun.png", stem)).to_string_lossy().to_string()
+    });
+    println!("Re-running {} ({}) into {}", image, metadata.software, output);
+
+    // later occurrences of an argument override the earlier ones
+    let arguments = std::iter::once(env!("CARGO_PKG_NAME").to_string())
+        .chain(cli::with_subcommand(metadata.arguments))
+        .chain(["-o".to_string(), output])
+        .chain(overrides);
+    match cli::Cli::try_parse_from(arguments)?.command {
+        cli::Command::Generate(args) => run_diffusion(args.into()),
+        cli::Command::Img2img(args) => run_diffusion(args.into()),
+        cli::Command::Inpaint(args) => run_diffusion(args.into()),
+        command => anyhow::bail!("{} does not record a generation: {:?}", image, command)
+    }
+}
 
+fn tokenize(args: cli::TokenizeArgs) -> Result<()> {
+    let selected = registry::select_model(&args.model, &registry::load_registry()?)?;
+    let sd_version = selected.sd_version;
+    let sd_config = stable_diffusion_files::get_sd_config_from_version(&sd_version, None, None, None);
+    let prompt = args.prompt.build_prompt().to_string();
+    let negative_prompt = args.prompt.build_negative_prompt().to_string();
 
+    let tokenizer_file = |sd_file| -> Result<Option<String>> {
+        Ok(Some(selected.files.resolve_from(&sd_file, true, &selected.source)?.path.to_string_lossy().to_string()))
+    };
+    let mut tokenizers = vec![(
+        "clip",
+        stable_diffusion::clip_embeddings::get_tokenizer(tokenizer_file(stable_diffusion_files::StableDiffusionFiles::Tokenizer)?, &sd_version)?,
+        &sd_config.clip,
+    )];
+    if let (true, Some(clip2)) = (sd_version.has_second_text_encoder(), &sd_config.clip2) {
+        tokenizers.push((
+            "clip_2",
+            stable_diffusion::clip_embeddings::get_tokenizer2(tokenizer_file(stable_diffusion_files::StableDiffusionFiles::Tokenizer2)?, &sd_version)?,
+            clip2,
+        ));
+    }
+    for (name, text) in [("Prompt", &prompt), ("Negative prompt", &negative_prompt)] {
+        if text.is_empty() {
+            continue;
+        }
+        println!("{}: {}", name, text);
+        for (encoder, tokenizer, clip_config) in &tokenizers {
+            let tokens = stable_diffusion::clip_embeddings::tokenize_prompt(text, tokenizer)?;
+            let n_chunks = stable_diffusion::clip_embeddings::count_chunks(text, tokenizer, clip_config)?;
+            println!("  {}: {} tokens in {} chunk(s) of {}", encoder, tokens.len(), n_chunks, clip_config.max_position_embeddings);
+            for token in tokens {
+                println!("    {:>6} {:<24} {:.2}", token.id, token.text, token.weight);
+            }
+        }
+    }
+    Ok(())
+}
 
-fn run_diffusion(args: Args) -> Result<()> {
-    
-
-    let width = Some(args.width);
-    let height = Some(args.height);
-    let sd_version = args.sd_version;
-    let sd_config = stable_diffusion::stable_diffusion_files::get_sd_config_from_version(&sd_version, None, height, width);
-    let n_steps = args.n_steps; 
-    let device = &candle_core::Device::new_cuda(0)?;
-    // let device = &candle_core::Device::Cpu;
-    let scheduler = sd_config.build_scheduler(n_steps)?;
-    let batch_size = 1;
-    let dtype = candle_core::DType::F16;
-    
-    
-    let t_start = 0 ; // relevant for img2img
-    let guidance_scale = match args.guidance_scale {
-        None => match sd_version {
-            stable_diffusion_files::StableDiffusionVersion::V1_5
-            | stable_diffusion_files::StableDiffusionVersion::V2_1
-            | stable_diffusion_files::StableDiffusionVersion::Xl => 7.5,
-            stable_diffusion_files::StableDiffusionVersion::Turbo => 0.,
+fn download(args: cli::DownloadArgs) -> Result<()> {
+    let precisions = match args.precision {
+        cli::DownloadPrecision::Device => {
+            let device = device::get_device(&args.model.device)?;
+            vec![device::get_dtype(&device) == candle_core::DType::F16]
         },
-        Some(guidance_scale) => guidance_scale
+        cli::DownloadPrecision::F16 => vec![true],
+        cli::DownloadPrecision::F32 => vec![false],
+        cli::DownloadPrecision::Both => vec![true, false],
     };
-    let use_guidance_scale = guidance_scale > 1.0;
-    let color = args.color;
-    let style = args.style;
-    let medium = args.medium;
-    let breed = args.breed;
-    let details = args.details;
-    let prompt_builder = prompt::prompt_builder::PromptBuilder::default();
-    let prompt = prompt_builder.set_breed(breed)
-                                        .set_color(color)
-                                        .set_details(details)
-                                        .set_medium(medium)
-                                        .set_style(style)
-                                        .build();
-
-    let prompt = prompt.to_string();
-    let uncond_prompt = "" ;
-    println!("Generate an image for prompt: {}", prompt);
-    let vae_scale: f64 = stable_diffusion::vae::get_vae_scale(&sd_version);
-
-    
-    
-
-    let embeddings = {
-        let tokenizer = stable_diffusion::clip_embeddings::get_tokenizer(None, &sd_version)?;
-        let encoded_prompt = stable_diffusion::clip_embeddings::encode_prompt(&prompt, &tokenizer, &sd_config, device)?;
-        let embedding_model = stable_diffusion::clip_embeddings::get_embedding_model(None, &sd_config, &sd_version, device)?;
-        if use_guidance_scale {
-            let encoded_uncond_prompt = stable_diffusion::clip_embeddings::encode_prompt(&uncond_prompt, &tokenizer, &sd_config, device)?;
-            stable_diffusion::clip_embeddings::get_embeddings_for_guidance_scale(&encoded_prompt, &encoded_uncond_prompt,&embedding_model)
-        }
-        else {
-            stable_diffusion::clip_embeddings::get_embeddings(&encoded_prompt, &embedding_model)
+    let mut selected = registry::select_model(&args.model, &registry::load_registry()?)?;
+    if let Some(checkpoint) = &args.model.checkpoint {
+        stable_diffusion::checkpoint::apply_checkpoint(&mut selected.source, checkpoint, &selected.sd_version)?;
+    }
+    let source = &selected.source;
+    let mut lock = stable_diffusion::checksums::LockFile::load(stable_diffusion::checksums::LOCK_FILE)?;
+    for use_f16 in precisions {
+        let name = selected.name.clone().unwrap_or_else(|| format!("{:?}", selected.sd_version));
+        println!("{} {}", name, if use_f16 { "fp16" } else { "fp32" });
+        let model_files = stable_diffusion::model_cache::download_model_files(selected.files.as_ref(), &selected.sd_version, use_f16, source)?;
+        // the files were fetched ahead of time, so is the cost of hashing them
+        for (model_file, sha256) in stable_diffusion::checksums::verify_model_files(model_files, source, &lock, true)? {
+            if let Some(sha256) = sha256 {
+                println!("{}: sha256 {}", model_file.component, sha256);
+                if args.write_lock {
+                    lock.sha256.insert(stable_diffusion::checksums::lock_key(&model_file), sha256);
+                }
+            }
         }
-        
-    }?;
-    println!("Embeddings created {:?}.", embeddings.shape());
-    // only needed for turbo and xl since they use different embedding models
-    // let text_embeddings = Tensor::cat(&embeddings, D::Minus1)?;
-    let embeddings = embeddings.repeat((batch_size, 1, 1))?;
-    println!("Batch of embeddings created {:?}.", embeddings.shape());
-
-    let vae = stable_diffusion::vae::get_vae(None, &sd_version, &sd_config, device, dtype)?;
-    println!("VAE created.");
-    let unet = stable_diffusion::unet::get_unet(None, &sd_version, &sd_config, device, dtype, args.use_flash_attn)?;
-    println!("UNet created");
-
-    for idx in 0..args.n_images {
-        println!("Generating image number {}", idx);
-        let timesteps = scheduler.timesteps();
-        println!("Scheduler timesteps instantiated");
-        // randomly generate latent representation of image
-        // TODO: img2img needs different approach
-        let mut latents = Tensor::randn(
-            0f32,
-            1f32,
-            (batch_size, 4, sd_config.height / 8, sd_config.width / 8),
-            &device,
-        )?;
-
-        // scale the initial noise by the standard deviation required by the scheduler
-        latents = (latents * scheduler.init_noise_sigma())?;
-        latents = latents.to_dtype(dtype)?;
-        
-        println!("Latents scaled by standard deviation");
-        println!("Entering diffusion process. Iterating for {:?} timesteps", timesteps);
-
-        for (timestep_index, &timestep) in timesteps.iter().enumerate() {
-            
-            if timestep_index < t_start {
-                continue;
+    }
+    if args.write_lock {
+        lock.save(stable_diffusion::checksums::LOCK_FILE)?;
+        println!("Hashes written to {}", stable_diffusion::checksums::LOCK_FILE);
+    }
+    Ok(())
+}
+
+fn models(args: cli::ModelsArgs) -> Result<()> {
+    let source = stable_diffusion_files::ModelSource {
+        cache_dir: args.hf_cache_dir.map(std::path::PathBuf::from),
+        ..Default::default()
+    };
+    let registry = registry::load_registry()?;
+    let cached = stable_diffusion::model_cache::cached_model_files(&source, &registry)?;
+    match args.command {
+        cli::ModelsCommand::List => {
+            println!("HF hub cache: {}", source.cache().path().display());
+            for cached_file in &cached {
+                println!(
+                    "{:<14} {:<12} {:<5} {:>10}  {}/{}",
+                    cached_file.model,
+                    cached_file.component,
+                    cached_file.precision.as_deref().unwrap_or("-"),
+                    stable_diffusion::model_cache::format_size(cached_file.size),
+                    cached_file.repo,
+                    cached_file.file,
+                );
             }
-            let start_time = std::time::Instant::now();
-
-            let latent_model_input = if use_guidance_scale {
-                // with guidance scale, need to start from duplicated latents
-                // because model will process prompt and unconditional prompt simultaneously
-                Tensor::cat(&[&latents, &latents], 0)?
-            } else {
-                latents.clone()
-            };
-
-            let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep)?;
-
-            let noise_pred =
-                unet.forward(&latent_model_input, timestep as f64, &embeddings)?;
-            
-            let noise_pred = if use_guidance_scale {
-                let noise_pred = noise_pred.chunk(2, 0)?;
-                let (noise_pred_uncond, noise_pred_text) = (&noise_pred[0], &noise_pred[1]);
-
-                (noise_pred_uncond + ((noise_pred_text - noise_pred_uncond)? * guidance_scale)?)?
-            } else {
-                noise_pred
-            };
-
-            latents = scheduler.step(&noise_pred, timestep, &latents)?;
-
-            let dt = start_time.elapsed().as_secs_f32();
-            println!("step {}/{n_steps} done, {:.2}s", timestep_index + 1, dt);
-
-            if args.intermediary_images {
-                image_utils::save::save_batch_encoded_images(
-                    &vae,
-                    &latents,
-                    vae_scale,
-                    batch_size,
-                    idx,
-                    &args.final_image,
-                    args.n_images,
-                    Some(timestep_index + 1),
-                )?;
+        },
+        cli::ModelsCommand::Prune { keep, dry_run } => {
+            stable_diffusion::model_cache::check_model_names(&keep, &registry)?;
+            let candidates = stable_diffusion::model_cache::prune_candidates(&cached, &keep);
+            let mut freed = 0;
+            for candidate in &candidates {
+                println!("{} {}/{} ({})", if dry_run { "Would remove" } else { "Removing" }, candidate.repo, candidate.file, stable_diffusion::model_cache::format_size(candidate.size));
+                if !dry_run {
+                    stable_diffusion::model_cache::remove_cached_file(&candidate.path)?;
+                }
+                freed += candidate.size;
             }
+            println!("{} {}", if dry_run { "Would free" } else { "Freed" }, stable_diffusion::model_cache::format_size(freed));
         }
+    }
+    Ok(())
+}
 
-        println!("Generating final image version for sample {}", idx);
-        image_utils::save::save_batch_encoded_images(
-            &vae,
-            &latents,
-            vae_scale,
-            batch_size,
-            idx,
-            &args.final_image,
-            args.n_images,
-            None
-        )?;
+fn batch(args: cli::BatchArgs, config: &config::Config, preset: Option<&str>) -> Result<()> {
+    let jobs = jobs::read_jobs(&args.jobs)?;
+    println!("Running {} jobs of {}", jobs.len(), args.jobs);
+    let pipeline = pipeline::Pipeline::load(&args.model)?;
+
+    let mut reports = vec![];
+    for (job_idx, job) in jobs.iter().enumerate() {
+        println!("Job {}/{}", job_idx + 1, jobs.len());
+        // a failing job is reported and the batch goes on with the next one
+        let images = jobs::parse_job(job, config, preset).and_then(|mut job_args| {
+            job_args.model = args.model.clone();
+            let run_manifest = pipeline.generate(&job_args)?;
+            Ok(run_manifest.images.into_iter().map(|image| image.path).collect())
+        });
+        let report = jobs::JobReport::new(job_idx, job, images);
+        match &report.error {
+            Some(error) => println!("Job {} failed: {}", job_idx + 1, error),
+            None => println!("Job {} done: {}", job_idx + 1, report.images.join(", "))
+        }
+        reports.push(report);
+    }
 
+    if let Some(report_path) = &args.report {
+        let file = std::io::BufWriter::new(std::fs::File::create(report_path)?);
+        serde_json::to_writer_pretty(file, &reports)?;
+        println!("Report written in {}", report_path);
+    }
+    let n_failed = reports.iter().filter(|report| report.error.is_some()).count();
+    println!("{} jobs succeeded, {} failed", reports.len() - n_failed, n_failed);
+    if n_failed > 0 {
+        anyhow::bail!("{} of {} jobs failed", n_failed, reports.len())
     }
+    Ok(())
+}
+
+fn run_diffusion(args: cli::DiffusionArgs) -> Result<()> {
+    let pipeline = pipeline::Pipeline::load(&args.model)?;
+    pipeline.generate(&args)?;
 
     println!("Finished!");
     Ok(())
-    // println!("{:?}", print_type_of(&weights))
 }
 
 
 fn main() -> Result<()>{
-    let args = Args::parse();
+    let config = config::load_config()?;
+    let arguments = cli::with_config(std::env::args().collect(), &config)?;
+    let args = cli::Cli::parse_from(arguments);
+    if let Some(preset) = &args.preset {
+        println!("Using preset {}", preset);
+    }
 
-    run_diffusion(args)
-}
\ No newline at end of file
+    match args.command {
+        cli::Command::Generate(args) => run_diffusion(args.into()),
+        cli::Command::Img2img(args) => run_diffusion(args.into()),
+        cli::Command::Inpaint(args) => run_diffusion(args.into()),
+        cli::Command::Batch(batch_args) => batch(batch_args, &config, args.preset.as_deref()),
+        cli::Command::Tokenize(args) => tokenize(args),
+        cli::Command::Download(args) => download(args),
+        cli::Command::Models(args) => models(args),
+        cli::Command::Info { image } => info(&image),
+        cli::Command::Rerun { image, output, overrides } => rerun(&image, output, overrides),
+        cli::Command::Serve(serve_args) => server::serve(serve_args, &config, args.preset.as_deref()),
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow;
+use serde::Serialize;
+
+use crate::stable_diffusion::stable_diffusion_files::ResolvedModelFile;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestImage {
+    pub path: String,
+    pub seed: u64,
+    pub batch: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StepTiming {
+    pub batch: usize,
+    pub step: usize,
+    pub timestep: usize,
+    pub seconds: f32,
+}
+
+/// Machine readable record of a run, written next to the images
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Manifest {
+    pub software: String,
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub sd_version: String,
+    pub scheduler: String,
+    pub steps: usize,
+    pub guidance_scale: f64,
+    pub width: usize,
+    pub height: usize,
+    pub batch_size: usize,
+    pub device: String,
+    pub models: Vec<ResolvedModelFile>,
+    pub images: Vec<ManifestImage>,
+    pub step_timings: Vec<StepTiming>,
+    pub total_seconds: f32,
+}
+
+/// Manifest path for an output image: same name with a `.json` extension
+pub fn manifest_path(final_image: &str) -> std::path::PathBuf {
+    std::path::Path::new(final_image).with_extension("json")
+}
+
+impl Manifest {
+    pub fn write<P: AsRef<std::path::Path>>(&self, p: P) -> anyhow::Result<()> {
+        let file = std::io::BufWriter::new(std::fs::File::create(p.as_ref())?);
+        serde_json::to_writer_pretty(file, self)?;
+        println!("Manifest written in {}", p.as_ref().display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_path_from_image() {
+        assert_eq!(manifest_path("out/cat.png"), std::path::PathBuf::from("out/cat.json"));
+        assert_eq!(manifest_path("cat"), std::path::PathBuf::from("cat.json"));
+    }
+
+    #[test]
+    fn manifest_serialize() -> anyhow::Result<()> {
+        let manifest = Manifest {
+            prompt: "red cat".to_string(),
+            images: vec![ManifestImage { path: "cat.1.png".to_string(), seed: 3, batch: 0 }],
+            ..Default::default()
+        };
+        let value = serde_json::to_value(&manifest)?;
+
+        assert_eq!(value["prompt"], "red cat");
+        assert_eq!(value["images"][0]["seed"], 3);
+        Ok(())
+    }
+}
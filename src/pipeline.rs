@@ -0,0 +1,347 @@
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::models::stable_diffusion::vae::AutoEncoderKL;
+
+use crate::cli;
+use crate::device;
+use crate::image_utils;
+use crate::manifest;
+use crate::registry;
+use crate::stable_diffusion::{checkpoint, checksums, clip_embeddings, inpainting, latents, schedulers, sdxl_unet, stable_diffusion_files, unet, vae};
+use crate::stable_diffusion::stable_diffusion_files::{StableDiffusionFiles, StableDiffusionVersion};
+
+/// Models of a stable diffusion version, loaded once and kept resident between generations
+pub struct Pipeline {
+    /// Registered model the weights come from, None for a built-in version
+    pub model_name: Option<String>,
+    /// Architecture of the weights
+    pub sd_version: StableDiffusionVersion,
+    pub device: Device,
+    pub dtype: DType,
+    /// Where the weights were loaded from
+    pub model_files: Vec<stable_diffusion_files::ResolvedModelFile>,
+    vae_scale: f64,
+    guidance_scale: f64,
+    text_encoders: Vec<clip_embeddings::TextEncoder>,
+    vae: AutoEncoderKL,
+    /// Encodes the init images of img2img and inpainting, built by the first run that has one
+    vae_encoder: OnceLock<vae::VaeEncoder>,
+    unet: unet::Unet,
+}
+
+impl Pipeline {
+    pub fn load(model: &cli::ModelArgs) -> Result<Self> {
+        let mut selected = registry::select_model(model, &registry::load_registry()?)?;
+        let sd_version = selected.sd_version;
+        if let Some(checkpoint) = &model.checkpoint {
+            checkpoint::apply_checkpoint(&mut selected.source, checkpoint, &sd_version)?;
+        }
+        // the image size does not change the weights, the default one is enough to build the models
+        let sd_config = stable_diffusion_files::get_sd_config_from_version(&sd_version, None, None, None);
+        let device = device::get_device(&model.device)?;
+        device::check_flash_attn(model.use_flash_attn, &device)?;
+        let dtype = device::get_dtype(&device);
+        println!("Running on {:?} with dtype {:?}", device, dtype);
+
+        // every file is resolved upfront, so that a missing one fails before loading anything
+        let source = &selected.source;
+        let model_files = stable_diffusion_files::resolve_files(selected.files.as_ref(), &sd_version, dtype == DType::F16, source)?;
+        let lock = checksums::LockFile::load(checksums::LOCK_FILE)?;
+        let model_files: Vec<stable_diffusion_files::ResolvedModelFile> = checksums::verify_model_files(model_files, source, &lock, model.verify_weights)?
+            .into_iter()
+            .map(|(model_file, _)| model_file)
+            .collect();
+        let file = |sd_file| stable_diffusion_files::model_file_path(&model_files, &sd_file);
+
+        let text_encoders = clip_embeddings::get_text_encoders(&sd_config, &sd_version, &model_files, &device, dtype)?;
+        println!("Text encoders created.");
+        let vae = vae::get_vae(file(StableDiffusionFiles::Vae), &sd_version, &sd_config, &device, dtype)?;
+        println!("VAE created.");
+        let unet = unet::get_unet(file(StableDiffusionFiles::Unet), &sd_version, &sd_config, &device, dtype, model.use_flash_attn)?;
+        println!("UNet created");
+
+        Ok(Self {
+            model_name: selected.name,
+            sd_version,
+            device,
+            dtype,
+            model_files,
+            vae_scale: selected.vae_scale,
+            guidance_scale: selected.guidance_scale,
+            text_encoders,
+            vae,
+            vae_encoder: OnceLock::new(),
+            unet,
+        })
+    }
+
+    fn vae_encoder(&self) -> Result<&vae::VaeEncoder> {
+        if let Some(vae_encoder) = self.vae_encoder.get() {
+            return Ok(vae_encoder);
+        }
+        let vae_file = stable_diffusion_files::model_file_path(&self.model_files, &StableDiffusionFiles::Vae);
+        let vae_encoder = vae::get_vae_encoder(vae_file, &self.sd_version, &self.vae, &self.device, self.dtype)?;
+        println!("VAE encoder created.");
+        Ok(self.vae_encoder.get_or_init(|| vae_encoder))
+    }
+
+    /// Generates the images of a run and writes its manifest
+    pub fn generate(&self, args: &cli::DiffusionArgs) -> Result<manifest::Manifest> {
+        let run_start = std::time::Instant::now();
+
+        let sd_version = self.sd_version;
+        if args.model.model_name != self.model_name || (self.model_name.is_none() && args.model.sd_version != sd_version) {
+            let requested = args.model.model_name.clone().unwrap_or_else(|| format!("{:?}", args.model.sd_version));
+            let loaded = self.model_name.clone().unwrap_or_else(|| format!("{:?}", sd_version));
+            anyhow::bail!("Generation for {} requested to a pipeline of {}", requested, loaded)
+        }
+        // checked here rather than by the config, which panics
+        if args.sampling.width % 8 != 0 || args.sampling.height % 8 != 0 {
+            anyhow::bail!("Width and height must be multiples of 8, got {}x{}", args.sampling.width, args.sampling.height)
+        }
+        let sd_config = stable_diffusion_files::get_sd_config_from_version(&sd_version, None, Some(args.sampling.height), Some(args.sampling.width));
+        let n_steps = args.sampling.n_steps;
+        let device = &self.device;
+        let dtype = self.dtype;
+
+        if sd_version.is_inpainting() && args.mask_image.is_none() {
+            anyhow::bail!("Inpainting model {:?} requires the inpaint command", sd_version)
+        }
+
+        let t_start = match &args.init_image {
+            Some(_) => latents::get_t_start(n_steps, args.strength)?,
+            None => 0
+        };
+        let guidance_scale = args.sampling.guidance_scale.unwrap_or(self.guidance_scale);
+        let use_guidance_scale = guidance_scale > 1.0;
+        let arguments = args.recorded_arguments(guidance_scale);
+        let final_image = args.sampling.final_image.clone();
+        let prompt = args.prompt.build_prompt().to_string();
+        let negative_prompt = args.prompt.build_negative_prompt();
+        let uncond_prompt = negative_prompt.to_string();
+        println!("Generate an image for prompt: {}", prompt);
+        if !uncond_prompt.is_empty() {
+            if use_guidance_scale {
+                println!("Negative prompt: {}", uncond_prompt);
+            } else {
+                println!("Negative prompt ignored, it requires a guidance scale above 1");
+            }
+        }
+        let vae_scale = self.vae_scale;
+
+        let embeddings = {
+            // SDXL based versions get the hidden states of both text encoders concatenated, and the pooled embeddings of the second
+            let uncond_prompt = if use_guidance_scale { Some(uncond_prompt.as_str()) } else { None };
+            clip_embeddings::get_text_embeddings(&self.text_encoders, &prompt, uncond_prompt, args.prompt.strict_prompt_length, device)
+        }?;
+        println!("Embeddings created {:?}.", embeddings.hidden_states.shape());
+        let vae = &self.vae;
+        let unet = &self.unet;
+
+        let init_image = match &args.init_image {
+            Some(init_image) => {
+                println!("Encoding init image {} (strength {})", init_image, args.strength);
+                let image = image_utils::preprocessing::image_preprocess_to_size(init_image, sd_config.width, sd_config.height)?
+                    .to_device(device)?
+                    .to_dtype(dtype)?;
+                Some(image)
+            },
+            None => None
+        };
+        let init_latent_dist = match &init_image {
+            Some(image) => Some(self.vae_encoder()?.encode(image)?),
+            None => None
+        };
+
+        let inpainting = match (&args.mask_image, &init_image) {
+            (Some(mask_image), Some(image)) => {
+                println!("Inpainting the white areas of mask {}", mask_image);
+                let mask = image_utils::preprocessing::mask_preprocess_to_size(mask_image, sd_config.width, sd_config.height)?
+                    .to_device(device)?;
+                Some(inpainting::Inpainting::new(image, &mask, self.vae_encoder()?, vae_scale, &sd_version, dtype)?)
+            },
+            _ => None
+        };
+
+        if args.sampling.batch_size == 0 {
+            anyhow::bail!("Batch size must be at least 1")
+        }
+        let base_seed = args.sampling.seed.unwrap_or_else(latents::random_seed);
+        let generation_metadata = image_utils::metadata::GenerationMetadata {
+            prompt: prompt.clone(),
+            negative_prompt: if use_guidance_scale { uncond_prompt.clone() } else { String::new() },
+            seed: base_seed,
+            steps: n_steps,
+            guidance_scale,
+            scheduler: args.sampling.scheduler.map_or_else(|| "default".to_string(), |scheduler| scheduler.to_string()),
+            sd_version: image_utils::metadata::value_name(&sd_version),
+            width: sd_config.width,
+            height: sd_config.height,
+            software: image_utils::metadata::SOFTWARE.to_string(),
+            arguments,
+        };
+        let mut run_manifest = manifest::Manifest {
+            software: generation_metadata.software.clone(),
+            prompt: generation_metadata.prompt.clone(),
+            negative_prompt: generation_metadata.negative_prompt.clone(),
+            sd_version: generation_metadata.sd_version.clone(),
+            scheduler: generation_metadata.scheduler.clone(),
+            steps: n_steps,
+            guidance_scale,
+            width: sd_config.width,
+            height: sd_config.height,
+            batch_size: args.sampling.batch_size,
+            device: format!("{:?}", device),
+            models: self.model_files.clone(),
+            ..Default::default()
+        };
+        let n_batches = args.sampling.n_images.div_ceil(args.sampling.batch_size);
+
+        for batch_idx in 0..n_batches {
+            // images are numbered across batches, the last batch can be smaller
+            let first_image_idx = batch_idx * args.sampling.batch_size;
+            let batch_size = args.sampling.batch_size.min(args.sampling.n_images - first_image_idx);
+            let seeds: Vec<u64> = (first_image_idx..first_image_idx + batch_size)
+                .map(|image_idx| latents::derive_seed(base_seed, image_idx))
+                .collect();
+            println!("Generating batch {} with seeds {:?}", batch_idx, seeds);
+            // a fresh scheduler per batch, so that a batch does not depend on the previous ones
+            let mut scheduler = schedulers::build_scheduler(args.sampling.scheduler, &sd_config, &sd_version, n_steps, &seeds)?;
+            let timesteps = scheduler.timesteps().to_vec();
+            println!("Scheduler timesteps instantiated");
+
+            let batch_metadata: Vec<_> = seeds.iter()
+                .map(|seed| generation_metadata.with_seed(*seed))
+                .collect();
+
+            let batch_embeddings = clip_embeddings::repeat_for_batch(&embeddings.hidden_states, batch_size, use_guidance_scale)?;
+            println!("Batch of embeddings created {:?}.", batch_embeddings.shape());
+            // SDXL adds the pooled embeddings and the size of the images to the timestep embedding
+            let added_conditioning = match &embeddings.pooled {
+                Some(pooled) => {
+                    let text_embeds = clip_embeddings::repeat_for_batch(pooled, batch_size, use_guidance_scale)?;
+                    let time_ids = sdxl_unet::add_time_ids(sd_config.height, sd_config.width, text_embeds.dim(0)?, dtype, device)?;
+                    Some(sdxl_unet::AddedConditioning { text_embeds, time_ids })
+                },
+                None => None
+            };
+
+            // randomly generate latent representation of every image, each from its own seed
+            let noise = latents::batch_noise(
+                &seeds,
+                (4, sd_config.height / 8, sd_config.width / 8),
+                device,
+            )?;
+
+            let noise = noise.to_dtype(dtype)?;
+
+            let init_latents = match &init_latent_dist {
+                // every image samples the posterior of the init image from its own seed
+                Some(init_latent_dist) => Some((init_latent_dist.sample(&seeds)? * vae_scale)?.to_dtype(dtype)?),
+                None => None
+            };
+
+            let mut latents = match &init_latents {
+                Some(init_latents) => {
+                    // img2img: start from the encoded image, noised up to the first step that will be run
+                    if t_start >= timesteps.len() {
+                        init_latents.clone()
+                    } else {
+                        scheduler.add_noise(init_latents, noise.clone(), timesteps[t_start])?
+                    }
+                },
+                None => {
+                    // scale the initial noise by the standard deviation required by the scheduler
+                    (&noise * scheduler.init_noise_sigma())?
+                }
+            };
+
+            println!("Latents initialized");
+            println!("Entering diffusion process. Iterating for {:?} timesteps", timesteps);
+
+            for (timestep_index, &timestep) in timesteps.iter().enumerate() {
+            
+                if timestep_index < t_start {
+                    continue;
+                }
+                let start_time = std::time::Instant::now();
+
+                let latent_model_input = if use_guidance_scale {
+                    // with guidance scale, need to start from duplicated latents
+                    // because model will process prompt and unconditional prompt simultaneously
+                    Tensor::cat(&[&latents, &latents], 0)?
+                } else {
+                    latents.clone()
+                };
+
+                let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep)?;
+                let latent_model_input = match &inpainting {
+                    Some(inpainting) => inpainting.unet_input(latent_model_input)?,
+                    None => latent_model_input
+                };
+
+                let noise_pred =
+                    unet.forward(&latent_model_input, timestep as f64, &batch_embeddings, added_conditioning.as_ref())?;
+            
+                let noise_pred = if use_guidance_scale {
+                    let noise_pred = noise_pred.chunk(2, 0)?;
+                    let (noise_pred_uncond, noise_pred_text) = (&noise_pred[0], &noise_pred[1]);
+
+                    (noise_pred_uncond + ((noise_pred_text - noise_pred_uncond)? * guidance_scale)?)?
+                } else {
+                    noise_pred
+                };
+
+                latents = scheduler.step(&noise_pred, timestep, &latents)?;
+
+                if let (Some(inpainting), Some(init_latents)) = (&inpainting, &init_latents) {
+                    let next_timestep = timesteps.get(timestep_index + 1).copied();
+                    latents = inpainting.blend_latents(&latents, init_latents, &noise, scheduler.as_ref(), next_timestep)?;
+                }
+
+                let dt = start_time.elapsed().as_secs_f32();
+                println!("step {}/{n_steps} done, {:.2}s", timestep_index + 1, dt);
+                run_manifest.step_timings.push(manifest::StepTiming { batch: batch_idx, step: timestep_index + 1, timestep, seconds: dt });
+
+                if args.sampling.intermediary_images {
+                    image_utils::save::save_batch_encoded_images(
+                        vae,
+                        &latents,
+                        vae_scale,
+                        first_image_idx,
+                        &final_image,
+                        args.sampling.n_images,
+                        Some(timestep_index + 1),
+                        Some(batch_metadata.as_slice()),
+                    )?;
+                }
+            }
+
+            println!("Generating final image version for batch {}", batch_idx);
+            let image_filenames = image_utils::save::save_batch_encoded_images(
+                vae,
+                &latents,
+                vae_scale,
+                first_image_idx,
+                &final_image,
+                args.sampling.n_images,
+                None,
+                Some(batch_metadata.as_slice()),
+            )?;
+            run_manifest.images.extend(image_filenames.into_iter().zip(seeds).map(|(path, seed)| {
+                manifest::ManifestImage { path, seed, batch: batch_idx }
+            }));
+
+        }
+
+        run_manifest.total_seconds = run_start.elapsed().as_secs_f32();
+        match &args.sampling.manifest {
+            Some(manifest_path) => run_manifest.write(manifest_path)?,
+            None => run_manifest.write(manifest::manifest_path(&final_image))?
+        }
+
+        Ok(run_manifest)
+    }
+}
@@ -1,2 +1,4 @@
 pub mod prompt_builder;
-pub mod prompt_entities;
\ No newline at end of file
+pub mod prompt_entities;
+pub mod negative_prompt_builder;
+pub mod prompt_weights;
\ No newline at end of file
@@ -0,0 +1,114 @@
+use std::string::ToString;
+
+use super::prompt_entities::{Color, Medium, Style, Breed};
+
+
+/// What the generation is pushed away from, used as the unconditional prompt with guidance
+pub struct NegativePrompt {
+    mediums: Vec<Medium>,
+    styles: Vec<Style>,
+    colors: Vec<Color>,
+    breeds: Vec<Breed>,
+    details: Option<String>
+}
+
+
+impl NegativePrompt {
+    pub fn builder() -> NegativePromptBuilder {
+        NegativePromptBuilder::default()
+    }
+
+}
+
+impl ToString for NegativePrompt {
+    fn to_string(&self) -> String {
+        self.styles.iter().map(|s| s.to_string())
+            .chain(self.colors.iter().map(|s| s.to_string()))
+            .chain(self.breeds.iter().map(|s| s.to_string()))
+            .chain(self.mediums.iter().map(|s| s.to_string()))
+            .chain(self.details.iter().cloned())
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[derive(Default)]
+pub struct NegativePromptBuilder {
+    mediums: Vec<Medium>,
+    styles: Vec<Style>,
+    colors: Vec<Color>,
+    breeds: Vec<Breed>,
+    details: Option<String>
+}
+
+impl NegativePromptBuilder {
+
+    pub fn set_mediums(mut self, mediums: Vec<Medium>) -> Self{
+        self.mediums = mediums;
+        self
+    }
+
+    pub fn set_styles(mut self, styles: Vec<Style>) -> Self{
+        self.styles = styles;
+        self
+    }
+
+    pub fn set_colors(mut self, colors: Vec<Color>) -> Self{
+        self.colors = colors;
+        self
+    }
+
+    pub fn set_breeds(mut self, breeds: Vec<Breed>) -> Self{
+        self.breeds = breeds;
+        self
+    }
+
+    pub fn set_details(mut self, details: Option<String>) -> Self{
+        self.details = details;
+        self
+    }
+
+    pub fn build(self) -> NegativePrompt {
+        NegativePrompt {
+            mediums: self.mediums,
+            styles: self.styles,
+            breeds: self.breeds,
+            colors: self.colors,
+            details: self.details
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn negative_prompt_set_breeds(){
+        let builder = NegativePromptBuilder::default();
+
+        let result = builder.set_breeds(vec![Breed::Persian, Breed::Siamese]);
+
+        assert_eq!(result.breeds, vec![Breed::Persian, Breed::Siamese]);
+    }
+
+    #[test]
+    fn negative_prompt_empty(){
+        let negative_prompt = NegativePromptBuilder::default().build();
+
+        assert_eq!("".to_string(), negative_prompt.to_string());
+    }
+
+    #[test]
+    fn negative_prompt_to_string(){
+        let negative_prompt = NegativePromptBuilder::default()
+                                            .set_styles(vec![Style::Anime])
+                                            .set_colors(vec![Color::Red, Color::Black])
+                                            .set_mediums(vec![Medium::PixelArt])
+                                            .set_details(Some(String::from("extra legs, blurry, text")))
+                                            .build();
+        assert_eq!("anime, red, black, pixel-art, extra legs, blurry, text".to_string(), negative_prompt.to_string());
+    }
+}
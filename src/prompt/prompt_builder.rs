@@ -1,14 +1,25 @@
 use std::string::ToString;
 
 use super::prompt_entities::{Color, Medium, Style, Breed};
+use super::prompt_weights::with_weight;
 
 
+/// Emphasis of each entity in the prompt, rendered with the `(entity:weight)` syntax
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct EntityWeights {
+    pub medium: Option<f64>,
+    pub style: Option<f64>,
+    pub color: Option<f64>,
+    pub breed: Option<f64>,
+}
+
 pub struct Prompt {
     medium: Option<Medium>,
     style: Option<Style>,    
     color: Option<Color>,
     breed: Option<Breed>,
-    details: Option<String>
+    details: Option<String>,
+    weights: EntityWeights
 }
 
 
@@ -22,10 +33,10 @@ impl Prompt {
 impl ToString for Prompt {
     fn to_string(&self) -> String {
         format!("{} {} {} cat {} {}", 
-            self.style.as_ref().map_or_else(String::default, |s| s.to_string()), 
-            self.color.as_ref().map_or_else(String::default, |s| s.to_string()),
-            self.breed.as_ref().map_or_else(String::default, |s| s.to_string()), 
-            self.medium.as_ref().map_or_else(String::default, |s| s.to_string()),
+            with_weight(&self.style.as_ref().map_or_else(String::default, |s| s.to_string()), self.weights.style), 
+            with_weight(&self.color.as_ref().map_or_else(String::default, |s| s.to_string()), self.weights.color),
+            with_weight(&self.breed.as_ref().map_or_else(String::default, |s| s.to_string()), self.weights.breed), 
+            with_weight(&self.medium.as_ref().map_or_else(String::default, |s| s.to_string()), self.weights.medium),
             self.details.as_ref().map_or_else(String::default, |s| s.to_string()))
     }
 }
@@ -36,7 +47,8 @@ pub struct PromptBuilder {
     style: Option<Style>,    
     color: Option<Color>,
     breed: Option<Breed>,
-    details: Option<String>
+    details: Option<String>,
+    weights: EntityWeights
 }
 
 impl PromptBuilder {
@@ -66,13 +78,19 @@ impl PromptBuilder {
         self
     }
 
+    pub fn set_weights(mut self, weights: EntityWeights) -> Self{
+        self.weights = weights;
+        self
+    }
+
     pub fn build(self) -> Prompt {
         Prompt {
             medium: self.medium,
             style: self.style,
             breed: self.breed,
             color: self.color,
-            details: self.details
+            details: self.details,
+            weights: self.weights
         }
     }
 }
@@ -145,4 +163,15 @@ mod tests {
                                             .build();
         assert_eq!(" red maine-coon cat oil-painting high quality".to_string(), prompt.to_string());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn prompt_to_string_with_weights(){
+        let prompt_builder = PromptBuilder::default();
+
+        let prompt = prompt_builder.set_style(Some(Style::Anime))
+                                            .set_breed(Some(Breed::MaineCoon))
+                                            .set_weights(EntityWeights { breed: Some(1.3), style: Some(0.8), ..Default::default() })
+                                            .build();
+        assert_eq!("(anime:0.8)  (maine-coon:1.3) cat  ".to_string(), prompt.to_string());
+    }
+}
@@ -0,0 +1,147 @@
+/// Weight multiplier of `(text)`, `[text]` divides by it
+pub const EMPHASIS_MULTIPLIER: f64 = 1.1;
+
+fn flush_text(text: &mut String, segments: &mut Vec<(String, f64)>) {
+    if !text.is_empty() {
+        segments.push((std::mem::take(text), 1.0));
+    }
+}
+
+fn multiply_weights(segments: &mut [(String, f64)], multiplier: f64) {
+    for (_, weight) in segments.iter_mut() {
+        *weight *= multiplier;
+    }
+}
+
+/// Splits a prompt written with the emphasis syntax into segments with their weight:
+/// `(text:1.4)` sets an explicit weight, `(text)` multiplies it by 1.1 and `[text]` divides it by 1.1.
+/// Groups can be nested, brackets are escaped with a backslash and unclosed groups extend to the end of the prompt.
+pub fn parse_weighted_prompt(prompt: &str) -> Vec<(String, f64)> {
+    let mut segments: Vec<(String, f64)> = vec![];
+    // index of the first segment inside each open group
+    let mut round_brackets: Vec<usize> = vec![];
+    let mut square_brackets: Vec<usize> = vec![];
+    let mut text = String::new();
+
+    let mut chars = prompt.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    text.push(escaped);
+                }
+            },
+            '(' => {
+                flush_text(&mut text, &mut segments);
+                round_brackets.push(segments.len());
+            },
+            '[' => {
+                flush_text(&mut text, &mut segments);
+                square_brackets.push(segments.len());
+            },
+            ')' if !round_brackets.is_empty() => {
+                let mut multiplier = EMPHASIS_MULTIPLIER;
+                if let Some((inner, weight)) = text.rsplit_once(':') {
+                    if let Ok(weight) = weight.trim().parse::<f64>() {
+                        multiplier = weight;
+                        text = inner.to_string();
+                    }
+                }
+                flush_text(&mut text, &mut segments);
+                let start = round_brackets.pop().unwrap_or_default();
+                multiply_weights(&mut segments[start..], multiplier);
+            },
+            ']' if !square_brackets.is_empty() => {
+                flush_text(&mut text, &mut segments);
+                let start = square_brackets.pop().unwrap_or_default();
+                multiply_weights(&mut segments[start..], 1. / EMPHASIS_MULTIPLIER);
+            },
+            _ => text.push(c)
+        }
+    }
+    flush_text(&mut text, &mut segments);
+
+    for start in round_brackets {
+        multiply_weights(&mut segments[start..], EMPHASIS_MULTIPLIER);
+    }
+    for start in square_brackets {
+        multiply_weights(&mut segments[start..], 1. / EMPHASIS_MULTIPLIER);
+    }
+
+    // merge neighbours with the same weight, so that they get tokenized together
+    let mut merged: Vec<(String, f64)> = vec![];
+    for (text, weight) in segments {
+        match merged.last_mut() {
+            Some((last_text, last_weight)) if *last_weight == weight => last_text.push_str(&text),
+            _ => merged.push((text, weight))
+        }
+    }
+    merged
+}
+
+/// Wraps `text` in the emphasis syntax, unless the weight is neutral
+pub fn with_weight(text: &str, weight: Option<f64>) -> String {
+    match weight {
+        Some(weight) if weight != 1.0 && !text.is_empty() => format!("({}:{})", text, weight),
+        _ => text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights_plain_prompt(){
+        let segments = parse_weighted_prompt("a cat on a sofa");
+
+        assert_eq!(segments, vec![("a cat on a sofa".to_string(), 1.0)]);
+    }
+
+    #[test]
+    fn weights_explicit_weight(){
+        let segments = parse_weighted_prompt("a cat, (fluffy tail:1.4), sofa");
+
+        assert_eq!(segments, vec![
+            ("a cat, ".to_string(), 1.0),
+            ("fluffy tail".to_string(), 1.4),
+            (", sofa".to_string(), 1.0),
+        ]);
+    }
+
+    #[test]
+    fn weights_emphasis_and_deemphasis(){
+        let segments = parse_weighted_prompt("(cat) [background]");
+
+        assert_eq!(segments.len(), 3);
+        assert!((segments[0].1 - 1.1).abs() < 1e-9);
+        assert_eq!(segments[1], (" ".to_string(), 1.0));
+        assert!((segments[2].1 - 1. / 1.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn weights_nested_and_escaped(){
+        let segments = parse_weighted_prompt("((cat:2)) \\(not weighted\\)");
+
+        assert_eq!(segments[0].0, "cat");
+        assert!((segments[0].1 - 2.2).abs() < 1e-9);
+        assert_eq!(segments[1], (" (not weighted)".to_string(), 1.0));
+    }
+
+    #[test]
+    fn weights_unclosed_group(){
+        let segments = parse_weighted_prompt("cat (fluffy");
+
+        assert_eq!(segments[0], ("cat ".to_string(), 1.0));
+        assert_eq!(segments[1].0, "fluffy");
+        assert!((segments[1].1 - 1.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn weights_with_weight(){
+        assert_eq!(with_weight("maine-coon", Some(1.3)), "(maine-coon:1.3)");
+        assert_eq!(with_weight("maine-coon", Some(1.0)), "maine-coon");
+        assert_eq!(with_weight("maine-coon", None), "maine-coon");
+        assert_eq!(with_weight("", Some(1.3)), "");
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::cli;
+use crate::config;
+use crate::stable_diffusion::stable_diffusion_files::{self, ModelFileBuild, ModelSource, StableDiffusionFiles, StableDiffusionVersion};
+use crate::stable_diffusion::{diffusers_folder, vae};
+
+/// Name of the project level model registry, looked up in the working directory
+pub const PROJECT_REGISTRY_FILE: &str = "fantacat-models.toml";
+
+/// Models declared on top of the built-in versions, selected with `--model`:
+///
+/// ```toml
+/// [model.whiskers]
+/// base = "v1-5"
+/// repo = "someone/whiskers-diffusion"
+/// guidance_scale = 6.0
+///
+/// [model.whiskers.unet]
+/// file = "unet/whiskers.safetensors"
+/// file_fp16 = "unet/whiskers.fp16.safetensors"
+///
+/// [model.tabby-xl]
+/// base = "xl"
+/// dir = "/models/tabby-xl"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Registry {
+    #[serde(default)]
+    pub model: BTreeMap<String, RegisteredModel>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegisteredModel {
+    /// Built-in version whose architecture and configuration the weights follow, e.g. `v1-5` or `xl`
+    pub base: String,
+    /// Hub repository of every component without one of its own
+    pub repo: Option<String>,
+    /// Local directory with the layout of the repositories, looked up before the hub
+    pub dir: Option<PathBuf>,
+    /// Defaults to the one of the base version
+    pub vae_scale: Option<f64>,
+    /// Defaults to the one of the base version
+    pub guidance_scale: Option<f64>,
+    #[serde(default)]
+    pub tokenizer: ComponentFiles,
+    #[serde(default)]
+    pub tokenizer2: ComponentFiles,
+    #[serde(default)]
+    pub clip: ComponentFiles,
+    #[serde(default)]
+    pub clip2: ComponentFiles,
+    #[serde(default)]
+    pub unet: ComponentFiles,
+    #[serde(default)]
+    pub vae: ComponentFiles,
+}
+
+/// Where a component comes from, each field defaulting to the model or base version
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ComponentFiles {
+    pub repo: Option<String>,
+    /// Path in the repository, or in the model directory
+    pub file: Option<String>,
+    /// Half precision variant, `file` is converted when missing
+    pub file_fp16: Option<String>,
+}
+
+impl Registry {
+    pub fn from_file<P: AsRef<Path>>(p: P) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(p.as_ref())?;
+        toml::from_str(&content)
+            .map_err(|error| anyhow::anyhow!("Invalid model registry {}: {}", p.as_ref().display(), error))
+    }
+
+    /// Models of `other` replace the ones of the same name
+    pub fn merge(mut self, other: Registry) -> Self {
+        self.model.extend(other.model);
+        self
+    }
+}
+
+impl RegisteredModel {
+    pub fn base_version(&self) -> anyhow::Result<StableDiffusionVersion> {
+        StableDiffusionVersion::from_str(&self.base, false)
+            .map_err(|_| anyhow::anyhow!("Unknown base version {}", self.base))
+    }
+
+    fn component(&self, sd_file: &StableDiffusionFiles) -> &ComponentFiles {
+        match sd_file {
+            StableDiffusionFiles::Tokenizer => &self.tokenizer,
+            StableDiffusionFiles::Tokenizer2 => &self.tokenizer2,
+            StableDiffusionFiles::Clip => &self.clip,
+            StableDiffusionFiles::Clip2 => &self.clip2,
+            StableDiffusionFiles::Unet => &self.unet,
+            StableDiffusionFiles::Vae => &self.vae,
+        }
+    }
+}
+
+/// Files of a registered model, falling back on the ones of its base version
+pub struct RegisteredModelFiles {
+    model: RegisteredModel,
+    base: Box<dyn ModelFileBuild>,
+}
+
+impl RegisteredModelFiles {
+    pub fn new(model: RegisteredModel) -> anyhow::Result<Self> {
+        let base = stable_diffusion_files::create_sd_from_version(&model.base_version()?);
+        Ok(Self { model, base })
+    }
+
+    /// Fine-tunes rarely ship tokenizers, the ones of the base version fit them
+    fn uses_base_repo(&self, sd_file: &StableDiffusionFiles) -> bool {
+        self.model.component(sd_file).repo.is_none()
+            && (self.model.repo.is_none() || matches!(sd_file, StableDiffusionFiles::Tokenizer | StableDiffusionFiles::Tokenizer2))
+    }
+}
+
+impl ModelFileBuild for RegisteredModelFiles {
+    fn get_repo_with_precision(&self, sd_file: &StableDiffusionFiles, use_f16: Option<bool>) -> &str {
+        match (&self.model.component(sd_file).repo, &self.model.repo) {
+            (Some(repo), _) => repo.as_str(),
+            _ if self.uses_base_repo(sd_file) => self.base.get_repo_with_precision(sd_file, use_f16),
+            (None, Some(repo)) => repo.as_str(),
+            (None, None) => self.base.get_repo_with_precision(sd_file, use_f16),
+        }
+    }
+
+    fn get_filepath(&self, sd_file: &StableDiffusionFiles, use_f16: bool) -> &str {
+        let component = self.model.component(sd_file);
+        let file = if use_f16 { component.file_fp16.as_ref().or(component.file.as_ref()) } else { component.file.as_ref() };
+        match file {
+            Some(file) => file.as_str(),
+            None if self.uses_base_repo(sd_file) => self.base.get_filepath(sd_file, use_f16),
+            // the base may take a component from elsewhere, e.g. the fp16 VAE of SDXL
+            None => stable_diffusion_files::diffusers_filepath(sd_file, use_f16),
+        }
+    }
+}
+
+/// User level registry, `models.toml` next to the user configuration file
+pub fn user_registry_path() -> Option<PathBuf> {
+    Some(config::user_config_path()?.with_file_name("models.toml"))
+}
+
+/// Merged registry of the user and project files, the project one taking precedence
+pub fn load_registry() -> anyhow::Result<Registry> {
+    let paths = user_registry_path()
+        .into_iter()
+        .chain(std::iter::once(PathBuf::from(PROJECT_REGISTRY_FILE)));
+
+    let mut registry = Registry::default();
+    for path in paths {
+        if path.is_file() {
+            registry = registry.merge(Registry::from_file(&path)?);
+        }
+    }
+    Ok(registry)
+}
+
+/// The model a run loads, a registered one or a built-in version
+pub struct SelectedModel {
+    /// Name in the registry, None for a built-in version
+    pub name: Option<String>,
+    /// Architecture of the weights
+    pub sd_version: StableDiffusionVersion,
+    pub files: Box<dyn ModelFileBuild>,
+    pub source: ModelSource,
+    pub vae_scale: f64,
+    pub guidance_scale: f64,
+}
+
+pub fn select_model(model: &cli::ModelArgs, registry: &Registry) -> anyhow::Result<SelectedModel> {
+    let mut source = model.model_source();
+    let (name, sd_version, files, vae_scale, guidance_scale) = match &model.model_name {
+        None => (None, model.sd_version, stable_diffusion_files::create_sd_from_version(&model.sd_version), None, None),
+        Some(name) => {
+            let registered = match registry.model.get(name) {
+                Some(registered) => registered.clone(),
+                None => anyhow::bail!(
+                    "Unknown model {}, registered models: {}",
+                    name,
+                    registry.model.keys().cloned().collect::<Vec<_>>().join(", ")
+                )
+            };
+            // --model_dir still wins over the directory of the registry
+            if source.model_dir.is_none() {
+                source.model_dir = registered.dir.clone();
+            }
+            let (vae_scale, guidance_scale) = (registered.vae_scale, registered.guidance_scale);
+            let sd_version = registered.base_version()?;
+            let files: Box<dyn ModelFileBuild> = Box::new(RegisteredModelFiles::new(registered)?);
+            (Some(name.clone()), sd_version, files, vae_scale, guidance_scale)
+        }
+    };
+
+    // a diffusers folder tells its own VAE scale, after the registry
+    let mut folder_vae_scale = None;
+    if let Some(model_dir) = &source.model_dir {
+        if let Some(folder) = diffusers_folder::DiffusersFolder::open(model_dir)? {
+            folder.check_version(&sd_version)?;
+            folder_vae_scale = folder.vae_config()?.scaling_factor;
+        }
+    }
+
+    Ok(SelectedModel {
+        name,
+        sd_version,
+        files,
+        source,
+        vae_scale: vae_scale.or(folder_vae_scale).unwrap_or_else(|| vae::get_vae_scale(&sd_version)),
+        guidance_scale: guidance_scale.unwrap_or_else(|| sd_version.default_guidance_scale()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use crate::stable_diffusion::constants;
+
+    const REGISTRY: &str = r#"
+[model.whiskers]
+base = "v1-5"
+repo = "someone/whiskers-diffusion"
+guidance_scale = 6.0
+
+[model.whiskers.unet]
+file = "unet/whiskers.safetensors"
+
+[model.tabby-xl]
+base = "xl"
+dir = "/models/tabby-xl"
+vae_scale = 0.13025
+"#;
+
+    #[test]
+    fn registry_model_files() -> anyhow::Result<()> {
+        let registry: Registry = toml::from_str(REGISTRY)?;
+        let whiskers = RegisteredModelFiles::new(registry.model["whiskers"].clone())?;
+
+        assert_eq!(whiskers.get_repo(&StableDiffusionFiles::Unet), "someone/whiskers-diffusion");
+        // the fp32 file is used in half precision too
+        assert_eq!(whiskers.get_filepath(&StableDiffusionFiles::Unet, true), "unet/whiskers.safetensors");
+        assert_eq!(whiskers.get_filepath(&StableDiffusionFiles::Vae, true), constants::MODELFILE_VAE_FP16);
+        assert_eq!(whiskers.get_repo(&StableDiffusionFiles::Tokenizer), constants::REPO_TOKENIZER);
+
+        let tabby = RegisteredModelFiles::new(registry.model["tabby-xl"].clone())?;
+        assert_eq!(tabby.get_repo_with_precision(&StableDiffusionFiles::Vae, Some(true)), constants::REPO_VAE_X1TURBO_FP16);
+        assert_eq!(tabby.get_repo(&StableDiffusionFiles::Clip2), constants::REPO_X1);
+        Ok(())
+    }
+
+    #[test]
+    fn registry_select_model() -> anyhow::Result<()> {
+        let registry: Registry = toml::from_str(REGISTRY)?;
+        let model_args = |arguments: &[&str]| -> anyhow::Result<cli::ModelArgs> {
+            let arguments = ["fantacat-cli", "download"].iter().chain(arguments.iter());
+            match cli::Cli::try_parse_from(arguments)?.command {
+                cli::Command::Download(args) => Ok(args.model),
+                command => anyhow::bail!("unexpected command {:?}", command)
+            }
+        };
+
+        let tabby = select_model(&model_args(&["--model", "tabby-xl"])?, &registry)?;
+        assert_eq!(tabby.name.as_deref(), Some("tabby-xl"));
+        assert_eq!(tabby.sd_version, StableDiffusionVersion::Xl);
+        assert_eq!(tabby.vae_scale, 0.13025);
+        assert_eq!(tabby.guidance_scale, 7.5);
+        assert_eq!(tabby.source.model_dir, Some(PathBuf::from("/models/tabby-xl")));
+
+        let whiskers = select_model(&model_args(&["--model", "whiskers", "--model_dir", "/mirror"])?, &registry)?;
+        assert_eq!(whiskers.guidance_scale, 6.0);
+        assert_eq!(whiskers.source.model_dir, Some(PathBuf::from("/mirror")));
+
+        let builtin = select_model(&model_args(&["--sd_version", "turbo"])?, &registry)?;
+        assert!(builtin.name.is_none());
+        assert_eq!(builtin.guidance_scale, 0.);
+
+        assert!(select_model(&model_args(&["--model", "missing"])?, &registry).is_err());
+        let unknown_base: Registry = toml::from_str("[model.cat]\nbase = \"v3\"")?;
+        assert!(select_model(&model_args(&["--model", "cat"])?, &unknown_base).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn registry_sdxl_folder_vae_scale() -> anyhow::Result<()> {
+        let dir = crate::test_utils::TempDir::new("sdxl-folder")?;
+        let write = |file: &str, content: &str| -> anyhow::Result<()> {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            Ok(std::fs::write(path, content)?)
+        };
+        write(diffusers_folder::MODEL_INDEX_FILE, r#"{
+            "_class_name": "StableDiffusionXLPipeline",
+            "text_encoder": ["transformers", "CLIPTextModel"],
+            "text_encoder_2": ["transformers", "CLIPTextModelWithProjection"],
+            "unet": ["diffusers", "UNet2DConditionModel"],
+            "vae": ["diffusers", "AutoencoderKL"]
+        }"#)?;
+        write("unet/config.json", r#"{"in_channels": 4, "cross_attention_dim": 2048}"#)?;
+        write("vae/config.json", r#"{"scaling_factor": 0.13025}"#)?;
+
+        let model_args = |arguments: &[&str]| -> anyhow::Result<cli::ModelArgs> {
+            let arguments = ["fantacat-cli", "download"].iter().chain(arguments.iter());
+            match cli::Cli::try_parse_from(arguments)?.command {
+                cli::Command::Download(args) => Ok(args.model),
+                command => anyhow::bail!("unexpected command {:?}", command)
+            }
+        };
+        let registry = Registry::default();
+        let hub = select_model(&model_args(&["--sd_version", "xl"])?, &registry)?;
+        let folder = select_model(&model_args(&["--sd_version", "xl", "--model_dir", dir.path().to_str().unwrap()])?, &registry)?;
+
+        // the same weights decode the same, wherever they come from
+        assert_eq!(hub.vae_scale, 0.13025);
+        assert_eq!(folder.vae_scale, hub.vae_scale);
+        Ok(())
+    }
+}
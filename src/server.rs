@@ -0,0 +1,240 @@
+pub mod queue;
+pub mod openai;
+pub mod automatic1111;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+
+use crate::cli;
+use crate::config;
+use crate::jobs;
+use crate::pipeline;
+
+/// Settings pointing at files of the server, the clients cannot set them
+const SERVER_SETTINGS: [&str; 5] = ["output", "manifest", "init_image", "mask_image", "intermediary_images"];
+
+/// Error answered as `{"error": message}`
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn bad_request<E: std::fmt::Display>(error: E) -> Self {
+        Self { status: StatusCode::BAD_REQUEST, message: error.to_string() }
+    }
+
+    pub fn not_found<E: std::fmt::Display>(error: E) -> Self {
+        Self { status: StatusCode::NOT_FOUND, message: error.to_string() }
+    }
+
+    pub fn internal<E: std::fmt::Display>(error: E) -> Self {
+        Self { status: StatusCode::INTERNAL_SERVER_ERROR, message: error.to_string() }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::internal(format!("{:#}", error))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(serde_json::json!({ "error": self.message }))).into_response()
+    }
+}
+
+/// Body of `POST /generate`: the settings of a job, as in batch files, e.g. `{"breed": "maine-coon", "n_steps": 30}`
+#[derive(Debug, Deserialize)]
+pub struct GenerationRequest {
+    /// Answer with the PNG bytes of the first image once generated, instead of the job id to poll
+    #[serde(default)]
+    pub wait: bool,
+    #[serde(flatten)]
+    pub settings: jobs::Job,
+}
+
+#[derive(Clone)]
+pub struct ServerState {
+    pub queue: queue::JobQueue,
+    pub config: Arc<config::Config>,
+    pub preset: Option<String>,
+    pub model: cli::ModelArgs,
+    pub output_dir: PathBuf,
+    /// Most images a single request can ask for, see `--max_images`
+    pub max_images: usize,
+}
+
+impl ServerState {
+    /// Arguments of a job generating its images in the output directory of the server
+    pub fn job_args(&self, id: &str, settings: jobs::Job) -> anyhow::Result<cli::DiffusionArgs> {
+        if let Some(name) = SERVER_SETTINGS.iter().find(|name| settings.contains_key(**name)) {
+            anyhow::bail!("Setting {} is not available through the API", name)
+        }
+        self.server_job_args(id, settings)
+    }
+
+    /// Same as `job_args` for settings filled by the server, which can point at its own files
+    pub fn server_job_args(&self, id: &str, mut settings: jobs::Job) -> anyhow::Result<cli::DiffusionArgs> {
+        let output = self.output_dir.join(format!("{}.png", id));
+        settings.insert("output".to_string(), toml::Value::String(output.to_string_lossy().to_string()));
+
+        let mut args = jobs::parse_job(&settings, &self.config, self.preset.as_deref())?;
+        args.model = self.model.clone();
+        args.sampling.intermediary_images = false;
+        Ok(args)
+    }
+
+    /// Queues a job, answering with its id or, when waiting for it, its first image
+    pub async fn run(&self, args: cli::DiffusionArgs, id: String, wait: bool) -> Result<Response, ApiError> {
+        let done = self.queue.submit(id.clone(), args)?;
+        if !wait {
+            return Ok((StatusCode::ACCEPTED, Json(self.queue.state(&id))).into_response());
+        }
+        let job_state = self.wait(done).await?;
+        match job_state.images.first() {
+            Some(image) => png_response(image),
+            None => Err(ApiError::internal("No image generated"))
+        }
+    }
+
+    /// Final state of a job that generated its images
+    pub async fn wait(&self, done: tokio::sync::oneshot::Receiver<queue::JobState>) -> Result<queue::JobState, ApiError> {
+        let job_state = done.await.map_err(|_| ApiError::internal("The generation worker stopped"))?;
+        match job_state.status {
+            queue::JobStatus::Done => Ok(job_state),
+            _ => Err(ApiError::internal(job_state.error.unwrap_or_else(|| "Generation failed".to_string())))
+        }
+    }
+}
+
+fn png_response(path: &str) -> Result<Response, ApiError> {
+    let bytes = std::fs::read(path).map_err(ApiError::internal)?;
+    Ok(([(header::CONTENT_TYPE, "image/png")], bytes).into_response())
+}
+
+async fn generate(State(state): State<ServerState>, Json(request): Json<GenerationRequest>) -> Result<Response, ApiError> {
+    let id = queue::new_job_id();
+    let args = state.job_args(&id, request.settings).map_err(|error| ApiError::bad_request(format!("{:#}", error)))?;
+    state.run(args, id, request.wait).await
+}
+
+async fn job(State(state): State<ServerState>, Path(id): Path<String>) -> Result<Json<queue::JobState>, ApiError> {
+    state.queue.state(&id)
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Unknown job {}", id)))
+}
+
+async fn job_image(State(state): State<ServerState>, Path((id, image_idx)): Path<(String, usize)>) -> Result<Response, ApiError> {
+    let job_state = state.queue.state(&id).ok_or_else(|| ApiError::not_found(format!("Unknown job {}", id)))?;
+    match job_state.images.get(image_idx) {
+        Some(image) => png_response(image),
+        None => Err(ApiError::not_found(format!("Job {} has no image {} ({:?})", id, image_idx, job_state.status)))
+    }
+}
+
+pub fn router(state: ServerState) -> Router {
+    Router::new()
+        .route("/generate", post(generate))
+        .route("/jobs/:id", get(job))
+        .route("/jobs/:id/images/:image_idx", get(job_image))
+        .route("/v1/images/generations", post(openai::images_generations))
+        .route("/sdapi/v1/txt2img", post(automatic1111::txt2img))
+        .route("/sdapi/v1/img2img", post(automatic1111::img2img))
+        .with_state(state)
+}
+
+/// Loads the models once and serves generations until interrupted
+pub fn serve(args: cli::ServeArgs, config: &config::Config, preset: Option<&str>) -> anyhow::Result<()> {
+    let (host, port) = (args.host, args.port);
+    let output_dir = args.output_dir.map_or_else(|| std::env::temp_dir().join("fantacat-server"), PathBuf::from);
+    std::fs::create_dir_all(&output_dir)?;
+
+    let pipeline = pipeline::Pipeline::load(&args.model)?;
+    let queue = queue::JobQueue::start(move |job_args| pipeline.generate(job_args));
+    let state = ServerState {
+        queue,
+        config: Arc::new(config.clone()),
+        preset: preset.map(str::to_string),
+        model: args.model,
+        output_dir,
+        max_images: args.max_images,
+    };
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async move {
+        let listener = tokio::net::TcpListener::bind((host.as_str(), port)).await?;
+        println!("Serving on http://{}, images in {}", listener.local_addr()?, state.output_dir.display());
+        axum::serve(listener, router(state)).await?;
+        Ok::<(), anyhow::Error>(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn test_state() -> ServerState {
+        let model = match cli::Cli::parse_from(["fantacat-cli", "serve"]).command {
+            cli::Command::Serve(args) => args.model,
+            command => panic!("unexpected command {:?}", command)
+        };
+        ServerState {
+            queue: queue::JobQueue::start(|_: &cli::DiffusionArgs| anyhow::bail!("no models in tests")),
+            config: Arc::new(config::Config::default()),
+            preset: None,
+            model,
+            output_dir: PathBuf::from("images"),
+            max_images: 10,
+        }
+    }
+
+    #[test]
+    fn server_generation_request() -> anyhow::Result<()> {
+        let request: GenerationRequest = serde_json::from_str("{\"breed\": \"maine-coon\", \"n_steps\": 30, \"wait\": true}")?;
+        assert!(request.wait);
+        assert_eq!(request.settings.len(), 2);
+
+        let args = test_state().job_args("1234", request.settings)?;
+        assert_eq!(args.sampling.n_steps, 30);
+        assert_eq!(PathBuf::from(&args.sampling.final_image), PathBuf::from("images").join("1234.png"));
+        assert!(!args.sampling.intermediary_images);
+        Ok(())
+    }
+
+    #[test]
+    fn server_rejects_server_settings() -> anyhow::Result<()> {
+        let state = test_state();
+        for body in ["{\"output\": \"/etc/cat.png\"}", "{\"init_image\": \"/etc/passwd\"}", "{\"sd_version\": \"xl\"}"] {
+            let request: GenerationRequest = serde_json::from_str(body)?;
+            assert!(state.job_args("1234", request.settings).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn server_evicted_job_not_found() -> anyhow::Result<()> {
+        let mut state = test_state();
+        let retention = queue::Retention { max_jobs: 0, ..Default::default() };
+        state.queue = queue::JobQueue::with_retention(retention, |_: &cli::DiffusionArgs| anyhow::bail!("no models in tests"));
+        let args = state.job_args("1234", jobs::Job::new())?;
+        state.queue.submit("1234".to_string(), args)?.blocking_recv()?;
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let polled = runtime.block_on(job(State(state.clone()), Path("1234".to_string())));
+        assert!(matches!(polled, Err(error) if error.status == StatusCode::NOT_FOUND));
+        let image = runtime.block_on(job_image(State(state), Path(("1234".to_string(), 0))));
+        assert!(matches!(image, Err(error) if error.status == StatusCode::NOT_FOUND));
+        Ok(())
+    }
+}
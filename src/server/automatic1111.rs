@@ -0,0 +1,272 @@
+use axum::extract::State;
+use axum::Json;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use super::{queue, ApiError, ServerState};
+use crate::jobs;
+use crate::manifest;
+
+/// Body of `POST /sdapi/v1/txt2img` and `/sdapi/v1/img2img`, missing fields keep the fantacat defaults.
+/// Other Automatic1111 fields, e.g. `styles` or `restore_faces`, are ignored.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct GenerationRequest {
+    /// Details of the cat, appended to the prompt built by fantacat
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub steps: Option<usize>,
+    pub cfg_scale: Option<f64>,
+    /// -1 for a random seed
+    pub seed: Option<i64>,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub sampler_name: Option<String>,
+    /// `Karras` picks the Karras variant of the sampler
+    pub scheduler: Option<String>,
+    pub batch_size: Option<usize>,
+    pub n_iter: Option<usize>,
+    /// Base64 images, img2img only starts from the first one
+    pub init_images: Vec<String>,
+    pub denoising_strength: Option<f64>,
+    /// Base64 black and white mask, inpainting the white areas
+    pub mask: Option<String>,
+    pub inpainting_mask_invert: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GenerationResponse {
+    pub images: Vec<String>,
+    pub parameters: serde_json::Value,
+    /// JSON document of the settings, as a string
+    pub info: String,
+}
+
+/// fantacat scheduler of an Automatic1111 sampler
+pub fn scheduler_name(sampler_name: &str, scheduler: Option<&str>) -> anyhow::Result<&'static str> {
+    let karras = scheduler.is_some_and(|scheduler| scheduler.eq_ignore_ascii_case("karras"));
+    let scheduler = match (sampler_name, karras) {
+        ("DDIM", _) => "ddim",
+        ("Euler a", _) => "euler-ancestral",
+        ("Euler", false) => "euler",
+        ("Euler", true) | ("Euler Karras", _) => "euler-karras",
+        ("DPM++ 2M", false) => "dpmpp-2m",
+        ("DPM++ 2M", true) | ("DPM++ 2M Karras", _) => "dpmpp-2m-karras",
+        _ => anyhow::bail!(
+            "Unsupported sampler {}, available samplers: DDIM, Euler a, Euler, Euler Karras, DPM++ 2M, DPM++ 2M Karras",
+            sampler_name
+        )
+    };
+    Ok(scheduler)
+}
+
+/// Decodes a base64 image, with or without a `data:image/png;base64,` prefix
+pub fn decode_image(image: &str) -> anyhow::Result<Vec<u8>> {
+    let data = image.split_once("base64,").map_or(image, |(_, data)| data);
+    Ok(base64::engine::general_purpose::STANDARD.decode(data.trim())?)
+}
+
+impl GenerationRequest {
+    /// Settings of the equivalent fantacat job, without the images, of at most `max_images` images
+    pub fn settings(&self, max_images: usize) -> anyhow::Result<jobs::Job> {
+        let mut settings = jobs::Job::new();
+        let mut insert = |name: &str, value: toml::Value| {
+            settings.insert(name.to_string(), value);
+        };
+        if !self.prompt.is_empty() { insert("details", toml::Value::String(self.prompt.clone())); }
+        if !self.negative_prompt.is_empty() { insert("negative", toml::Value::String(self.negative_prompt.clone())); }
+        if let Some(steps) = self.steps { insert("n_steps", toml::Value::Integer(steps as i64)); }
+        if let Some(cfg_scale) = self.cfg_scale { insert("guidance_scale", toml::Value::Float(cfg_scale)); }
+        if let Some(seed) = self.seed.filter(|seed| *seed >= 0) { insert("seed", toml::Value::Integer(seed)); }
+        if let Some(width) = self.width { insert("width", toml::Value::Integer(width as i64)); }
+        if let Some(height) = self.height { insert("height", toml::Value::Integer(height as i64)); }
+        if let Some(sampler_name) = &self.sampler_name {
+            let scheduler = scheduler_name(sampler_name, self.scheduler.as_deref())?;
+            insert("scheduler", toml::Value::String(scheduler.to_string()));
+        }
+        // n_iter batches of batch_size images
+        let batch_size = self.batch_size.unwrap_or(1);
+        let n_iter = self.n_iter.unwrap_or(1);
+        if batch_size == 0 || n_iter == 0 {
+            anyhow::bail!("batch_size and n_iter must be at least 1")
+        }
+        let n_images = match batch_size.checked_mul(n_iter) {
+            Some(n_images) if n_images <= max_images => n_images,
+            _ => anyhow::bail!("batch_size times n_iter must be at most {}", max_images)
+        };
+        insert("batch_size", toml::Value::Integer(batch_size as i64));
+        insert("n_images", toml::Value::Integer(n_images as i64));
+        if let Some(denoising_strength) = self.denoising_strength {
+            insert("strength", toml::Value::Float(denoising_strength));
+        }
+        if self.inpainting_mask_invert != 0 {
+            anyhow::bail!("Inverted inpainting masks are not supported")
+        }
+        Ok(settings)
+    }
+}
+
+/// Settings of an image in the Automatic1111 infotext format
+pub fn infotext(run_manifest: &manifest::Manifest, seed: u64, sampler_name: &str) -> String {
+    let mut infotext = run_manifest.prompt.trim().to_string();
+    if !run_manifest.negative_prompt.is_empty() {
+        infotext.push_str(&format!("\nNegative prompt: {}", run_manifest.negative_prompt));
+    }
+    infotext.push_str(&format!(
+        "\nSteps: {}, Sampler: {}, CFG scale: {}, Seed: {}, Size: {}x{}, Model: {}",
+        run_manifest.steps, sampler_name, run_manifest.guidance_scale, seed, run_manifest.width, run_manifest.height, run_manifest.sd_version
+    ));
+    infotext
+}
+
+/// The `info` document, with the settings of the run and the seed of every image
+pub fn info(run_manifest: &manifest::Manifest, sampler_name: &str) -> serde_json::Value {
+    let seeds: Vec<u64> = run_manifest.images.iter().map(|image| image.seed).collect();
+    let infotexts: Vec<String> = seeds.iter().map(|seed| infotext(run_manifest, *seed, sampler_name)).collect();
+    let prompt = run_manifest.prompt.trim();
+    serde_json::json!({
+        "prompt": prompt,
+        "all_prompts": vec![prompt; seeds.len()],
+        "negative_prompt": run_manifest.negative_prompt,
+        "all_negative_prompts": vec![&run_manifest.negative_prompt; seeds.len()],
+        "seed": seeds.first().copied().unwrap_or_default(),
+        "all_seeds": seeds,
+        "width": run_manifest.width,
+        "height": run_manifest.height,
+        "sampler_name": sampler_name,
+        "cfg_scale": run_manifest.guidance_scale,
+        "steps": run_manifest.steps,
+        "batch_size": run_manifest.batch_size,
+        "sd_model_name": run_manifest.sd_version,
+        "infotexts": infotexts,
+    })
+}
+
+/// Writes an image sent by the client in the output directory, with the extension of its format
+fn save_request_image(state: &ServerState, id: &str, name: &str, image: &str) -> anyhow::Result<toml::Value> {
+    let bytes = decode_image(image)?;
+    let format = image::guess_format(&bytes)?;
+    let extension = format.extensions_str().first().copied().unwrap_or("png");
+    let path = state.output_dir.join(format!("{}-{}.{}", id, name, extension));
+    std::fs::write(&path, bytes)?;
+    Ok(toml::Value::String(path.to_string_lossy().to_string()))
+}
+
+async fn generate(state: ServerState, payload: serde_json::Value, img2img: bool) -> Result<Json<GenerationResponse>, ApiError> {
+    let request: GenerationRequest = serde_json::from_value(payload.clone()).map_err(ApiError::bad_request)?;
+    let id = queue::new_job_id();
+    let mut settings = request.settings(state.max_images).map_err(ApiError::bad_request)?;
+    if img2img {
+        let init_image = request.init_images.first().ok_or_else(|| ApiError::bad_request("Missing init_images"))?;
+        let init_image = save_request_image(&state, &id, "init", init_image).map_err(ApiError::bad_request)?;
+        settings.insert("init_image".to_string(), init_image);
+        if let Some(mask) = &request.mask {
+            let mask = save_request_image(&state, &id, "mask", mask).map_err(ApiError::bad_request)?;
+            settings.insert("mask_image".to_string(), mask);
+        }
+    } else {
+        // txt2img runs on the image size only
+        settings.remove("strength");
+    }
+    let args = state.server_job_args(&id, settings).map_err(|error| ApiError::bad_request(format!("{:#}", error)))?;
+
+    let done = state.queue.submit(id, args)?;
+    let job_state = state.wait(done).await?;
+
+    let images = job_state.images.iter()
+        .map(|path| -> anyhow::Result<String> { Ok(base64::engine::general_purpose::STANDARD.encode(std::fs::read(path)?)) })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let sampler_name = request.sampler_name.as_deref().unwrap_or("default");
+    let info = match &job_state.manifest {
+        Some(run_manifest) => info(run_manifest, sampler_name),
+        None => serde_json::json!({})
+    };
+
+    Ok(Json(GenerationResponse { images, parameters: payload, info: info.to_string() }))
+}
+
+pub async fn txt2img(State(state): State<ServerState>, Json(payload): Json<serde_json::Value>) -> Result<Json<GenerationResponse>, ApiError> {
+    generate(state, payload, false).await
+}
+
+pub async fn img2img(State(state): State<ServerState>, Json(payload): Json<serde_json::Value>) -> Result<Json<GenerationResponse>, ApiError> {
+    generate(state, payload, true).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn automatic1111_request_settings() -> anyhow::Result<()> {
+        let request: GenerationRequest = serde_json::from_str(r#"{
+            "prompt": "on a sofa", "negative_prompt": "blurry", "steps": 20, "cfg_scale": 7,
+            "seed": -1, "width": 512, "height": 768, "sampler_name": "DPM++ 2M", "scheduler": "Karras",
+            "batch_size": 2, "n_iter": 3, "restore_faces": false
+        }"#)?;
+        let settings = request.settings(10)?;
+
+        assert_eq!(settings.get("details"), Some(&toml::Value::String("on a sofa".to_string())));
+        assert_eq!(settings.get("negative"), Some(&toml::Value::String("blurry".to_string())));
+        assert_eq!(settings.get("guidance_scale"), Some(&toml::Value::Float(7.0)));
+        assert_eq!(settings.get("scheduler"), Some(&toml::Value::String("dpmpp-2m-karras".to_string())));
+        assert_eq!(settings.get("n_images"), Some(&toml::Value::Integer(6)));
+        assert!(settings.get("seed").is_none());
+
+        assert!(request.settings(5).is_err());
+
+        let request: GenerationRequest = serde_json::from_str(r#"{"sampler_name": "LMS"}"#)?;
+        assert!(request.settings(10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn automatic1111_request_overflowing_images() -> anyhow::Result<()> {
+        let request: GenerationRequest = serde_json::from_str(r#"{"batch_size": 18446744073709551615, "n_iter": 2}"#)?;
+        assert!(request.settings(usize::MAX).is_err());
+        assert!(request.settings(10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn automatic1111_scheduler_name() -> anyhow::Result<()> {
+        assert_eq!(scheduler_name("Euler a", None)?, "euler-ancestral");
+        assert_eq!(scheduler_name("Euler", Some("Automatic"))?, "euler");
+        assert_eq!(scheduler_name("DPM++ 2M Karras", None)?, "dpmpp-2m-karras");
+        Ok(())
+    }
+
+    #[test]
+    fn automatic1111_decode_image() -> anyhow::Result<()> {
+        assert_eq!(decode_image("iVBORw==")?, vec![137, 80, 78, 71]);
+        assert_eq!(decode_image("data:image/png;base64,iVBORw==")?, vec![137, 80, 78, 71]);
+        assert!(decode_image("not base64!").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn automatic1111_info() {
+        let run_manifest = manifest::Manifest {
+            prompt: "  cat  on a sofa".to_string(),
+            negative_prompt: "blurry".to_string(),
+            sd_version: "v1-5".to_string(),
+            steps: 20,
+            guidance_scale: 7.5,
+            width: 512,
+            height: 512,
+            images: vec![
+                manifest::ManifestImage { path: "a.png".to_string(), seed: 1, batch: 0 },
+                manifest::ManifestImage { path: "b.png".to_string(), seed: 2, batch: 0 },
+            ],
+            ..Default::default()
+        };
+
+        let info = info(&run_manifest, "Euler a");
+        assert_eq!(info["all_seeds"], serde_json::json!([1, 2]));
+        assert_eq!(info["prompt"], "cat  on a sofa");
+        assert_eq!(
+            info["infotexts"][1],
+            "cat  on a sofa\nNegative prompt: blurry\nSteps: 20, Sampler: Euler a, CFG scale: 7.5, Seed: 2, Size: 512x512, Model: v1-5"
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use super::{queue, ApiError, ServerState};
+use crate::jobs;
+
+/// Body of `POST /v1/images/generations`. The images come from the model the server was started with,
+/// `model` is ignored as well as the fields of other models such as `quality` or `style`
+#[derive(Debug, Deserialize)]
+pub struct ImageGenerationRequest {
+    /// Details of the cat, appended to the prompt built by fantacat
+    pub prompt: String,
+    #[serde(default = "default_n")]
+    pub n: usize,
+    /// `<width>x<height>`, e.g. `512x512`
+    #[serde(default)]
+    pub size: Option<String>,
+    #[serde(default)]
+    pub response_format: ResponseFormat,
+}
+
+fn default_n() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Url,
+    #[default]
+    B64Json,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub b64_json: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    pub revised_prompt: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImagesResponse {
+    pub created: u64,
+    pub data: Vec<ImageData>,
+}
+
+/// Errors answered in the OpenAI format, `{"error": {"message": ..., "type": ...}}`
+pub struct OpenAiError(ApiError);
+
+impl From<ApiError> for OpenAiError {
+    fn from(error: ApiError) -> Self {
+        Self(error)
+    }
+}
+
+impl IntoResponse for OpenAiError {
+    fn into_response(self) -> Response {
+        let error_type = match self.0.status {
+            StatusCode::BAD_REQUEST => "invalid_request_error",
+            _ => "server_error",
+        };
+        let body = serde_json::json!({ "error": { "message": self.0.message, "type": error_type, "param": null, "code": null } });
+        (self.0.status, Json(body)).into_response()
+    }
+}
+
+pub fn parse_size(size: &str) -> anyhow::Result<(usize, usize)> {
+    match size.split_once('x') {
+        Some((width, height)) => Ok((width.trim().parse()?, height.trim().parse()?)),
+        None => anyhow::bail!("Invalid size {}, expected <width>x<height>", size)
+    }
+}
+
+impl ImageGenerationRequest {
+    /// Settings of the equivalent fantacat job, of at most `max_images` images
+    pub fn settings(&self, max_images: usize) -> anyhow::Result<jobs::Job> {
+        if self.n == 0 || self.n > max_images {
+            anyhow::bail!("n must be between 1 and {}", max_images)
+        }
+        let mut settings = jobs::Job::new();
+        settings.insert("details".to_string(), toml::Value::String(self.prompt.clone()));
+        settings.insert("n_images".to_string(), toml::Value::Integer(self.n as i64));
+        if let Some(size) = &self.size {
+            let (width, height) = parse_size(size)?;
+            settings.insert("width".to_string(), toml::Value::Integer(width as i64));
+            settings.insert("height".to_string(), toml::Value::Integer(height as i64));
+        }
+        Ok(settings)
+    }
+}
+
+/// An image in the requested format, `url` being where the server exposes it
+pub fn image_data(path: &str, response_format: ResponseFormat, url: String, revised_prompt: &str) -> anyhow::Result<ImageData> {
+    let image_data = match response_format {
+        ResponseFormat::B64Json => {
+            let bytes = std::fs::read(path)?;
+            ImageData { b64_json: Some(base64::engine::general_purpose::STANDARD.encode(bytes)), url: None, revised_prompt: revised_prompt.to_string() }
+        },
+        ResponseFormat::Url => ImageData { b64_json: None, url: Some(url), revised_prompt: revised_prompt.to_string() }
+    };
+    Ok(image_data)
+}
+
+pub async fn images_generations(State(state): State<ServerState>, headers: HeaderMap, Json(request): Json<ImageGenerationRequest>) -> Result<Json<ImagesResponse>, OpenAiError> {
+    let id = queue::new_job_id();
+    let settings = request.settings(state.max_images).map_err(ApiError::bad_request)?;
+    let args = state.job_args(&id, settings).map_err(|error| ApiError::bad_request(format!("{:#}", error)))?;
+    let revised_prompt = args.prompt.build_prompt().to_string();
+
+    let done = state.queue.submit(id.clone(), args).map_err(ApiError::from)?;
+    let job_state = state.wait(done).await?;
+
+    // urls point at the job images route, on the host the client reached
+    let base_url = headers.get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map_or_else(String::new, |host| format!("http://{}", host));
+    let data = job_state.images.iter().enumerate()
+        .map(|(image_idx, path)| {
+            let url = format!("{}/jobs/{}/images/{}", base_url, id, image_idx);
+            image_data(path, request.response_format, url, revised_prompt.trim())
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(ApiError::from)?;
+    let created = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+
+    Ok(Json(ImagesResponse { created, data }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openai_request_settings() -> anyhow::Result<()> {
+        let request: ImageGenerationRequest = serde_json::from_str(
+            "{\"model\": \"dall-e-3\", \"prompt\": \"sleeping on a keyboard\", \"n\": 2, \"size\": \"512x768\", \"quality\": \"hd\"}"
+        )?;
+        assert_eq!(request.response_format, ResponseFormat::B64Json);
+
+        let settings = request.settings(10)?;
+        assert_eq!(settings.get("details"), Some(&toml::Value::String("sleeping on a keyboard".to_string())));
+        assert_eq!(settings.get("n_images"), Some(&toml::Value::Integer(2)));
+        assert_eq!(settings.get("width"), Some(&toml::Value::Integer(512)));
+        assert_eq!(settings.get("height"), Some(&toml::Value::Integer(768)));
+
+        let request: ImageGenerationRequest = serde_json::from_str("{\"prompt\": \"a cat\", \"response_format\": \"url\"}")?;
+        assert_eq!(request.response_format, ResponseFormat::Url);
+        assert_eq!(request.n, 1);
+        assert!(request.settings(10)?.get("width").is_none());
+
+        let request: ImageGenerationRequest = serde_json::from_str("{\"prompt\": \"a cat\", \"n\": 1000000}")?;
+        assert!(request.settings(10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn openai_parse_size() {
+        assert_eq!(parse_size("1024x1024").unwrap(), (1024, 1024));
+        assert!(parse_size("1024").is_err());
+        assert!(parse_size("largex1024").is_err());
+    }
+
+    #[test]
+    fn openai_image_data() -> anyhow::Result<()> {
+        let dir = crate::test_utils::TempDir::new("openai")?;
+        let path = dir.join("cat.png");
+        std::fs::write(&path, [137, 80, 78, 71])?;
+        let path = path.to_string_lossy().to_string();
+
+        let b64 = image_data(&path, ResponseFormat::B64Json, "http://localhost/jobs/1/images/0".to_string(), "cat")?;
+        assert_eq!(b64.b64_json.as_deref(), Some("iVBORw=="));
+        assert!(b64.url.is_none());
+
+        let url = image_data(&path, ResponseFormat::Url, "http://localhost/jobs/1/images/0".to_string(), "cat")?;
+        assert_eq!(url.url.as_deref(), Some("http://localhost/jobs/1/images/0"));
+        Ok(())
+    }
+}
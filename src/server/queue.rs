@@ -0,0 +1,221 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::cli;
+use crate::manifest;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+/// What a client polling a job gets back
+#[derive(Debug, Clone, Serialize)]
+pub struct JobState {
+    pub id: String,
+    pub status: JobStatus,
+    pub images: Vec<String>,
+    pub seeds: Vec<u64>,
+    pub error: Option<String>,
+    /// Settings the images were generated with, once done
+    #[serde(skip)]
+    pub manifest: Option<manifest::Manifest>,
+}
+
+/// How long finished jobs stay available to the clients polling them
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    /// Finished jobs kept at most, the oldest ones go first
+    pub max_jobs: usize,
+    pub duration: Duration,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self { max_jobs: 1000, duration: Duration::from_secs(60 * 60) }
+    }
+}
+
+#[derive(Default)]
+struct JobStates {
+    states: HashMap<String, JobState>,
+    /// Finished jobs, oldest first, with the time they finished at
+    finished: VecDeque<(String, Instant)>,
+}
+
+impl JobStates {
+    fn evict(&mut self, retention: &Retention) {
+        while let Some((_, finished_at)) = self.finished.front() {
+            if self.finished.len() <= retention.max_jobs && finished_at.elapsed() < retention.duration {
+                break;
+            }
+            if let Some((id, _)) = self.finished.pop_front() {
+                self.states.remove(&id);
+            }
+        }
+    }
+}
+
+struct QueuedJob {
+    id: String,
+    args: cli::DiffusionArgs,
+    done: Option<oneshot::Sender<JobState>>,
+}
+
+/// Jobs run one at a time by a worker thread owning the models, in submission order.
+/// Finished jobs are forgotten past the retention, so that the states do not grow with every request
+#[derive(Clone)]
+pub struct JobQueue {
+    states: Arc<Mutex<JobStates>>,
+    sender: mpsc::Sender<QueuedJob>,
+    retention: Retention,
+}
+
+pub fn new_job_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+impl JobQueue {
+    /// Starts the worker thread, `generate` runs a job, usually with a resident `Pipeline`
+    pub fn start<F>(generate: F) -> Self
+    where
+        F: FnMut(&cli::DiffusionArgs) -> anyhow::Result<manifest::Manifest> + Send + 'static,
+    {
+        Self::with_retention(Retention::default(), generate)
+    }
+
+    pub fn with_retention<F>(retention: Retention, mut generate: F) -> Self
+    where
+        F: FnMut(&cli::DiffusionArgs) -> anyhow::Result<manifest::Manifest> + Send + 'static,
+    {
+        let states: Arc<Mutex<JobStates>> = Arc::new(Mutex::new(JobStates::default()));
+        let (sender, receiver) = mpsc::channel::<QueuedJob>();
+
+        let worker_states = states.clone();
+        std::thread::spawn(move || {
+            for job in receiver {
+                update_state(&worker_states, &job.id, |state| state.status = JobStatus::Running);
+                let result = generate(&job.args);
+                let state = update_state(&worker_states, &job.id, |state| match result {
+                    Ok(run_manifest) => {
+                        state.status = JobStatus::Done;
+                        state.images = run_manifest.images.iter().map(|image| image.path.clone()).collect();
+                        state.seeds = run_manifest.images.iter().map(|image| image.seed).collect();
+                        state.manifest = Some(run_manifest);
+                    },
+                    Err(error) => {
+                        println!("Job {} failed: {:#}", job.id, error);
+                        state.status = JobStatus::Failed;
+                        state.error = Some(format!("{:#}", error));
+                    }
+                });
+                if let Ok(mut states) = worker_states.lock() {
+                    states.finished.push_back((job.id.clone(), Instant::now()));
+                    states.evict(&retention);
+                }
+                if let (Some(done), Some(state)) = (job.done, state) {
+                    // the client may have gone away in the meantime
+                    let _ = done.send(state);
+                }
+            }
+        });
+
+        Self { states, sender, retention }
+    }
+
+    /// Queues a job, the receiver gets its final state once it ran
+    pub fn submit(&self, id: String, args: cli::DiffusionArgs) -> anyhow::Result<oneshot::Receiver<JobState>> {
+        let (done, receiver) = oneshot::channel();
+        let state = JobState { id: id.clone(), status: JobStatus::Queued, images: vec![], seeds: vec![], error: None, manifest: None };
+        {
+            let mut states = self.states.lock().map_err(|_| anyhow::anyhow!("Job states poisoned"))?;
+            states.evict(&self.retention);
+            states.states.insert(id.clone(), state);
+        }
+        self.sender
+            .send(QueuedJob { id, args, done: Some(done) })
+            .map_err(|_| anyhow::anyhow!("The generation worker stopped"))?;
+        Ok(receiver)
+    }
+
+    /// None for unknown jobs, and for finished ones past the retention
+    pub fn state(&self, id: &str) -> Option<JobState> {
+        let mut states = self.states.lock().ok()?;
+        states.evict(&self.retention);
+        states.states.get(id).cloned()
+    }
+}
+
+fn update_state<F: FnOnce(&mut JobState)>(states: &Mutex<JobStates>, id: &str, update: F) -> Option<JobState> {
+    let mut states = states.lock().ok()?;
+    let state = states.states.get_mut(id)?;
+    update(state);
+    Some(state.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs;
+
+    fn job_args(output: &str) -> anyhow::Result<cli::DiffusionArgs> {
+        let job: jobs::Job = serde_json::from_str(&format!("{{\"output\": \"{}\", \"seed\": 7}}", output))?;
+        jobs::parse_job(&job, &Default::default(), None)
+    }
+
+    #[test]
+    fn queue_runs_jobs_in_order() -> anyhow::Result<()> {
+        let queue = JobQueue::start(|args: &cli::DiffusionArgs| {
+            if args.sampling.final_image == "fail.png" {
+                anyhow::bail!("out of memory")
+            }
+            Ok(manifest::Manifest {
+                images: vec![manifest::ManifestImage { path: args.sampling.final_image.clone(), seed: 7, batch: 0 }],
+                ..Default::default()
+            })
+        });
+
+        let done = queue.submit("a".to_string(), job_args("cat.png")?)?;
+        let failed = queue.submit("b".to_string(), job_args("fail.png")?)?;
+
+        let done = done.blocking_recv()?;
+        assert_eq!(done.status, JobStatus::Done);
+        assert_eq!(done.images, vec!["cat.png".to_string()]);
+        assert_eq!(done.seeds, vec![7]);
+
+        let failed = failed.blocking_recv()?;
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert_eq!(failed.error.as_deref(), Some("out of memory"));
+        assert_eq!(queue.state("b").map(|state| state.status), Some(JobStatus::Failed));
+        assert!(queue.state("c").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn queue_evicts_finished_jobs() -> anyhow::Result<()> {
+        let generate = |args: &cli::DiffusionArgs| Ok(manifest::Manifest {
+            images: vec![manifest::ManifestImage { path: args.sampling.final_image.clone(), seed: 7, batch: 0 }],
+            ..Default::default()
+        });
+        let queue = JobQueue::with_retention(Retention { max_jobs: 1, ..Default::default() }, generate);
+        queue.submit("a".to_string(), job_args("a.png")?)?.blocking_recv()?;
+        assert!(queue.state("a").is_some());
+        queue.submit("b".to_string(), job_args("b.png")?)?.blocking_recv()?;
+        assert!(queue.state("a").is_none());
+        assert_eq!(queue.state("b").map(|state| state.status), Some(JobStatus::Done));
+
+        let queue = JobQueue::with_retention(Retention { max_jobs: 1, duration: Duration::ZERO }, generate);
+        queue.submit("a".to_string(), job_args("a.png")?)?.blocking_recv()?;
+        assert!(queue.state("a").is_none());
+        Ok(())
+    }
+}
@@ -1,6 +1,13 @@
-
 pub mod stable_diffusion_files;
 pub mod clip_embeddings;
 pub mod vae;
 pub mod unet;
-pub mod constants;
\ No newline at end of file
+pub mod sdxl_unet;
+pub mod constants;
+pub mod latents;
+pub mod inpainting;
+pub mod schedulers;
+pub mod model_cache;
+pub mod checksums;
+pub mod checkpoint;
+pub mod diffusers_folder;
@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+use sha2::{Digest, Sha256};
+
+use crate::stable_diffusion::stable_diffusion_files::{ModelSource, StableDiffusionVersion};
+
+/// Resnets per down block of every UNet, an input block more holds the downsampler
+const LAYERS_PER_BLOCK: usize = 2;
+/// Down and up blocks of every VAE
+const VAE_BLOCKS: usize = 4;
+
+/// Tensors of a single-file checkpoint split per component, under the names `build_unet`, `build_vae`
+/// and `build_clip_transformer` expect
+#[derive(Debug, Default)]
+pub struct CheckpointComponents {
+    pub unet: HashMap<String, Tensor>,
+    pub vae: HashMap<String, Tensor>,
+    pub clip: HashMap<String, Tensor>,
+    /// Second text encoder of SDXL based checkpoints
+    pub clip2: HashMap<String, Tensor>,
+}
+
+/// Component files converted from a checkpoint
+#[derive(Debug, Clone)]
+pub struct ConvertedCheckpoint {
+    pub unet: PathBuf,
+    pub vae: PathBuf,
+    pub clip: PathBuf,
+    pub clip2: Option<PathBuf>,
+}
+
+fn resnet_key(tail: &str) -> String {
+    let renames = [
+        ("in_layers.0.", "norm1."),
+        ("in_layers.2.", "conv1."),
+        ("out_layers.0.", "norm2."),
+        ("out_layers.3.", "conv2."),
+        ("emb_layers.1.", "time_emb_proj."),
+        ("skip_connection.", "conv_shortcut."),
+    ];
+    renames.iter()
+        .find_map(|(from, to)| tail.strip_prefix(from).map(|rest| format!("{}{}", to, rest)))
+        .unwrap_or_else(|| tail.to_string())
+}
+
+/// Splits `3.0.in_layers.0.weight` in the block, the layer in the block and the rest
+fn split_block(key: &str) -> Option<(usize, usize, &str)> {
+    let mut parts = key.splitn(3, '.');
+    let block = parts.next()?.parse().ok()?;
+    let layer = parts.next()?.parse().ok()?;
+    Some((block, layer, parts.next()?))
+}
+
+/// Diffusers name of a tensor of the original UNet, given without its `model.diffusion_model.` prefix
+pub fn unet_key(key: &str) -> Option<String> {
+    let renames = [
+        ("time_embed.0.", "time_embedding.linear_1."),
+        ("time_embed.2.", "time_embedding.linear_2."),
+        ("label_emb.0.0.", "add_embedding.linear_1."),
+        ("label_emb.0.2.", "add_embedding.linear_2."),
+        ("input_blocks.0.0.", "conv_in."),
+        ("out.0.", "conv_norm_out."),
+        ("out.2.", "conv_out."),
+    ];
+    if let Some(key) = renames.iter().find_map(|(from, to)| key.strip_prefix(from).map(|rest| format!("{}{}", to, rest))) {
+        return Some(key);
+    }
+
+    if let Some(rest) = key.strip_prefix("input_blocks.") {
+        let (idx, layer, tail) = split_block(rest)?;
+        if idx == 0 {
+            return None;
+        }
+        let (block_id, layer_id) = ((idx - 1) / (LAYERS_PER_BLOCK + 1), (idx - 1) % (LAYERS_PER_BLOCK + 1));
+        return match (layer, tail.strip_prefix("op.")) {
+            (0, Some(op)) => Some(format!("down_blocks.{}.downsamplers.0.conv.{}", block_id, op)),
+            (0, None) => Some(format!("down_blocks.{}.resnets.{}.{}", block_id, layer_id, resnet_key(tail))),
+            (1, _) => Some(format!("down_blocks.{}.attentions.{}.{}", block_id, layer_id, tail)),
+            _ => None
+        };
+    }
+
+    if let Some(rest) = key.strip_prefix("middle_block.") {
+        let (layer, tail) = rest.split_once('.')?;
+        return match layer {
+            "0" => Some(format!("mid_block.resnets.0.{}", resnet_key(tail))),
+            "1" => Some(format!("mid_block.attentions.0.{}", tail)),
+            "2" => Some(format!("mid_block.resnets.1.{}", resnet_key(tail))),
+            _ => None
+        };
+    }
+
+    if let Some(rest) = key.strip_prefix("output_blocks.") {
+        let (idx, layer, tail) = split_block(rest)?;
+        let (block_id, layer_id) = (idx / (LAYERS_PER_BLOCK + 1), idx % (LAYERS_PER_BLOCK + 1));
+        return match layer {
+            0 => Some(format!("up_blocks.{}.resnets.{}.{}", block_id, layer_id, resnet_key(tail))),
+            // blocks without attention have their upsampler right after the resnet
+            1 if tail.starts_with("conv.") => Some(format!("up_blocks.{}.upsamplers.0.{}", block_id, tail)),
+            1 => Some(format!("up_blocks.{}.attentions.{}.{}", block_id, layer_id, tail)),
+            2 => Some(format!("up_blocks.{}.upsamplers.0.{}", block_id, tail)),
+            _ => None
+        };
+    }
+    None
+}
+
+fn vae_resnet_key(tail: &str) -> String {
+    match tail.strip_prefix("nin_shortcut.") {
+        Some(rest) => format!("conv_shortcut.{}", rest),
+        None => tail.to_string()
+    }
+}
+
+fn vae_attention_key(tail: &str) -> Option<String> {
+    let renames = [("norm.", "group_norm."), ("q.", "to_q."), ("k.", "to_k."), ("v.", "to_v."), ("proj_out.", "to_out.0.")];
+    renames.iter().find_map(|(from, to)| tail.strip_prefix(from).map(|rest| format!("{}{}", to, rest)))
+}
+
+/// Diffusers name of a tensor of the original VAE, given without its `first_stage_model.` prefix
+pub fn vae_key(key: &str) -> Option<String> {
+    let (coder, rest) = key.split_once('.')?;
+    if coder == "quant_conv" || coder == "post_quant_conv" {
+        return Some(key.to_string());
+    }
+    let up = match coder {
+        "encoder" => false,
+        "decoder" => true,
+        _ => return None
+    };
+
+    let rest = if let Some(tail) = rest.strip_prefix("norm_out.") {
+        format!("conv_norm_out.{}", tail)
+    } else if rest.starts_with("conv_in.") || rest.starts_with("conv_out.") {
+        rest.to_string()
+    } else if let Some(mid) = rest.strip_prefix("mid.") {
+        let (layer, tail) = mid.split_once('.')?;
+        match layer {
+            "block_1" => format!("mid_block.resnets.0.{}", vae_resnet_key(tail)),
+            "block_2" => format!("mid_block.resnets.1.{}", vae_resnet_key(tail)),
+            "attn_1" => format!("mid_block.attentions.0.{}", vae_attention_key(tail)?),
+            _ => return None
+        }
+    } else {
+        let (blocks, samplers, diffusers_blocks) = if up { ("up.", "upsample.", "up_blocks") } else { ("down.", "downsample.", "down_blocks") };
+        let (idx, tail) = rest.strip_prefix(blocks)?.split_once('.')?;
+        let idx: usize = idx.parse().ok()?;
+        // the original decoder numbers its blocks from the lowest resolution up
+        let block_id = if up { VAE_BLOCKS.checked_sub(idx + 1)? } else { idx };
+        if let Some(tail) = tail.strip_prefix("block.") {
+            let (layer_id, tail) = tail.split_once('.')?;
+            format!("{}.{}.resnets.{}.{}", diffusers_blocks, block_id, layer_id, vae_resnet_key(tail))
+        } else if let Some(tail) = tail.strip_prefix(samplers) {
+            let diffusers_samplers = if up { "upsamplers" } else { "downsamplers" };
+            format!("{}.{}.{}.0.{}", diffusers_blocks, block_id, diffusers_samplers, tail)
+        } else {
+            return None
+        }
+    };
+    Some(format!("{}.{}", coder, rest))
+}
+
+/// Name of a tensor of a HF transformers CLIP text model, old checkpoints lack the `text_model.` prefix
+pub fn clip_key(key: &str) -> String {
+    if key.starts_with("text_model.") {
+        key.to_string()
+    } else {
+        format!("text_model.{}", key)
+    }
+}
+
+/// HF transformers name of a tensor of an OpenCLIP text model, as in SD 2.x and the second SDXL encoder.
+/// The fused attention projections are split apart by `insert_open_clip`
+pub fn open_clip_key(key: &str) -> Option<String> {
+    match key {
+        "token_embedding.weight" => return Some("text_model.embeddings.token_embedding.weight".to_string()),
+        "positional_embedding" => return Some("text_model.embeddings.position_embedding.weight".to_string()),
+        _ => {}
+    }
+    if let Some(tail) = key.strip_prefix("ln_final.") {
+        return Some(format!("text_model.final_layer_norm.{}", tail));
+    }
+    let (layer, tail) = key.strip_prefix("transformer.resblocks.")?.split_once('.')?;
+    let renames = [
+        ("ln_1.", "layer_norm1."),
+        ("ln_2.", "layer_norm2."),
+        ("mlp.c_fc.", "mlp.fc1."),
+        ("mlp.c_proj.", "mlp.fc2."),
+        ("attn.out_proj.", "self_attn.out_proj."),
+    ];
+    let tail = renames.iter().find_map(|(from, to)| tail.strip_prefix(from).map(|rest| format!("{}{}", to, rest)))?;
+    Some(format!("text_model.encoder.layers.{}.{}", layer, tail))
+}
+
+fn insert_open_clip(tensors: &mut HashMap<String, Tensor>, key: &str, tensor: Tensor) -> Result<()> {
+    if key == "text_projection" {
+        tensors.insert("text_projection.weight".to_string(), tensor.t()?.contiguous()?);
+        return Ok(());
+    }
+    if let Some(rest) = key.strip_prefix("transformer.resblocks.") {
+        if let Some((layer, tail)) = rest.split_once(".attn.in_proj_") {
+            let projections = tensor.chunk(3, 0)?;
+            for (name, projection) in ["q_proj", "k_proj", "v_proj"].iter().zip(projections) {
+                tensors.insert(format!("text_model.encoder.layers.{}.self_attn.{}.{}", layer, name, tail), projection.contiguous()?);
+            }
+            return Ok(());
+        }
+    }
+    if let Some(key) = open_clip_key(key) {
+        tensors.insert(key, tensor);
+    }
+    Ok(())
+}
+
+/// Splits the tensors of a checkpoint per component and renames them,
+/// the ones of no component such as EMA weights are left out
+pub fn split_checkpoint(tensors: HashMap<String, Tensor>) -> Result<CheckpointComponents> {
+    let mut components = CheckpointComponents::default();
+    for (key, tensor) in tensors {
+        if let Some(rest) = key.strip_prefix("model.diffusion_model.") {
+            if let Some(key) = unet_key(rest) {
+                components.unet.insert(key, tensor);
+            }
+        } else if let Some(rest) = key.strip_prefix("first_stage_model.") {
+            if let Some(key) = vae_key(rest) {
+                // the attention of the original VAE uses 1x1 convolutions where diffusers has linear layers
+                let dims = tensor.dims().to_vec();
+                let tensor = match dims[..] {
+                    [out_channels, in_channels, 1, 1] if key.contains(".attentions.") => tensor.reshape((out_channels, in_channels))?,
+                    _ => tensor
+                };
+                components.vae.insert(key, tensor);
+            }
+        } else if let Some(rest) = key.strip_prefix("cond_stage_model.transformer.").or_else(|| key.strip_prefix("conditioner.embedders.0.transformer.")) {
+            components.clip.insert(clip_key(rest), tensor);
+        } else if let Some(rest) = key.strip_prefix("cond_stage_model.model.") {
+            insert_open_clip(&mut components.clip, rest, tensor)?;
+        } else if let Some(rest) = key.strip_prefix("conditioner.embedders.1.model.") {
+            insert_open_clip(&mut components.clip2, rest, tensor)?;
+        }
+    }
+    Ok(components)
+}
+
+/// `$XDG_CACHE_HOME/fantacat/checkpoints` or `~/.cache/fantacat/checkpoints`
+pub fn checkpoint_cache_dir() -> Option<PathBuf> {
+    let cache_dir = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(cache_dir) if !cache_dir.is_empty() => PathBuf::from(cache_dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache_dir.join("fantacat").join("checkpoints"))
+}
+
+/// Converts a checkpoint into component files, kept in the cache until the checkpoint changes
+pub fn convert_checkpoint(checkpoint: &Path, sd_version: &StableDiffusionVersion) -> Result<ConvertedCheckpoint> {
+    let cache_dir = match checkpoint_cache_dir() {
+        Some(cache_dir) => cache_dir,
+        None => anyhow::bail!("No cache directory for the converted checkpoints, set XDG_CACHE_HOME")
+    };
+    convert_checkpoint_to(checkpoint, sd_version, &cache_dir)
+}
+
+pub fn convert_checkpoint_to(checkpoint: &Path, sd_version: &StableDiffusionVersion, cache_dir: &Path) -> Result<ConvertedCheckpoint> {
+    if !checkpoint.is_file() {
+        anyhow::bail!("The checkpoint {} does not exist", checkpoint.display())
+    }
+    let checkpoint = std::fs::canonicalize(checkpoint)?;
+    let metadata = std::fs::metadata(&checkpoint)?;
+    let modified = metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?.as_secs();
+    let key = Sha256::digest(format!("{}:{}:{}", checkpoint.display(), metadata.len(), modified));
+    let stem = checkpoint.file_stem().map_or_else(|| "checkpoint".into(), |stem| stem.to_string_lossy());
+    let dir = cache_dir.join(format!("{}-{}", stem, &format!("{:x}", key)[..16]));
+
+    let has_clip2 = sd_version.has_second_text_encoder();
+    let converted = ConvertedCheckpoint {
+        unet: dir.join("unet.safetensors"),
+        vae: dir.join("vae.safetensors"),
+        clip: dir.join("text_encoder.safetensors"),
+        clip2: if has_clip2 { Some(dir.join("text_encoder_2.safetensors")) } else { None },
+    };
+    let files = [Some(&converted.unet), Some(&converted.vae), Some(&converted.clip), converted.clip2.as_ref()];
+    if files.iter().flatten().all(|file| file.is_file()) {
+        return Ok(converted);
+    }
+
+    println!("Converting checkpoint {} into {}", checkpoint.display(), dir.display());
+    let components = split_checkpoint(candle_core::safetensors::load(&checkpoint, &Device::Cpu)?)?;
+    let mut outputs = vec![("UNet", &components.unet, &converted.unet), ("VAE", &components.vae, &converted.vae), ("text encoder", &components.clip, &converted.clip)];
+    if let Some(clip2) = &converted.clip2 {
+        outputs.push(("second text encoder", &components.clip2, clip2));
+    }
+    if let Some((name, _, _)) = outputs.iter().find(|(_, tensors, _)| tensors.is_empty()) {
+        anyhow::bail!("The checkpoint {} has no {} weights for {:?}", checkpoint.display(), name, sd_version)
+    }
+
+    std::fs::create_dir_all(&dir)?;
+    for (_, tensors, file) in outputs {
+        // written aside first, an interrupted conversion is never taken for a complete one
+        let partial = file.with_extension("safetensors.part");
+        candle_core::safetensors::save(tensors, &partial)?;
+        std::fs::rename(&partial, file)?;
+    }
+    Ok(converted)
+}
+
+/// Loads the UNet, VAE and text encoders from a checkpoint, weights given explicitly still win, e.g. a better VAE
+pub fn apply_checkpoint(source: &mut ModelSource, checkpoint: &str, sd_version: &StableDiffusionVersion) -> Result<()> {
+    let converted = convert_checkpoint(Path::new(checkpoint), sd_version)?;
+    let path = |file: &PathBuf| Some(file.to_string_lossy().to_string());
+    let overrides = &mut source.overrides;
+    overrides.unet = overrides.unet.take().or_else(|| path(&converted.unet));
+    overrides.vae = overrides.vae.take().or_else(|| path(&converted.vae));
+    overrides.clip = overrides.clip.take().or_else(|| path(&converted.clip));
+    if let Some(clip2) = &converted.clip2 {
+        overrides.clip2 = overrides.clip2.take().or_else(|| path(clip2));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::DType;
+
+    #[test]
+    fn checkpoint_unet_keys() {
+        let key = |key: &str| unet_key(key).unwrap();
+        assert_eq!(key("time_embed.0.weight"), "time_embedding.linear_1.weight");
+        assert_eq!(key("input_blocks.0.0.bias"), "conv_in.bias");
+        assert_eq!(key("input_blocks.1.0.in_layers.2.weight"), "down_blocks.0.resnets.0.conv1.weight");
+        assert_eq!(key("input_blocks.2.1.proj_in.weight"), "down_blocks.0.attentions.1.proj_in.weight");
+        assert_eq!(key("input_blocks.3.0.op.weight"), "down_blocks.0.downsamplers.0.conv.weight");
+        assert_eq!(key("input_blocks.4.0.skip_connection.weight"), "down_blocks.1.resnets.0.conv_shortcut.weight");
+        assert_eq!(key("middle_block.1.transformer_blocks.0.attn1.to_q.weight"), "mid_block.attentions.0.transformer_blocks.0.attn1.to_q.weight");
+        assert_eq!(key("middle_block.2.emb_layers.1.bias"), "mid_block.resnets.1.time_emb_proj.bias");
+        assert_eq!(key("output_blocks.2.1.conv.weight"), "up_blocks.0.upsamplers.0.conv.weight");
+        assert_eq!(key("output_blocks.5.1.norm.weight"), "up_blocks.1.attentions.2.norm.weight");
+        assert_eq!(key("output_blocks.5.2.conv.bias"), "up_blocks.1.upsamplers.0.conv.bias");
+        assert_eq!(key("out.2.weight"), "conv_out.weight");
+        assert_eq!(key("label_emb.0.2.bias"), "add_embedding.linear_2.bias");
+        assert!(unet_key("input_blocks.0.1.weight").is_none());
+    }
+
+    #[test]
+    fn checkpoint_vae_keys() {
+        let key = |key: &str| vae_key(key).unwrap();
+        assert_eq!(key("encoder.down.0.block.1.conv2.weight"), "encoder.down_blocks.0.resnets.1.conv2.weight");
+        assert_eq!(key("encoder.down.1.downsample.conv.bias"), "encoder.down_blocks.1.downsamplers.0.conv.bias");
+        assert_eq!(key("encoder.mid.attn_1.q.weight"), "encoder.mid_block.attentions.0.to_q.weight");
+        assert_eq!(key("decoder.mid.attn_1.proj_out.bias"), "decoder.mid_block.attentions.0.to_out.0.bias");
+        assert_eq!(key("decoder.up.3.block.0.nin_shortcut.weight"), "decoder.up_blocks.0.resnets.0.conv_shortcut.weight");
+        assert_eq!(key("decoder.up.1.upsample.conv.weight"), "decoder.up_blocks.2.upsamplers.0.conv.weight");
+        assert_eq!(key("decoder.norm_out.weight"), "decoder.conv_norm_out.weight");
+        assert_eq!(key("post_quant_conv.bias"), "post_quant_conv.bias");
+        assert!(vae_key("decoder.up.4.block.0.conv1.weight").is_none());
+    }
+
+    #[test]
+    fn checkpoint_split() -> Result<()> {
+        let zeros = |shape: &[usize]| Tensor::zeros(shape, DType::F32, &Device::Cpu);
+        let mut tensors = HashMap::new();
+        tensors.insert("model.diffusion_model.out.0.weight".to_string(), zeros(&[4])?);
+        tensors.insert("first_stage_model.encoder.mid.attn_1.k.weight".to_string(), zeros(&[8, 8, 1, 1])?);
+        tensors.insert("cond_stage_model.transformer.text_model.final_layer_norm.bias".to_string(), zeros(&[4])?);
+        tensors.insert("conditioner.embedders.1.model.transformer.resblocks.3.attn.in_proj_weight".to_string(), zeros(&[12, 4])?);
+        tensors.insert("conditioner.embedders.1.model.text_projection".to_string(), zeros(&[4, 2])?);
+        tensors.insert("model_ema.decay".to_string(), zeros(&[1])?);
+
+        let components = split_checkpoint(tensors)?;
+        assert!(components.unet.contains_key("conv_norm_out.weight"));
+        assert_eq!(components.vae["encoder.mid_block.attentions.0.to_k.weight"].dims(), &[8, 8]);
+        assert!(components.clip.contains_key("text_model.final_layer_norm.bias"));
+        assert_eq!(components.clip2["text_model.encoder.layers.3.self_attn.v_proj.weight"].dims(), &[4, 4]);
+        assert_eq!(components.clip2["text_projection.weight"].dims(), &[2, 4]);
+        assert_eq!(components.clip2.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoint_convert_cached() -> Result<()> {
+        let root = crate::test_utils::TempDir::new("checkpoint")?;
+        let checkpoint = root.join("whiskers.safetensors");
+        let zeros = |shape: &[usize]| Tensor::zeros(shape, DType::F32, &Device::Cpu);
+        let mut tensors = HashMap::new();
+        tensors.insert("model.diffusion_model.input_blocks.0.0.weight".to_string(), zeros(&[4])?);
+        tensors.insert("first_stage_model.quant_conv.weight".to_string(), zeros(&[4])?);
+        candle_core::safetensors::save(&tensors, &checkpoint)?;
+
+        // a text encoder is missing
+        assert!(convert_checkpoint_to(&checkpoint, &StableDiffusionVersion::V1_5, &root.join("cache")).is_err());
+
+        tensors.insert("cond_stage_model.transformer.embeddings.position_ids".to_string(), zeros(&[4])?);
+        candle_core::safetensors::save(&tensors, &checkpoint)?;
+        let converted = convert_checkpoint_to(&checkpoint, &StableDiffusionVersion::V1_5, &root.join("cache"))?;
+        assert!(converted.clip2.is_none());
+        let unet = candle_core::safetensors::load(&converted.unet, &Device::Cpu)?;
+        assert!(unet.contains_key("conv_in.weight"));
+        let clip = candle_core::safetensors::load(&converted.clip, &Device::Cpu)?;
+        assert!(clip.contains_key("text_model.embeddings.position_ids"));
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Read, Write};
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::stable_diffusion::model_cache;
+use crate::stable_diffusion::stable_diffusion_files::{ModelSource, ResolvedModelFile};
+
+/// Name of the lock file pinning the SHA-256 of model files, looked up in the working directory
+pub const LOCK_FILE: &str = "fantacat.lock";
+
+/// Content of the lock file, hashes keyed by `<repo>/<file>` for hub files and by path for local ones:
+///
+/// ```toml
+/// [sha256]
+/// "stabilityai/stable-diffusion-2-1/unet/diffusion_pytorch_model.fp16.safetensors" = "..."
+/// "/models/cat-vae.safetensors" = "..."
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LockFile {
+    #[serde(default)]
+    pub sha256: BTreeMap<String, String>,
+}
+
+impl LockFile {
+    /// An empty lock file when there is none
+    pub fn load<P: AsRef<Path>>(p: P) -> Result<Self> {
+        if !p.as_ref().is_file() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(p.as_ref())?;
+        toml::from_str(&content)
+            .map_err(|error| anyhow::anyhow!("Invalid lock file {}: {}", p.as_ref().display(), error))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, p: P) -> Result<()> {
+        std::fs::write(p, toml::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+pub fn lock_key(model_file: &ResolvedModelFile) -> String {
+    match &model_file.repo {
+        Some(repo) => format!("{}/{}", repo, model_file.file),
+        None => model_file.path.to_string_lossy().to_string()
+    }
+}
+
+pub fn sha256_file<P: AsRef<Path>>(p: P) -> Result<String> {
+    let mut file = std::fs::File::open(p)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The HF hub cache names the blobs of LFS files, the weights among them, after their SHA-256.
+/// Smaller files such as tokenizers get a git SHA-1 instead, which is not checked
+pub fn cache_blob_sha256(path: &Path) -> Option<String> {
+    let blob = std::fs::canonicalize(path).ok()?;
+    let name = blob.file_name()?.to_str()?;
+    let in_blobs = blob.parent().and_then(Path::file_name).is_some_and(|dir| dir == "blobs");
+    if in_blobs && name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(name.to_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Hash a file must have, pinned by the lock file or given by the hub
+pub fn expected_sha256(model_file: &ResolvedModelFile, lock: &LockFile) -> Option<String> {
+    match lock.sha256.get(&lock_key(model_file)) {
+        Some(sha256) => Some(sha256.to_lowercase()),
+        None if model_file.repo.is_some() => cache_blob_sha256(&model_file.path),
+        None => None
+    }
+}
+
+/// Checks the size of a safetensors file against its header, which catches truncated downloads without hashing
+pub fn check_safetensors_size(path: &Path) -> Result<()> {
+    let file_size = std::fs::metadata(path)?.len();
+    let mut file = std::fs::File::open(path)?;
+    let mut header_size = [0u8; 8];
+    if file.read_exact(&mut header_size).is_err() {
+        anyhow::bail!("{} is truncated, {} bytes", path.display(), file_size)
+    }
+    let header_size = u64::from_le_bytes(header_size);
+    if header_size > file_size - 8 {
+        anyhow::bail!("{} is truncated, its header alone needs {} bytes out of {}", path.display(), header_size, file_size)
+    }
+    let mut header = vec![0u8; header_size as usize];
+    file.read_exact(&mut header)?;
+    let header: BTreeMap<String, serde_json::Value> = serde_json::from_slice(&header)
+        .map_err(|error| anyhow::anyhow!("{} has an invalid safetensors header: {}", path.display(), error))?;
+
+    let data_size = header.iter()
+        .filter(|(name, _)| *name != "__metadata__")
+        .filter_map(|(_, tensor)| tensor.get("data_offsets")?.get(1)?.as_u64())
+        .max()
+        .unwrap_or(0);
+    let expected_size = 8 + header_size + data_size;
+    if file_size != expected_size {
+        anyhow::bail!("{} has {} bytes where its header describes {}, the download is likely partial", path.display(), file_size, expected_size)
+    }
+    Ok(())
+}
+
+/// Checks a model file before loading it, hashing it only when `hash` is set.
+/// Returns the SHA-256 when computed
+pub fn verify_model_file(model_file: &ResolvedModelFile, lock: &LockFile, hash: bool) -> Result<Option<String>> {
+    if model_file.path.extension().is_some_and(|extension| extension == "safetensors") {
+        check_safetensors_size(&model_file.path)?;
+    }
+    if !hash {
+        return Ok(None);
+    }
+    let sha256 = sha256_file(&model_file.path)?;
+    if let Some(expected) = expected_sha256(model_file, lock) {
+        if sha256 != expected {
+            anyhow::bail!("{} has SHA-256 {} instead of {}", model_file.path.display(), sha256, expected)
+        }
+    }
+    Ok(Some(sha256))
+}
+
+fn confirm(question: &str) -> bool {
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    print!("{} [y/N] ", question);
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Verifies every model file, offering in a terminal to fetch the corrupted hub files again.
+/// Returns the files along with their SHA-256 when hashed
+pub fn verify_model_files(model_files: Vec<ResolvedModelFile>, source: &ModelSource, lock: &LockFile, hash: bool) -> Result<Vec<(ResolvedModelFile, Option<String>)>> {
+    let mut verified = vec![];
+    for mut model_file in model_files {
+        let error = match verify_model_file(&model_file, lock, hash) {
+            Ok(sha256) => {
+                verified.push((model_file, sha256));
+                continue;
+            },
+            Err(error) => error
+        };
+        println!("{}: {}", model_file.component, error);
+        let repo = match &model_file.repo {
+            Some(repo) if !source.offline => repo.clone(),
+            _ => anyhow::bail!("Corrupted {} file {}: {}", model_file.component, model_file.path.display(), error)
+        };
+        if !confirm(&format!("Fetch {} from {} again?", model_file.file, repo)) {
+            anyhow::bail!("Corrupted {} file {}, remove it to fetch it again: {}", model_file.component, model_file.path.display(), error)
+        }
+        model_cache::remove_cached_file(&model_file.path)?;
+        model_file.path = model_cache::download_file(source, &repo, &model_file.file)?;
+        // hashed anyway, the file was just found corrupted
+        let sha256 = verify_model_file(&model_file, lock, true)?;
+        verified.push((model_file, sha256));
+    }
+    Ok(verified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn safetensors(data_size: u64) -> Vec<u8> {
+        let header = format!("{{\"cat\":{{\"dtype\":\"F32\",\"shape\":[2],\"data_offsets\":[0,{}]}}}}", data_size);
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(header.as_bytes());
+        bytes.extend(vec![0u8; data_size as usize]);
+        bytes
+    }
+
+    #[test]
+    fn checksums_safetensors_size() -> Result<()> {
+        let dir = crate::test_utils::TempDir::new("checksums-size")?;
+        let path = dir.join("cat.safetensors");
+        let bytes = safetensors(8);
+        std::fs::write(&path, &bytes)?;
+        check_safetensors_size(&path)?;
+
+        std::fs::write(&path, &bytes[..bytes.len() - 3])?;
+        assert!(check_safetensors_size(&path).unwrap_err().to_string().contains("partial"));
+        std::fs::write(&path, &bytes[..4])?;
+        assert!(check_safetensors_size(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn checksums_lock_file() -> Result<()> {
+        let dir = crate::test_utils::TempDir::new("checksums-lock")?;
+        let path = dir.join("tokenizer.json");
+        std::fs::write(&path, b"abc")?;
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(sha256_file(&path)?, abc);
+
+        let model_file = ResolvedModelFile { component: "tokenizer".to_string(), repo: None, file: "tokenizer.json".to_string(), path: path.clone() };
+        let mut lock = LockFile::default();
+        // nothing to compare with, the hash is still reported
+        assert_eq!(verify_model_file(&model_file, &lock, true)?, Some(abc.to_string()));
+        assert_eq!(verify_model_file(&model_file, &lock, false)?, None);
+
+        lock.sha256.insert(lock_key(&model_file), "0".repeat(64));
+        assert!(verify_model_file(&model_file, &lock, true).is_err());
+
+        let lock: LockFile = toml::from_str(&toml::to_string(&lock)?)?;
+        assert_eq!(expected_sha256(&model_file, &lock), Some("0".repeat(64)));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn checksums_cache_blob() -> Result<()> {
+        let repo_dir = crate::test_utils::TempDir::new("checksums-repo")?;
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let blob = repo_dir.join("blobs").join(abc);
+        std::fs::create_dir_all(blob.parent().unwrap())?;
+        std::fs::write(&blob, b"abc")?;
+        let path = repo_dir.join("snapshots/0123abcd/unet.bin");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::os::unix::fs::symlink(&blob, &path)?;
+
+        assert_eq!(cache_blob_sha256(&path), Some(abc.to_string()));
+        let model_file = ResolvedModelFile { component: "unet".to_string(), repo: Some("org/cat".to_string()), file: "unet.bin".to_string(), path: path.clone() };
+        assert!(verify_model_file(&model_file, &LockFile::default(), true).is_ok());
+
+        std::fs::write(&blob, b"ab")?;
+        assert!(verify_model_file(&model_file, &LockFile::default(), true).is_err());
+        Ok(())
+    }
+}
@@ -5,102 +5,541 @@ use anyhow;
 use candle_core::{Device, DType};
 
 
-use crate::stable_diffusion::stable_diffusion_files;
+use crate::prompt;
+use crate::stable_diffusion::{diffusers_folder, stable_diffusion_files};
 
 const CLIP_SPECIAL_TOKEN: &str = "<|endoftext|>";
 
+/// Splits the text the way the CLIP tokenizer does before the byte level BPE
+const CLIP_PRE_TOKENIZER_PATTERN: &str = r"<\|startoftext\|>|<\|endoftext\|>|'s|'t|'re|'ve|'m|'ll|'d|[\p{L}]+|[\p{N}]|[^\s\p{L}\p{N}]+";
+const CLIP_START_TOKEN: &str = "<|startoftext|>";
+
+/// A `tokenizer.json`, or the `vocab.json` of a slow CLIP tokenizer with its `merges.txt` alongside
+fn load_tokenizer(tokenizer_file: &std::path::Path) -> anyhow::Result<Tokenizer> {
+    if tokenizer_file.file_name().is_some_and(|name| name == diffusers_folder::TOKENIZER_VOCAB_FILE) {
+        let merges_file = tokenizer_file.with_file_name(diffusers_folder::TOKENIZER_MERGES_FILE);
+        return clip_bpe_tokenizer(tokenizer_file, &merges_file);
+    }
+    Tokenizer::from_file(tokenizer_file).map_err(anyhow::Error::msg)
+}
+
+/// Same tokenizer as the `tokenizer.json` transformers converts the slow CLIP tokenizer to
+pub fn clip_bpe_tokenizer(vocab_file: &std::path::Path, merges_file: &std::path::Path) -> anyhow::Result<Tokenizer> {
+    let vocab: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&std::fs::read_to_string(vocab_file)?)
+        .map_err(|error| anyhow::anyhow!("Invalid {}: {}", vocab_file.display(), error))?;
+    // the first line is the `#version` header
+    let merges: Vec<String> = std::fs::read_to_string(merges_file)?
+        .lines()
+        .filter(|line| !line.starts_with("#version") && !line.trim().is_empty())
+        .map(str::to_string)
+        .collect();
+    let token_id = |token: &str| {
+        vocab.get(token)
+            .and_then(|id| id.as_u64())
+            .ok_or_else(|| anyhow::anyhow!("No {} token in {}", token, vocab_file.display()))
+    };
+    let (start_id, end_id) = (token_id(CLIP_START_TOKEN)?, token_id(CLIP_SPECIAL_TOKEN)?);
+    let special_token = |id: u64, content: &str| serde_json::json!({
+        "id": id, "content": content, "single_word": false, "lstrip": false, "rstrip": false, "normalized": true, "special": true
+    });
+
+    let tokenizer = serde_json::json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [special_token(start_id, CLIP_START_TOKEN), special_token(end_id, CLIP_SPECIAL_TOKEN)],
+        "normalizer": {
+            "type": "Sequence",
+            "normalizers": [
+                { "type": "NFC" },
+                { "type": "Replace", "pattern": { "Regex": r"\s+" }, "content": " " },
+                { "type": "Lowercase" }
+            ]
+        },
+        "pre_tokenizer": {
+            "type": "Sequence",
+            "pretokenizers": [
+                { "type": "Split", "pattern": { "Regex": CLIP_PRE_TOKENIZER_PATTERN }, "behavior": "Removed", "invert": true },
+                { "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": false }
+            ]
+        },
+        "post_processor": {
+            "type": "RobertaProcessing",
+            "sep": [CLIP_SPECIAL_TOKEN, end_id],
+            "cls": [CLIP_START_TOKEN, start_id],
+            "trim_offsets": false,
+            "add_prefix_space": false
+        },
+        "decoder": { "type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true },
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": CLIP_SPECIAL_TOKEN,
+            "continuing_subword_prefix": "",
+            "end_of_word_suffix": "</w>",
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "vocab": vocab,
+            "merges": merges
+        }
+    });
+    tokenizer.to_string().parse::<Tokenizer>().map_err(anyhow::Error::msg)
+}
+
 pub fn get_tokenizer(tokenizer_file: Option<String>, sd_version: &stable_diffusion_files::StableDiffusionVersion) -> anyhow::Result<Tokenizer>{
 
     let tokenizer = stable_diffusion_files::StableDiffusionFiles::Tokenizer;
     let sd = stable_diffusion_files::create_sd_from_version(sd_version);
     let tokenizer_file = sd.get(&tokenizer, tokenizer_file, true)?;
 
-    let tokenizer = Tokenizer::from_file(tokenizer_file).map_err(anyhow::Error::msg)?;
+    let tokenizer = load_tokenizer(&tokenizer_file)?;
 
     Ok(tokenizer)
     
 }
 
-pub fn get_embedding_model(embedding_file: Option<String>, stable_diffusion_config: &stable_diffusion::StableDiffusionConfig, sd_version: &stable_diffusion_files::StableDiffusionVersion, device: &Device) -> anyhow::Result<stable_diffusion::clip::ClipTextTransformer>{
+/// Tokenizer of the second text encoder of SDXL based versions
+pub fn get_tokenizer2(tokenizer_file: Option<String>, sd_version: &stable_diffusion_files::StableDiffusionVersion) -> anyhow::Result<Tokenizer>{
+
+    let tokenizer = stable_diffusion_files::StableDiffusionFiles::Tokenizer2;
+    let sd = stable_diffusion_files::create_sd_from_version(sd_version);
+    let tokenizer_file = sd.get(&tokenizer, tokenizer_file, true)?;
+
+    let tokenizer = load_tokenizer(&tokenizer_file)?;
+
+    Ok(tokenizer)
+}
+
+pub fn get_embedding_model(embedding_file: Option<String>, stable_diffusion_config: &stable_diffusion::StableDiffusionConfig, sd_version: &stable_diffusion_files::StableDiffusionVersion, device: &Device, dtype: DType) -> anyhow::Result<stable_diffusion::clip::ClipTextTransformer>{
     let clip = stable_diffusion_files::StableDiffusionFiles::Clip;
     let sd = stable_diffusion_files::create_sd_from_version(sd_version);
-    let clip_weights_file = sd.get(&clip, embedding_file, true)?;
+    let clip_weights_file = sd.get(&clip, embedding_file, dtype == DType::F16)?;
 
-    let text_model = stable_diffusion::build_clip_transformer(&stable_diffusion_config.clip, clip_weights_file, device, DType::F16)?;
+    let text_model = stable_diffusion::build_clip_transformer(&stable_diffusion_config.clip, clip_weights_file, device, dtype)?;
 
     
     Ok(text_model)
 }
 
+/// Second text encoder of SDXL based versions, configured by `clip2` in the stable diffusion config
+pub fn get_embedding_model2(embedding_file: Option<String>, stable_diffusion_config: &stable_diffusion::StableDiffusionConfig, sd_version: &stable_diffusion_files::StableDiffusionVersion, device: &Device, dtype: DType) -> anyhow::Result<stable_diffusion::clip::ClipTextTransformer>{
+    let clip_config = match &stable_diffusion_config.clip2 {
+        Some(clip_config) => clip_config,
+        None => anyhow::bail!("{:?} has no second text encoder", sd_version)
+    };
+    let clip = stable_diffusion_files::StableDiffusionFiles::Clip2;
+    let sd = stable_diffusion_files::create_sd_from_version(sd_version);
+    let clip_weights_file = sd.get(&clip, embedding_file, dtype == DType::F16)?;
+
+    let text_model = stable_diffusion::build_clip_transformer(clip_config, clip_weights_file, device, dtype)?;
+
+    Ok(text_model)
+}
+
+/// Projection of the pooled output of the second text encoder of SDXL based versions,
+/// stored next to the text model in the weights of `CLIPTextModelWithProjection`
+pub fn get_text_projection(embedding_file: Option<String>, stable_diffusion_config: &stable_diffusion::StableDiffusionConfig, sd_version: &stable_diffusion_files::StableDiffusionVersion, device: &Device, dtype: DType) -> anyhow::Result<candle_nn::Linear>{
+    let clip_config = match &stable_diffusion_config.clip2 {
+        Some(clip_config) => clip_config,
+        None => anyhow::bail!("{:?} has no second text encoder", sd_version)
+    };
+    let clip = stable_diffusion_files::StableDiffusionFiles::Clip2;
+    let sd = stable_diffusion_files::create_sd_from_version(sd_version);
+    let clip_weights_file = sd.get(&clip, embedding_file, dtype == DType::F16)?;
+
+    let vs = unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[clip_weights_file], dtype, device)? };
+    let text_projection = candle_nn::linear_no_bias(clip_config.embed_dim, clip_config.projection_dim, vs.pp("text_projection"))?;
+
+    Ok(text_projection)
+}
+
 
-fn get_padding_id(tokenizer: &Tokenizer, stable_diffusion_config: &stable_diffusion::StableDiffusionConfig) -> u32{
+fn get_padding_id(tokenizer: &Tokenizer, clip_config: &stable_diffusion::clip::Config) -> u32{
     // padding id depends on passed configuration
-    let pad_id = match &stable_diffusion_config.clip.pad_with {
+    let pad_id = match &clip_config.pad_with {
         Some(padding) => *tokenizer.get_vocab(true).get(padding.as_str()).unwrap(),
         None => *tokenizer.get_vocab(true).get(CLIP_SPECIAL_TOKEN).unwrap()
     };
     pad_id
 }
 
-pub fn encode_prompt(prompt: &str, tokenizer: &Tokenizer, stable_diffusion_config: &stable_diffusion::StableDiffusionConfig, device: &candle_core::Device) -> anyhow::Result<candle_core::Tensor>{
+/// Token ids of a prompt split in CLIP context windows of shape (n_chunks, max_position_embeddings),
+/// with the emphasis weight of every token
+pub struct EncodedPrompt {
+    pub tokens: candle_core::Tensor,
+    /// None when every token has the default weight of 1
+    pub weights: Option<candle_core::Tensor>,
+}
 
-    let tokens = tokenizer
-        .encode(prompt, true)
+impl EncodedPrompt {
+    pub fn n_chunks(&self) -> anyhow::Result<usize> {
+        Ok(self.tokens.dim(0)?)
+    }
+}
+
+/// How prompts longer than a single CLIP context window are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptLength {
+    /// Fail when the prompt does not fit in `max_position_embeddings` tokens
+    Strict,
+    /// Split the prompt in windows encoded separately, padding with empty windows up to `min_chunks`
+    Chunked { min_chunks: usize },
+}
+
+pub fn encode_prompt(prompt: &str, tokenizer: &Tokenizer, stable_diffusion_config: &stable_diffusion::StableDiffusionConfig, device: &candle_core::Device) -> anyhow::Result<EncodedPrompt>{
+    encode_prompt_with_config(prompt, tokenizer, &stable_diffusion_config.clip, PromptLength::Chunked { min_chunks: 1 }, device)
+}
+
+/// Prompt tokens with the weight of each, plus the start and end of text tokens that wrap every window
+struct WeightedTokens {
+    tokens: Vec<u32>,
+    weights: Vec<f32>,
+    start_tokens: Vec<u32>,
+    end_tokens: Vec<u32>,
+}
+
+/// Tokenizes every weighted segment of the prompt on its own, keeping the weight of each token
+fn tokenize_weighted_prompt(prompt: &str, tokenizer: &Tokenizer) -> anyhow::Result<WeightedTokens>{
+    // start and end of text tokens, as added by the tokenizer post processor
+    let special_tokens = tokenizer
+        .encode("", true)
         .map_err(anyhow::Error::msg)?
         .get_ids()
         .to_vec();
+    let (start_tokens, end_tokens) = special_tokens.split_at(special_tokens.len().min(1));
+
+    let mut tokens = vec![];
+    let mut weights = vec![];
+    for (text, weight) in prompt::prompt_weights::parse_weighted_prompt(prompt) {
+        let segment_tokens = tokenizer
+            .encode(text, false)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        weights.extend(std::iter::repeat(weight as f32).take(segment_tokens.len()));
+        tokens.extend(segment_tokens);
+    }
 
-    let padding_id = get_padding_id(&tokenizer, &stable_diffusion_config);
-    
-    let padded_tokens: Vec<_>;
-    let n_tokens = tokens.len();
-    if  n_tokens <= stable_diffusion_config.clip.max_position_embeddings{
-        padded_tokens = tokens
-        .into_iter()
-        .chain(std::iter::repeat(padding_id)
-            .take(stable_diffusion_config.clip.max_position_embeddings - n_tokens))
+    Ok(WeightedTokens { tokens, weights, start_tokens: start_tokens.to_vec(), end_tokens: end_tokens.to_vec() })
+}
+
+/// Number of context windows the prompt needs once tokenized
+pub fn count_chunks(prompt: &str, tokenizer: &Tokenizer, clip_config: &stable_diffusion::clip::Config) -> anyhow::Result<usize>{
+    let weighted_tokens = tokenize_weighted_prompt(prompt, tokenizer)?;
+    let window = chunk_window(&weighted_tokens, clip_config)?;
+    Ok(weighted_tokens.tokens.len().div_ceil(window).max(1))
+}
+
+/// A prompt token as seen by the text encoder
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptToken {
+    pub id: u32,
+    pub text: String,
+    pub weight: f32,
+}
+
+/// Tokens of the prompt with their emphasis weight, without the start and end of text tokens
+pub fn tokenize_prompt(prompt: &str, tokenizer: &Tokenizer) -> anyhow::Result<Vec<PromptToken>>{
+    let weighted_tokens = tokenize_weighted_prompt(prompt, tokenizer)?;
+    let prompt_tokens = weighted_tokens.tokens.iter()
+        .zip(weighted_tokens.weights.iter())
+        .map(|(&id, &weight)| PromptToken {
+            id,
+            text: tokenizer.id_to_token(id).unwrap_or_default(),
+            weight,
+        })
         .collect();
-    } 
-    else {
-        // prompt too long, panic
-        anyhow::bail!("Prompt is too long ({}), max tokens allowed {}", n_tokens, stable_diffusion_config.clip.max_position_embeddings)
+    Ok(prompt_tokens)
+}
+
+/// Prompt tokens fitting in a window, once the start and end of text tokens are added
+fn chunk_window(weighted_tokens: &WeightedTokens, clip_config: &stable_diffusion::clip::Config) -> anyhow::Result<usize>{
+    let n_special_tokens = weighted_tokens.start_tokens.len() + weighted_tokens.end_tokens.len();
+    if clip_config.max_position_embeddings <= n_special_tokens {
+        anyhow::bail!("Context of {} tokens is too small", clip_config.max_position_embeddings)
     }
+    Ok(clip_config.max_position_embeddings - n_special_tokens)
+}
+
+/// Same as `encode_prompt` for an explicit text encoder configuration, e.g. `clip2` for the second SDXL encoder
+pub fn encode_prompt_with_config(prompt: &str, tokenizer: &Tokenizer, clip_config: &stable_diffusion::clip::Config, prompt_length: PromptLength, device: &candle_core::Device) -> anyhow::Result<EncodedPrompt>{
+
+    let weighted_tokens = tokenize_weighted_prompt(prompt, tokenizer)?;
+    let window = chunk_window(&weighted_tokens, clip_config)?;
+    let max_tokens = clip_config.max_position_embeddings;
+
+    let n_chunks = weighted_tokens.tokens.len().div_ceil(window).max(1);
+    let n_chunks = match prompt_length {
+        PromptLength::Strict => {
+            if n_chunks > 1 {
+                let n_tokens = weighted_tokens.tokens.len() + weighted_tokens.start_tokens.len() + weighted_tokens.end_tokens.len();
+                anyhow::bail!("Prompt is too long ({}), max tokens allowed {}", n_tokens, max_tokens)
+            }
+            n_chunks
+        },
+        PromptLength::Chunked { min_chunks } => n_chunks.max(min_chunks)
+    };
 
+    let padding_id = get_padding_id(tokenizer, clip_config);
+
+    let mut padded_tokens: Vec<u32> = Vec::with_capacity(n_chunks * max_tokens);
+    let mut padded_weights: Vec<f32> = Vec::with_capacity(n_chunks * max_tokens);
+    for chunk_idx in 0..n_chunks {
+        // trailing chunks past the end of the prompt only hold start and end of text tokens
+        let start = (chunk_idx * window).min(weighted_tokens.tokens.len());
+        let end = ((chunk_idx + 1) * window).min(weighted_tokens.tokens.len());
+
+        let chunk_tokens: Vec<u32> = weighted_tokens.start_tokens.iter()
+            .chain(weighted_tokens.tokens[start..end].iter())
+            .chain(weighted_tokens.end_tokens.iter())
+            .copied()
+            .collect();
+        let n_tokens = chunk_tokens.len();
+        padded_tokens.extend(chunk_tokens);
+        padded_tokens.extend(std::iter::repeat(padding_id).take(max_tokens - n_tokens));
+
+        padded_weights.extend(std::iter::repeat(1f32).take(weighted_tokens.start_tokens.len()));
+        padded_weights.extend_from_slice(&weighted_tokens.weights[start..end]);
+        padded_weights.extend(std::iter::repeat(1f32).take(max_tokens - weighted_tokens.start_tokens.len() - (end - start)));
+    }
 
+    let weights = if padded_weights.iter().all(|weight| *weight == 1.0) {
+        None
+    } else {
+        Some(candle_core::Tensor::from_vec(padded_weights, (n_chunks, max_tokens), device)?)
+    };
 
-    let encoded_prompt = candle_core::Tensor::new(padded_tokens.as_slice(), device)?.unsqueeze(0)?;
-    Ok(encoded_prompt)
+    let encoded_prompt = candle_core::Tensor::from_vec(padded_tokens, (n_chunks, max_tokens), device)?;
+    Ok(EncodedPrompt { tokens: encoded_prompt, weights })
 
 }
 
+/// Scales the embedding of every token by its weight, then rescales the whole prompt
+/// so that its mean stays the one of the unweighted embeddings.
+fn apply_weights(embeddings: candle_core::Tensor, weights: &Option<candle_core::Tensor>) -> anyhow::Result<candle_core::Tensor>{
+    match weights {
+        None => Ok(embeddings),
+        Some(weights) => {
+            let original_mean = embeddings.to_dtype(DType::F32)?.mean_all()?.to_scalar::<f32>()?;
+            let weights = weights.unsqueeze(candle_core::D::Minus1)?.to_dtype(embeddings.dtype())?;
+            let weighted = embeddings.broadcast_mul(&weights)?;
+            let weighted_mean = weighted.to_dtype(DType::F32)?.mean_all()?.to_scalar::<f32>()?;
+            Ok((weighted * (original_mean / weighted_mean) as f64)?)
+        }
+    }
+}
+
+/// Each context window goes through the CLIP transformer on its own,
+/// then the windows are concatenated back into a (1, n_chunks * max_position_embeddings, hidden) sequence
+pub fn get_embeddings(encoded_prompt: &EncodedPrompt, embedding_model: &stable_diffusion::clip::ClipTextTransformer) -> anyhow::Result<candle_core::Tensor>{
+
+    let hidden_states = embedding_model.forward(&encoded_prompt.tokens)?;
+    join_windows(hidden_states, &encoded_prompt.weights)
 
-pub fn get_embeddings(encoded_prompt: &candle_core::Tensor, embedding_model: &stable_diffusion::clip::ClipTextTransformer) -> anyhow::Result<candle_core::Tensor>{
+}
+
+/// Weighted hidden states of every context window, concatenated back into a single sequence
+fn join_windows(hidden_states: candle_core::Tensor, weights: &Option<candle_core::Tensor>) -> anyhow::Result<candle_core::Tensor>{
+    let embeddings = apply_weights(hidden_states, weights)?;
+    let (n_chunks, seq_len, hidden_size) = embeddings.dims3()?;
+    Ok(embeddings.reshape((1, n_chunks * seq_len, hidden_size))?)
+}
 
-    let embeddings = embedding_model.forward(encoded_prompt)?;
-    Ok(embeddings)
+/// Position of the end of text token in the first context window, the one with the highest id
+fn end_of_text_position(encoded_prompt: &EncodedPrompt) -> anyhow::Result<usize>{
+    let tokens = encoded_prompt.tokens.get(0)?.to_vec1::<u32>()?;
+    let max_token = tokens.iter().copied().max().unwrap_or_default();
+    Ok(tokens.iter().position(|token| *token == max_token).unwrap_or_default())
+}
 
+/// Pooled embeddings of a prompt of shape (1, projection_dim): the final hidden state of its end of text token
+/// in the first context window, projected. The emphasis weights only apply to the hidden states
+pub fn get_pooled_embeddings(encoded_prompt: &EncodedPrompt, hidden_states: &candle_core::Tensor, text_projection: &candle_nn::Linear) -> anyhow::Result<candle_core::Tensor>{
+    let position = end_of_text_position(encoded_prompt)?;
+    let pooled = hidden_states.get(0)?.get(position)?.unsqueeze(0)?;
+    Ok(text_projection.forward(&pooled)?)
 }
 
-pub fn get_embeddings_for_guidance_scale(encoded_prompt: &candle_core::Tensor, encoded_uncond_prompt: &candle_core::Tensor, embedding_model: &stable_diffusion::clip::ClipTextTransformer) -> anyhow::Result<candle_core::Tensor>{
-    let embeddings = embedding_model.forward(encoded_prompt)?;
-    let uncond_embeddings = embedding_model.forward(&encoded_uncond_prompt)?;
+pub fn get_embeddings_for_guidance_scale(encoded_prompt: &EncodedPrompt, encoded_uncond_prompt: &EncodedPrompt, embedding_model: &stable_diffusion::clip::ClipTextTransformer) -> anyhow::Result<candle_core::Tensor>{
+    let embeddings = get_embeddings(encoded_prompt, embedding_model)?;
+    let uncond_embeddings = get_embeddings(encoded_uncond_prompt, embedding_model)?;
 
     let final_embeddings = candle_core::Tensor::cat(&[uncond_embeddings, embeddings], 0)?;
 
     Ok(final_embeddings)
 }
 
+/// A CLIP text encoder together with its tokenizer and configuration
+pub struct TextEncoder {
+    tokenizer: Tokenizer,
+    model: stable_diffusion::clip::ClipTextTransformer,
+    config: stable_diffusion::clip::Config,
+    /// Set for the encoder whose pooled embeddings condition the UNet, the second one of SDXL based versions
+    text_projection: Option<candle_nn::Linear>,
+}
+
+/// Text conditioning of the UNet, with the unconditional embeddings first in the batch when guided
+pub struct TextEmbeddings {
+    /// (batch, sequence, features)
+    pub hidden_states: candle_core::Tensor,
+    /// (batch, projection_dim), only for SDXL based versions
+    pub pooled: Option<candle_core::Tensor>,
+}
+
+impl TextEncoder {
+    pub fn encode(&self, prompt: &str, prompt_length: PromptLength, device: &Device) -> anyhow::Result<EncodedPrompt> {
+        encode_prompt_with_config(prompt, &self.tokenizer, &self.config, prompt_length, device)
+    }
+
+    pub fn count_chunks(&self, prompt: &str) -> anyhow::Result<usize> {
+        count_chunks(prompt, &self.tokenizer, &self.config)
+    }
+
+    /// Embeddings of a single prompt
+    fn prompt_embeddings(&self, prompt: &str, prompt_length: PromptLength, device: &Device) -> anyhow::Result<TextEmbeddings> {
+        let encoded_prompt = self.encode(prompt, prompt_length, device)?;
+        let hidden_states = self.model.forward(&encoded_prompt.tokens)?;
+        let pooled = match &self.text_projection {
+            Some(text_projection) => Some(get_pooled_embeddings(&encoded_prompt, &hidden_states, text_projection)?),
+            None => None
+        };
+        let hidden_states = join_windows(hidden_states, &encoded_prompt.weights)?;
+        Ok(TextEmbeddings { hidden_states, pooled })
+    }
+
+    pub fn embeddings(&self, prompt: &str, uncond_prompt: Option<&str>, prompt_length: PromptLength, device: &Device) -> anyhow::Result<TextEmbeddings> {
+        let embeddings = self.prompt_embeddings(prompt, prompt_length, device)?;
+        let uncond_prompt = match uncond_prompt {
+            Some(uncond_prompt) => uncond_prompt,
+            None => return Ok(embeddings)
+        };
+        let uncond_embeddings = self.prompt_embeddings(uncond_prompt, prompt_length, device)?;
+        let pooled = match (uncond_embeddings.pooled, embeddings.pooled) {
+            (Some(uncond_pooled), Some(pooled)) => Some(candle_core::Tensor::cat(&[uncond_pooled, pooled], 0)?),
+            _ => None
+        };
+        Ok(TextEmbeddings {
+            hidden_states: candle_core::Tensor::cat(&[uncond_embeddings.hidden_states, embeddings.hidden_states], 0)?,
+            pooled,
+        })
+    }
+}
+
+/// Loads every text encoder used by the version: one CLIP model, or two for SDXL based versions.
+/// Files missing from `model_files` are fetched from the hub.
+pub fn get_text_encoders(stable_diffusion_config: &stable_diffusion::StableDiffusionConfig, sd_version: &stable_diffusion_files::StableDiffusionVersion, model_files: &[stable_diffusion_files::ResolvedModelFile], device: &Device, dtype: DType) -> anyhow::Result<Vec<TextEncoder>>{
+    let file = |sd_file| stable_diffusion_files::model_file_path(model_files, &sd_file);
+    let mut text_encoders = vec![TextEncoder {
+        tokenizer: get_tokenizer(file(stable_diffusion_files::StableDiffusionFiles::Tokenizer), sd_version)?,
+        model: get_embedding_model(file(stable_diffusion_files::StableDiffusionFiles::Clip), stable_diffusion_config, sd_version, device, dtype)?,
+        config: stable_diffusion_config.clip.clone(),
+        text_projection: None,
+    }];
+
+    if sd_version.has_second_text_encoder() {
+        if let Some(clip2) = &stable_diffusion_config.clip2 {
+            text_encoders.push(TextEncoder {
+                tokenizer: get_tokenizer2(file(stable_diffusion_files::StableDiffusionFiles::Tokenizer2), sd_version)?,
+                model: get_embedding_model2(file(stable_diffusion_files::StableDiffusionFiles::Clip2), stable_diffusion_config, sd_version, device, dtype)?,
+                config: clip2.clone(),
+                text_projection: Some(get_text_projection(file(stable_diffusion_files::StableDiffusionFiles::Clip2), stable_diffusion_config, sd_version, device, dtype)?),
+            });
+        }
+    }
+    Ok(text_encoders)
+}
+
+/// Text conditioning for the UNet: the hidden states of every encoder concatenated on the feature dimension,
+/// plus the pooled embeddings of SDXL based versions, with the unconditional embeddings first in the batch
+/// when `uncond_prompt` is set.
+/// Unless `strict_length` is set, long prompts are split in context windows and every prompt of every encoder
+/// is padded to the same number of windows, so that the sequences line up.
+pub fn get_text_embeddings(text_encoders: &[TextEncoder], prompt: &str, uncond_prompt: Option<&str>, strict_length: bool, device: &Device) -> anyhow::Result<TextEmbeddings>{
+    let prompt_length = if strict_length {
+        PromptLength::Strict
+    } else {
+        let mut min_chunks = 1;
+        for text_encoder in text_encoders {
+            min_chunks = min_chunks.max(text_encoder.count_chunks(prompt)?);
+            if let Some(uncond_prompt) = uncond_prompt {
+                min_chunks = min_chunks.max(text_encoder.count_chunks(uncond_prompt)?);
+            }
+        }
+        PromptLength::Chunked { min_chunks }
+    };
+
+    let mut hidden_states = vec![];
+    let mut pooled = None;
+    for text_encoder in text_encoders {
+        let embeddings = text_encoder.embeddings(prompt, uncond_prompt, prompt_length, device)?;
+        hidden_states.push(embeddings.hidden_states);
+        pooled = embeddings.pooled.or(pooled);
+    }
+
+    let hidden_states = candle_core::Tensor::cat(&hidden_states, candle_core::D::Minus1)?;
+    Ok(TextEmbeddings { hidden_states, pooled })
+}
+
+
+/// Repeats text embeddings, hidden states or pooled ones, for every image of a batch. With guidance the
+/// unconditional and conditional embeddings are grouped, matching latents duplicated as `cat([latents, latents])`.
+pub fn repeat_for_batch(embeddings: &candle_core::Tensor, batch_size: usize, use_guidance_scale: bool) -> anyhow::Result<candle_core::Tensor>{
+    let mut repeats = vec![1; embeddings.rank()];
+    repeats[0] = batch_size;
+    if use_guidance_scale {
+        let embeddings = embeddings.chunk(2, 0)?;
+        let uncond_embeddings = embeddings[0].repeat(repeats.clone())?;
+        let cond_embeddings = embeddings[1].repeat(repeats)?;
+        Ok(candle_core::Tensor::cat(&[uncond_embeddings, cond_embeddings], 0)?)
+    } else {
+        Ok(embeddings.repeat(repeats)?)
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn stable_diffusion_repeat_for_batch() -> anyhow::Result<()>{
+        let uncond = candle_core::Tensor::zeros((1, 2, 3), DType::F32, &candle_core::Device::Cpu)?;
+        let cond = candle_core::Tensor::ones((1, 2, 3), DType::F32, &candle_core::Device::Cpu)?;
+        let embeddings = candle_core::Tensor::cat(&[&uncond, &cond], 0)?;
+
+        let batch = repeat_for_batch(&embeddings, 3, true)?;
+        let firsts: Vec<f32> = (0..6)
+            .map(|i| batch.get(i)?.flatten_all()?.to_vec1::<f32>().map(|values| values[0]))
+            .collect::<candle_core::Result<_>>()?;
+        assert_eq!(firsts, vec![0., 0., 0., 1., 1., 1.]);
+
+        let batch = repeat_for_batch(&cond, 3, false)?;
+        assert_eq!(batch.dims(), &[3, 2, 3]);
+
+        let pooled = candle_core::Tensor::zeros((2, 5), DType::F32, &candle_core::Device::Cpu)?;
+        assert_eq!(repeat_for_batch(&pooled, 3, true)?.dims(), &[6, 5]);
+        Ok(())
+    }
+
     #[test]
     fn stable_diffusion_tokenizer(){
         let tokenizer = get_tokenizer(None, &stable_diffusion_files::StableDiffusionVersion::Turbo);
 
         assert!(tokenizer.is_ok())
     }
+    #[test]
+    fn stable_diffusion_clip_bpe_tokenizer() -> anyhow::Result<()> {
+        let dir = crate::test_utils::TempDir::new("bpe")?;
+        let vocab_file = dir.join(diffusers_folder::TOKENIZER_VOCAB_FILE);
+        std::fs::write(&vocab_file, r#"{"<|startoftext|>": 0, "<|endoftext|>": 1, "a</w>": 2, "c": 3, "a": 4, "t</w>": 5, "at</w>": 6, "cat</w>": 7}"#)?;
+        std::fs::write(dir.join(diffusers_folder::TOKENIZER_MERGES_FILE), "#version: 0.2\na t</w>\nc at</w>\n")?;
+
+        let tokenizer = load_tokenizer(&vocab_file)?;
+        let encoding = tokenizer.encode("A  Cat", true).map_err(anyhow::Error::msg)?;
+        assert_eq!(encoding.get_ids(), &[0, 2, 7, 1]);
+        Ok(())
+    }
+
     #[test]
     fn stable_diffusion_get_padding_id() -> anyhow::Result<()>{
 
@@ -109,7 +548,7 @@ mod tests {
         let sd_config = stable_diffusion::StableDiffusionConfig::sdxl(None, height, width);
         let tokenizer = get_tokenizer(None, &stable_diffusion_files::StableDiffusionVersion::Xl)?;
         // assumes padding token in clip config has been set to None
-        let padding_id = get_padding_id(&tokenizer, &sd_config);
+        let padding_id = get_padding_id(&tokenizer, &sd_config.clip);
         assert_eq!(padding_id, *tokenizer.get_vocab(true).get("!").unwrap());
         Ok(())
 
@@ -138,7 +577,7 @@ mod tests {
         let height: Option<usize> = Some(480 as usize);
         let sd_config = stable_diffusion::StableDiffusionConfig::v1_5(None, height, width);
 
-        let embedding_model = get_embedding_model(None, &sd_config, &stable_diffusion_files::StableDiffusionVersion::V1_5, &candle_core::Device::Cpu);
+        let embedding_model = get_embedding_model(None, &sd_config, &stable_diffusion_files::StableDiffusionVersion::V1_5, &candle_core::Device::Cpu, DType::F32);
         assert!(embedding_model.is_ok());
         Ok(())
  
@@ -158,7 +597,7 @@ mod tests {
         // assumes padding token in clip config has been set to None
         let encoded_prompt = encode_prompt(prompt, &tokenizer, &sd_config, &candle_core::Device::Cpu)?;
         let encoded_uncond_prompt = encode_prompt(uncond_prompt, &tokenizer, &sd_config, &candle_core::Device::Cpu)?;
-        let embedding_model = get_embedding_model(None, &sd_config, &stable_diffusion_files::StableDiffusionVersion::Turbo, &candle_core::Device::Cpu)?;
+        let embedding_model = get_embedding_model(None, &sd_config, &stable_diffusion_files::StableDiffusionVersion::Turbo, &candle_core::Device::Cpu, DType::F32)?;
 
         let embeddings = get_embeddings_for_guidance_scale(&encoded_prompt, &encoded_uncond_prompt, &embedding_model);
 
@@ -167,7 +606,7 @@ mod tests {
         if let Ok(embs) = embeddings {
             
             let embeddings_size: &candle_core::Shape = embs.shape();
-            let encoded_prompt_size: &candle_core::Shape = encoded_prompt.shape();
+            let encoded_prompt_size: &candle_core::Shape = encoded_prompt.tokens.shape();
             assert_eq!(embeddings_size.rank(), 3);
             assert_eq!(encoded_prompt_size.rank(), 2);
             assert_eq!(embeddings_size.clone().into_dims()[1], encoded_prompt_size.clone().into_dims()[1]);
@@ -177,5 +616,78 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn stable_diffusion_text_embeddings_dual_encoder() -> anyhow::Result<()>{
+        let prompt: &str = "Test sentence";
+
+        let width = Some(512 as usize);
+        let height: Option<usize> = Some(512 as usize);
+        let sd_version = stable_diffusion_files::StableDiffusionVersion::Turbo;
+        let sd_config = stable_diffusion::StableDiffusionConfig::sdxl_turbo(None, height, width);
+        let text_encoders = get_text_encoders(&sd_config, &sd_version, &[], &candle_core::Device::Cpu, DType::F32)?;
+        assert_eq!(text_encoders.len(), 2);
+
+        let embeddings = get_text_embeddings(&text_encoders, prompt, Some(""), false, &candle_core::Device::Cpu)?;
+        // 768 features from CLIP ViT-L plus 1280 from OpenCLIP ViT-bigG
+        assert_eq!(embeddings.hidden_states.dims()[0], 2);
+        assert_eq!(embeddings.hidden_states.dims()[2], 2048);
+        // the pooled embeddings come from OpenCLIP ViT-bigG only
+        assert_eq!(embeddings.pooled.map(|pooled| pooled.dims().to_vec()), Some(vec![2, 1280]));
+        Ok(())
+    }
+
+    #[test]
+    fn stable_diffusion_encode_weighted_prompt() -> anyhow::Result<()>{
+        let width = Some(512 as usize);
+        let height: Option<usize> = Some(512 as usize);
+        let sd_config = stable_diffusion::StableDiffusionConfig::v1_5(None, height, width);
+        let tokenizer = get_tokenizer(None, &stable_diffusion_files::StableDiffusionVersion::V1_5)?;
 
-}
\ No newline at end of file
+        let plain = encode_prompt("a cat with a fluffy tail", &tokenizer, &sd_config, &candle_core::Device::Cpu)?;
+        let weighted = encode_prompt("a cat with a (fluffy tail:1.4)", &tokenizer, &sd_config, &candle_core::Device::Cpu)?;
+
+        // the emphasis syntax is stripped from the tokens and only kept as weights
+        assert!(plain.weights.is_none());
+        assert_eq!(plain.tokens.to_vec2::<u32>()?, weighted.tokens.to_vec2::<u32>()?);
+        let weights = weighted.weights.unwrap().to_vec2::<f32>()?;
+        assert_eq!(weights[0].len(), sd_config.clip.max_position_embeddings);
+        assert!(weights[0].iter().any(|weight| (*weight - 1.4).abs() < 1e-6));
+        Ok(())
+    }
+
+    #[test]
+    fn stable_diffusion_encode_long_prompt() -> anyhow::Result<()>{
+        let prompt = "a fluffy cat sitting on a sofa ".repeat(20);
+
+        let width = Some(512 as usize);
+        let height: Option<usize> = Some(512 as usize);
+        let sd_config = stable_diffusion::StableDiffusionConfig::v1_5(None, height, width);
+        let tokenizer = get_tokenizer(None, &stable_diffusion_files::StableDiffusionVersion::V1_5)?;
+        let device = candle_core::Device::Cpu;
+
+        let chunked = encode_prompt(&prompt, &tokenizer, &sd_config, &device)?;
+        let n_chunks = count_chunks(&prompt, &tokenizer, &sd_config.clip)?;
+        assert!(n_chunks > 1);
+        assert_eq!(chunked.tokens.dims(), &[n_chunks, sd_config.clip.max_position_embeddings]);
+
+        let strict = encode_prompt_with_config(&prompt, &tokenizer, &sd_config.clip, PromptLength::Strict, &device);
+        assert!(strict.is_err());
+
+        // the unconditional prompt gets padded with empty windows
+        let uncond = encode_prompt_with_config("", &tokenizer, &sd_config.clip, PromptLength::Chunked { min_chunks: n_chunks }, &device)?;
+        assert_eq!(uncond.n_chunks()?, n_chunks);
+        Ok(())
+    }
+
+    #[test]
+    fn stable_diffusion_tokenize_prompt() -> anyhow::Result<()>{
+        let tokenizer = get_tokenizer(None, &stable_diffusion_files::StableDiffusionVersion::V1_5)?;
+
+        let tokens = tokenize_prompt("a (fluffy:1.2) cat", &tokenizer)?;
+        assert_eq!(tokens.len(), 3);
+        assert_eq!(tokens[1].text, "fluffy</w>");
+        assert!((tokens[1].weight - 1.2).abs() < 1e-6);
+        assert_eq!(tokens[2].weight, 1.0);
+        Ok(())
+    }
+}
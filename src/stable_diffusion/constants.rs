@@ -7,11 +7,16 @@ pub const REPO_1_5: &str = "stable-diffusion-v1-5/stable-diffusion-v1-5";
 pub const REPO_2_1: &str = "stabilityai/stable-diffusion-2-1";
 pub const REPO_X1: &str = "stabilityai/stable-diffusion-xl-base-1.0";
 pub const REPO_TURBO: &str = "stabilityai/sdxl-turbo";
+pub const REPO_1_5_INPAINT: &str = "stable-diffusion-v1-5/stable-diffusion-inpainting";
+pub const REPO_2_INPAINT: &str = "stabilityai/stable-diffusion-2-inpainting";
+pub const REPO_X1_INPAINT: &str = "diffusers/stable-diffusion-xl-1.0-inpainting-0.1";
 pub const REPO_VAE_X1TURBO_FP16: &str = "madebyollin/sdxl-vae-fp16-fix";
 
 pub const MODELFILE_TOKENIZER: &str = "tokenizer.json";
 pub const MODELFILE_CLIP: &str = "text_encoder/model.safetensors";
 pub const MODELFILE_CLIP_FP16: &str = "text_encoder/model.fp16.safetensors";
+pub const MODELFILE_CLIP2: &str = "text_encoder_2/model.safetensors";
+pub const MODELFILE_CLIP2_FP16: &str = "text_encoder_2/model.fp16.safetensors";
 pub const MODELFILE_UNET: &str = "unet/diffusion_pytorch_model.safetensors";
 pub const MODELFILE_UNET_FP16: &str = "unet/diffusion_pytorch_model.fp16.safetensors";
 pub const MODELFILE_VAE: &str = "vae/diffusion_pytorch_model.safetensors";
@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::stable_diffusion::stable_diffusion_files::{StableDiffusionFiles, StableDiffusionVersion};
+
+pub const MODEL_INDEX_FILE: &str = "model_index.json";
+/// Files of the slow CLIP tokenizer, the only ones most pipelines save
+pub const TOKENIZER_VOCAB_FILE: &str = "vocab.json";
+pub const TOKENIZER_MERGES_FILE: &str = "merges.txt";
+
+/// `model_index.json` of a diffusers pipeline folder, the components map to their library and class,
+/// e.g. `"unet": ["diffusers", "UNet2DConditionModel"]`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelIndex {
+    #[serde(rename = "_class_name")]
+    pub class_name: String,
+    #[serde(flatten)]
+    pub components: BTreeMap<String, serde_json::Value>,
+}
+
+/// Fields of `unet/config.json` telling the architecture apart
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnetConfig {
+    pub in_channels: usize,
+    pub cross_attention_dim: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VaeConfig {
+    /// Missing from the configs written by older diffusers versions
+    pub scaling_factor: Option<f64>,
+}
+
+/// A diffusers pipeline folder, `model_index.json` next to one subfolder per component
+#[derive(Debug, Clone)]
+pub struct DiffusersFolder {
+    pub dir: PathBuf,
+    pub model_index: ModelIndex,
+}
+
+pub fn is_diffusers_folder(dir: &Path) -> bool {
+    dir.join(MODEL_INDEX_FILE).is_file()
+}
+
+/// Subfolder of a component in a diffusers pipeline
+pub fn component_dir(sd_file: &StableDiffusionFiles) -> &'static str {
+    match sd_file {
+        StableDiffusionFiles::Tokenizer => "tokenizer",
+        StableDiffusionFiles::Tokenizer2 => "tokenizer_2",
+        StableDiffusionFiles::Clip => "text_encoder",
+        StableDiffusionFiles::Clip2 => "text_encoder_2",
+        StableDiffusionFiles::Unet => "unet",
+        StableDiffusionFiles::Vae => "vae",
+    }
+}
+
+/// `tokenizer.json`, else `vocab.json` when `merges.txt` is next to it, see clip_embeddings::clip_bpe_tokenizer
+fn tokenizer_file(dir: &Path, sd_file: &StableDiffusionFiles) -> Option<String> {
+    let file = format!("{}/tokenizer.json", component_dir(sd_file));
+    if dir.join(&file).is_file() {
+        return Some(file);
+    }
+    let vocab = format!("{}/{}", component_dir(sd_file), TOKENIZER_VOCAB_FILE);
+    let merges = format!("{}/{}", component_dir(sd_file), TOKENIZER_MERGES_FILE);
+    if dir.join(&vocab).is_file() && dir.join(merges).is_file() { Some(vocab) } else { None }
+}
+
+/// Path of the file of a component relative to the folder, None when absent.
+/// The weights of the requested precision are preferred, the other precision is converted on load
+pub fn component_file(dir: &Path, sd_file: &StableDiffusionFiles, use_f16: bool) -> Option<String> {
+    let name = match sd_file {
+        StableDiffusionFiles::Tokenizer | StableDiffusionFiles::Tokenizer2 => return tokenizer_file(dir, sd_file),
+        StableDiffusionFiles::Clip | StableDiffusionFiles::Clip2 => "model",
+        StableDiffusionFiles::Unet | StableDiffusionFiles::Vae => "diffusion_pytorch_model",
+    };
+    let fp16 = format!("{}/{}.fp16.safetensors", component_dir(sd_file), name);
+    let fp32 = format!("{}/{}.safetensors", component_dir(sd_file), name);
+    let candidates = if use_f16 { [fp16, fp32] } else { [fp32, fp16] };
+    candidates.into_iter().find(|file| dir.join(file).is_file())
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|error| anyhow::anyhow!("Cannot read {}: {}", path.display(), error))?;
+    serde_json::from_str(&content)
+        .map_err(|error| anyhow::anyhow!("Invalid {}: {}", path.display(), error))
+}
+
+/// Cross attention dimension of the UNet of every version, the text encoder hidden states it attends to
+fn cross_attention_dim(sd_version: &StableDiffusionVersion) -> usize {
+    match sd_version {
+        StableDiffusionVersion::V1_5 | StableDiffusionVersion::V1_5Inpaint => 768,
+        StableDiffusionVersion::V2_1 | StableDiffusionVersion::V2Inpaint => 1024,
+        StableDiffusionVersion::Xl | StableDiffusionVersion::Turbo | StableDiffusionVersion::XlInpaint => 2048,
+    }
+}
+
+impl DiffusersFolder {
+    /// None when `dir` is not a diffusers folder, e.g. a plain copy of the repository files
+    pub fn open(dir: &Path) -> Result<Option<Self>> {
+        if !is_diffusers_folder(dir) {
+            return Ok(None);
+        }
+        let model_index = read_json(&dir.join(MODEL_INDEX_FILE))?;
+        Ok(Some(Self { dir: dir.to_path_buf(), model_index }))
+    }
+
+    /// Listed in the model index, optional components are listed as `[null, null]`
+    pub fn has_component(&self, sd_file: &StableDiffusionFiles) -> bool {
+        match self.model_index.components.get(component_dir(sd_file)) {
+            Some(serde_json::Value::Array(library_class)) => library_class.first().is_some_and(|library| !library.is_null()),
+            _ => false
+        }
+    }
+
+    pub fn unet_config(&self) -> Result<UnetConfig> {
+        read_json(&self.dir.join("unet").join("config.json"))
+    }
+
+    pub fn vae_config(&self) -> Result<VaeConfig> {
+        read_json(&self.dir.join("vae").join("config.json"))
+    }
+
+    /// Checks the folder holds weights of the architecture of `sd_version`, before any shape mismatch while loading
+    pub fn check_version(&self, sd_version: &StableDiffusionVersion) -> Result<()> {
+        let has_clip2 = self.has_component(&StableDiffusionFiles::Clip2);
+        if has_clip2 != sd_version.has_second_text_encoder() {
+            anyhow::bail!(
+                "The {} of {} {} a second text encoder, unlike {:?}",
+                self.model_index.class_name, self.dir.display(), if has_clip2 { "has" } else { "lacks" }, sd_version
+            )
+        }
+        let unet = self.unet_config()?;
+        if unet.cross_attention_dim != cross_attention_dim(sd_version) || unet.in_channels != sd_version.unet_in_channels() {
+            anyhow::bail!(
+                "The UNet of {} has {} input channels and a cross attention dimension of {}, it does not fit {:?}, see --sd_version",
+                self.dir.display(), unet.in_channels, unet.cross_attention_dim, sd_version
+            )
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, file: &str, content: &str) -> Result<()> {
+        let path = dir.join(file);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diffusers_folder_components() -> Result<()> {
+        let temp_dir = crate::test_utils::TempDir::new("diffusers")?;
+        let dir = temp_dir.path();
+        assert!(DiffusersFolder::open(dir)?.is_none());
+        write(dir, MODEL_INDEX_FILE, r#"{
+            "_class_name": "StableDiffusionPipeline",
+            "_diffusers_version": "0.30.0",
+            "safety_checker": [null, null],
+            "text_encoder": ["transformers", "CLIPTextModel"],
+            "tokenizer": ["transformers", "CLIPTokenizer"],
+            "unet": ["diffusers", "UNet2DConditionModel"],
+            "vae": ["diffusers", "AutoencoderKL"]
+        }"#)?;
+        write(dir, "unet/config.json", r#"{"_class_name": "UNet2DConditionModel", "in_channels": 4, "cross_attention_dim": 768, "sample_size": 64}"#)?;
+        write(dir, "vae/config.json", r#"{"_class_name": "AutoencoderKL", "scaling_factor": 0.18215}"#)?;
+        write(dir, "unet/diffusion_pytorch_model.fp16.safetensors", "")?;
+        write(dir, "vae/diffusion_pytorch_model.safetensors", "")?;
+        write(dir, "vae/diffusion_pytorch_model.fp16.safetensors", "")?;
+
+        let folder = DiffusersFolder::open(dir)?.unwrap();
+        assert!(folder.has_component(&StableDiffusionFiles::Unet));
+        assert!(!folder.has_component(&StableDiffusionFiles::Clip2));
+        assert_eq!(folder.vae_config()?.scaling_factor, Some(0.18215));
+        folder.check_version(&StableDiffusionVersion::V1_5)?;
+        assert!(folder.check_version(&StableDiffusionVersion::V2_1).is_err());
+        assert!(folder.check_version(&StableDiffusionVersion::V1_5Inpaint).is_err());
+        assert!(folder.check_version(&StableDiffusionVersion::Xl).is_err());
+
+        // the precision present is taken when the requested one is missing
+        assert_eq!(component_file(dir, &StableDiffusionFiles::Unet, false).as_deref(), Some("unet/diffusion_pytorch_model.fp16.safetensors"));
+        assert_eq!(component_file(dir, &StableDiffusionFiles::Vae, false).as_deref(), Some("vae/diffusion_pytorch_model.safetensors"));
+        assert_eq!(component_file(dir, &StableDiffusionFiles::Vae, true).as_deref(), Some("vae/diffusion_pytorch_model.fp16.safetensors"));
+        assert!(component_file(dir, &StableDiffusionFiles::Clip, true).is_none());
+        assert!(component_file(dir, &StableDiffusionFiles::Tokenizer, true).is_none());
+        write(dir, "tokenizer/vocab.json", "{}")?;
+        assert!(component_file(dir, &StableDiffusionFiles::Tokenizer, true).is_none());
+        write(dir, "tokenizer/merges.txt", "#version: 0.2")?;
+        assert_eq!(component_file(dir, &StableDiffusionFiles::Tokenizer, true).as_deref(), Some("tokenizer/vocab.json"));
+        write(dir, "tokenizer/tokenizer.json", "{}")?;
+        assert_eq!(component_file(dir, &StableDiffusionFiles::Tokenizer, true).as_deref(), Some("tokenizer/tokenizer.json"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use anyhow;
+use candle_core::{DType, Tensor};
+use candle_transformers::models::stable_diffusion::schedulers::Scheduler;
+
+use crate::stable_diffusion::{stable_diffusion_files, vae};
+
+pub struct Inpainting {
+    /// mask at latent resolution, 1 where the image gets repainted
+    latent_mask: Tensor,
+    /// latents of the image with the repainted area blanked out, only set for inpainting UNets
+    masked_image_latents: Option<Tensor>,
+}
+
+impl Inpainting {
+    /// `image` is the preprocessed init image, `mask` the (1, 1, height, width) output of `mask_preprocess_to_size`,
+    /// both already on the device of the vae.
+    pub fn new(image: &Tensor, mask: &Tensor, vae_encoder: &vae::VaeEncoder, vae_scale: f64, sd_version: &stable_diffusion_files::StableDiffusionVersion, dtype: DType) -> anyhow::Result<Self> {
+        let (_, _, height, width) = mask.dims4()?;
+        let latent_mask = mask.interpolate2d(height / 8, width / 8)?.to_dtype(dtype)?;
+
+        let masked_image_latents = if sd_version.is_inpainting() {
+            let keep_mask = (1. - mask.to_dtype(image.dtype())?)?;
+            let masked_image = image.broadcast_mul(&keep_mask)?;
+            // the conditioning is the same for every image of the run, the mean of the posterior keeps it free of any seed
+            let masked_image_latents = (vae_encoder.encode(&masked_image)?.mean * vae_scale)?.to_dtype(dtype)?;
+            Some(masked_image_latents)
+        } else {
+            None
+        };
+
+        Ok(Self { latent_mask, masked_image_latents })
+    }
+
+    /// Inpainting UNets get the mask and the masked image latents concatenated to the latents
+    /// along the channel dimension, regular UNets get the latents unchanged.
+    pub fn unet_input(&self, latent_model_input: Tensor) -> anyhow::Result<Tensor> {
+        match &self.masked_image_latents {
+            Some(masked_image_latents) => {
+                let batch = latent_model_input.dim(0)?;
+                let mask = self.latent_mask.repeat((batch, 1, 1, 1))?;
+                let masked_image_latents = masked_image_latents.repeat((batch, 1, 1, 1))?;
+                Ok(Tensor::cat(&[&latent_model_input, &mask, &masked_image_latents], 1)?)
+            },
+            None => Ok(latent_model_input)
+        }
+    }
+
+    /// With regular UNets the area outside of the mask is replaced after every step by the init latents,
+    /// noised to the level of the next timestep (or left clean after the last one).
+    pub fn blend_latents(&self, latents: &Tensor, init_latents: &Tensor, noise: &Tensor, scheduler: &dyn Scheduler, next_timestep: Option<usize>) -> anyhow::Result<Tensor> {
+        if self.masked_image_latents.is_some() {
+            return Ok(latents.clone());
+        }
+        let known_latents = match next_timestep {
+            Some(timestep) => scheduler.add_noise(init_latents, noise.clone(), timestep)?,
+            None => init_latents.clone()
+        };
+        let keep_mask = (1. - &self.latent_mask)?;
+        let blended = (latents.broadcast_mul(&self.latent_mask)? + known_latents.broadcast_mul(&keep_mask)?)?;
+        Ok(blended)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn inpainting_unet_input_regular_unet() -> anyhow::Result<()> {
+        let inpainting = Inpainting {
+            latent_mask: Tensor::ones((1, 1, 4, 4), DType::F32, &Device::Cpu)?,
+            masked_image_latents: None,
+        };
+        let latents = Tensor::zeros((2, 4, 4, 4), DType::F32, &Device::Cpu)?;
+        let input = inpainting.unet_input(latents)?;
+
+        assert_eq!(input.dims(), &[2, 4, 4, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn inpainting_unet_input_inpainting_unet() -> anyhow::Result<()> {
+        let inpainting = Inpainting {
+            latent_mask: Tensor::ones((1, 1, 4, 4), DType::F32, &Device::Cpu)?,
+            masked_image_latents: Some(Tensor::zeros((1, 4, 4, 4), DType::F32, &Device::Cpu)?),
+        };
+        let latents = Tensor::zeros((2, 4, 4, 4), DType::F32, &Device::Cpu)?;
+        let input = inpainting.unet_input(latents)?;
+
+        assert_eq!(input.dims(), &[2, 9, 4, 4]);
+        Ok(())
+    }
+}
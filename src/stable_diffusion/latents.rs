@@ -0,0 +1,123 @@
+use anyhow;
+use candle_core::{Device, Tensor};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::StandardNormal;
+
+pub fn random_seed() -> u64 {
+    rand::random()
+}
+
+/// Seed used for the sample at `sample_idx` when generating several images from one base seed.
+pub fn derive_seed(base_seed: u64, sample_idx: usize) -> u64 {
+    base_seed.wrapping_add(sample_idx as u64)
+}
+
+/// Independent streams of noise drawn from the seed of an image, so that the noise of one use
+/// does not repeat the noise of another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseStream {
+    InitialLatents,
+    /// Sample of the VAE posterior of an init image
+    Posterior,
+    /// Noise injected while stepping by the ancestral schedulers
+    Scheduler,
+}
+
+/// ChaCha RNG of the host seeded for one of the noise streams of an image
+pub fn seeded_rng(seed: u64, stream: NoiseStream) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(stream as u64);
+    rng
+}
+
+/// Standard normal noise drawn from `rng` on the host and then moved to `device`
+pub fn rng_noise(rng: &mut ChaCha8Rng, shape: (usize, usize, usize, usize), device: &Device) -> anyhow::Result<Tensor> {
+    let (b, c, h, w) = shape;
+    let noise: Vec<f32> = (0..b * c * h * w)
+        .map(|_| rng.sample::<f32, _>(StandardNormal))
+        .collect();
+
+    let noise = Tensor::from_vec(noise, shape, &Device::Cpu)?.to_device(device)?;
+    Ok(noise)
+}
+
+/// Standard normal noise drawn from a seeded ChaCha RNG on the host and then moved to `device`,
+/// so that the same seed gives the same latents regardless of the backend.
+pub fn seeded_noise(seed: u64, shape: (usize, usize, usize, usize), device: &Device) -> anyhow::Result<Tensor> {
+    rng_noise(&mut seeded_rng(seed, NoiseStream::InitialLatents), shape, device)
+}
+
+/// Noise for a batch of images, the one at position i drawn from `seeds[i]` so that
+/// every image can be reproduced on its own whatever the batch it was generated in.
+pub fn batch_noise(seeds: &[u64], shape: (usize, usize, usize), device: &Device) -> anyhow::Result<Tensor> {
+    let (c, h, w) = shape;
+    let noise = seeds
+        .iter()
+        .map(|seed| seeded_noise(*seed, (1, c, h, w), device))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(Tensor::cat(&noise, 0)?)
+}
+
+/// Index of the first scheduler step to run for img2img: a strength of 1 runs every step
+/// (the input image is fully noised), a strength of 0 runs none and returns the input as is.
+pub fn get_t_start(n_steps: usize, strength: f64) -> anyhow::Result<usize> {
+    if !(0.0..=1.0).contains(&strength) {
+        anyhow::bail!("Strength must be between 0 and 1, got {}", strength)
+    }
+    Ok(n_steps - (n_steps as f64 * strength) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latents_derive_seed() {
+        assert_eq!(derive_seed(42, 0), 42);
+        assert_eq!(derive_seed(42, 3), 45);
+        assert_eq!(derive_seed(u64::MAX, 1), 0);
+    }
+
+    #[test]
+    fn latents_seeded_noise_is_reproducible() -> anyhow::Result<()> {
+        let shape = (1, 4, 8, 8);
+        let first = seeded_noise(7, shape, &Device::Cpu)?.flatten_all()?.to_vec1::<f32>()?;
+        let second = seeded_noise(7, shape, &Device::Cpu)?.flatten_all()?.to_vec1::<f32>()?;
+        let other = seeded_noise(8, shape, &Device::Cpu)?.flatten_all()?.to_vec1::<f32>()?;
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        Ok(())
+    }
+
+    #[test]
+    fn latents_noise_streams_differ() -> anyhow::Result<()> {
+        let shape = (1, 4, 8, 8);
+        let initial = seeded_noise(7, shape, &Device::Cpu)?.flatten_all()?.to_vec1::<f32>()?;
+        let posterior = rng_noise(&mut seeded_rng(7, NoiseStream::Posterior), shape, &Device::Cpu)?.flatten_all()?.to_vec1::<f32>()?;
+
+        assert_eq!(initial, rng_noise(&mut seeded_rng(7, NoiseStream::InitialLatents), shape, &Device::Cpu)?.flatten_all()?.to_vec1::<f32>()?);
+        assert_ne!(initial, posterior);
+        Ok(())
+    }
+
+    #[test]
+    fn latents_batch_noise_matches_single_images() -> anyhow::Result<()> {
+        let batch = batch_noise(&[3, 4], (4, 8, 8), &Device::Cpu)?;
+        let second = seeded_noise(4, (1, 4, 8, 8), &Device::Cpu)?;
+
+        assert_eq!(batch.dims(), &[2, 4, 8, 8]);
+        assert_eq!(batch.narrow(0, 1, 1)?.flatten_all()?.to_vec1::<f32>()?, second.flatten_all()?.to_vec1::<f32>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn latents_get_t_start() -> anyhow::Result<()> {
+        assert_eq!(get_t_start(30, 1.0)?, 0);
+        assert_eq!(get_t_start(30, 0.8)?, 6);
+        assert_eq!(get_t_start(30, 0.0)?, 30);
+        assert!(get_t_start(30, 1.5).is_err());
+        Ok(())
+    }
+}
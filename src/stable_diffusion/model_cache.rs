@@ -0,0 +1,342 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::image_utils::metadata::value_name;
+use crate::registry::{RegisteredModelFiles, Registry};
+use crate::stable_diffusion::stable_diffusion_files::{self, ModelFileBuild, ModelSource, ResolvedModelFile, StableDiffusionVersion};
+
+/// Prints the progress of a download on a single line, at every percent
+struct DownloadProgress {
+    filename: String,
+    size: usize,
+    downloaded: usize,
+    percent: usize,
+}
+
+impl DownloadProgress {
+    fn new() -> Self {
+        Self { filename: String::new(), size: 0, downloaded: 0, percent: 0 }
+    }
+
+    fn print(&self) {
+        print!("\r  {} {:>3}% of {}", self.filename, self.percent, format_size(self.size as u64));
+        let _ = std::io::stdout().flush();
+    }
+}
+
+impl hf_hub::api::Progress for DownloadProgress {
+    fn init(&mut self, size: usize, filename: &str) {
+        self.filename = filename.to_string();
+        self.size = size;
+        self.print();
+    }
+
+    fn update(&mut self, size: usize) {
+        self.downloaded += size;
+        let percent = if self.size == 0 { 100 } else { (self.downloaded * 100 / self.size).min(100) };
+        if percent != self.percent {
+            self.percent = percent;
+            self.print();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.percent = 100;
+        self.print();
+        println!();
+    }
+}
+
+/// Downloads a file of a hub repository into the cache, even if already there
+pub fn download_file(source: &ModelSource, repo: &str, filepath: &str) -> Result<PathBuf> {
+    Ok(source.api_builder().build()?.model(repo.to_string()).download_with_progress(filepath, DownloadProgress::new())?)
+}
+
+/// Fetches the files of `sd_version` that are neither local nor cached, reporting where each one comes from.
+/// hf-hub keeps the part of an interrupted transfer and resumes it on the next run
+pub fn download_model_files(sd: &dyn ModelFileBuild, sd_version: &StableDiffusionVersion, use_f16: bool, source: &ModelSource) -> Result<Vec<ResolvedModelFile>> {
+    let mut model_files = vec![];
+    for sd_file in stable_diffusion_files::model_sd_files(sd_version) {
+        let use_f16 = stable_diffusion_files::sd_file_use_f16(&sd_file, use_f16);
+        if let Some(model_file) = sd.resolve_local(&sd_file, use_f16, source)? {
+            println!("{}: local {}", sd_file, model_file.path.display());
+            model_files.push(model_file);
+            continue;
+        }
+
+        let repo = sd.get_repo_with_precision(&sd_file, Some(use_f16));
+        let filepath = sd.get_filepath(&sd_file, use_f16);
+        let path = match source.cached_file(repo, filepath) {
+            Some(path) => {
+                println!("{}: cached {}", sd_file, path.display());
+                path
+            },
+            // fails with the offline error
+            None if source.offline => source.hub_file(repo, filepath)?,
+            None => {
+                println!("{}: downloading {} from {}", sd_file, filepath, repo);
+                download_file(source, repo, filepath)?
+            }
+        };
+        model_files.push(ResolvedModelFile { component: sd_file.to_string(), repo: Some(repo.to_string()), file: filepath.to_string(), path });
+    }
+    Ok(model_files)
+}
+
+/// A file of a built-in version or a registered model found in the HF hub cache
+#[derive(Debug, Clone)]
+pub struct CachedModelFile {
+    /// Name of the built-in version, e.g. `v2-1`, or of the registered model
+    pub model: String,
+    pub component: String,
+    /// fp16 or fp32, tokenizers have none
+    pub precision: Option<String>,
+    pub repo: String,
+    pub file: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Built-in versions then registered models, with the version their architecture follows
+fn known_models(registry: &Registry) -> Result<Vec<(String, StableDiffusionVersion, Box<dyn ModelFileBuild>)>> {
+    let mut models = vec![];
+    for sd_version in StableDiffusionVersion::value_variants() {
+        models.push((value_name(sd_version), *sd_version, stable_diffusion_files::create_sd_from_version(sd_version)));
+    }
+    for (name, registered) in &registry.model {
+        let files: Box<dyn ModelFileBuild> = Box::new(RegisteredModelFiles::new(registered.clone())?);
+        models.push((name.clone(), registered.base_version()?, files));
+    }
+    Ok(models)
+}
+
+/// Fails on a name that is neither a built-in version nor a registered model
+pub fn check_model_names(names: &[String], registry: &Registry) -> Result<()> {
+    let known: Vec<String> = known_models(registry)?.into_iter().map(|(name, _, _)| name).collect();
+    for name in names {
+        if !known.contains(name) {
+            anyhow::bail!("Unknown model {}, expected one of: {}", name, known.join(", "))
+        }
+    }
+    Ok(())
+}
+
+/// Files of every version, registered model and precision present in the cache of `source`,
+/// a file shared by several models is listed for each of them
+pub fn cached_model_files(source: &ModelSource, registry: &Registry) -> Result<Vec<CachedModelFile>> {
+    let mut cached: Vec<CachedModelFile> = vec![];
+    for (model, sd_version, sd) in known_models(registry)? {
+        for use_f16 in [true, false] {
+            for sd_file in stable_diffusion_files::model_sd_files(&sd_version) {
+                let file_use_f16 = stable_diffusion_files::sd_file_use_f16(&sd_file, use_f16);
+                let repo = sd.get_repo_with_precision(&sd_file, Some(file_use_f16));
+                let filepath = sd.get_filepath(&sd_file, file_use_f16);
+                let path = match source.cached_file(repo, filepath) {
+                    Some(path) => path,
+                    None => continue
+                };
+                // tokenizers are the same for both precisions
+                if cached.iter().any(|cached_file| cached_file.model == model && cached_file.path == path) {
+                    continue;
+                }
+                let precision = match sd_file {
+                    stable_diffusion_files::StableDiffusionFiles::Tokenizer | stable_diffusion_files::StableDiffusionFiles::Tokenizer2 => None,
+                    _ => Some(if use_f16 { "fp16" } else { "fp32" }.to_string())
+                };
+                let size = std::fs::metadata(&path).map_or(0, |metadata| metadata.len());
+                cached.push(CachedModelFile {
+                    model: model.clone(),
+                    component: sd_file.to_string(),
+                    precision,
+                    repo: repo.to_string(),
+                    file: filepath.to_string(),
+                    path,
+                    size,
+                });
+            }
+        }
+    }
+    Ok(cached)
+}
+
+/// Cached files none of the `keep` models loads, in either precision, each listed once
+pub fn prune_candidates(cached: &[CachedModelFile], keep: &[String]) -> Vec<CachedModelFile> {
+    let kept: HashSet<&Path> = cached.iter()
+        .filter(|cached_file| keep.contains(&cached_file.model))
+        .map(|cached_file| cached_file.path.as_path())
+        .collect();
+    let mut candidates: Vec<CachedModelFile> = vec![];
+    for cached_file in cached {
+        if !kept.contains(cached_file.path.as_path()) && !candidates.iter().any(|candidate| candidate.path == cached_file.path) {
+            candidates.push(cached_file.clone());
+        }
+    }
+    candidates
+}
+
+/// Removes a snapshot file of the HF hub cache, and its blob unless another snapshot still links to it
+pub fn remove_cached_file(path: &Path) -> Result<()> {
+    let blob = std::fs::canonicalize(path)?;
+    std::fs::remove_file(path)?;
+    // snapshots hold copies rather than links where symlinks are unavailable
+    if !blob.exists() {
+        return Ok(());
+    }
+    // <repo>/snapshots/<revision>/<file> links to <repo>/blobs/<hash>
+    if let Some(repo_dir) = blob.parent().and_then(Path::parent) {
+        if !links_to(&repo_dir.join("snapshots"), &blob)? {
+            std::fs::remove_file(&blob)?;
+        }
+    }
+    Ok(())
+}
+
+fn links_to(dir: &Path, blob: &Path) -> Result<bool> {
+    if !dir.is_dir() {
+        return Ok(false);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && !path.is_symlink() {
+            if links_to(&path, blob)? {
+                return Ok(true);
+            }
+        } else if std::fs::canonicalize(&path).is_ok_and(|target| target == blob) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub fn format_size(bytes: u64) -> String {
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next_unit in ["kB", "MB", "GB", "TB"] {
+        if size < 1000. {
+            break;
+        }
+        size /= 1000.;
+        unit = next_unit;
+    }
+    format!("{:.2} {}", size, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stable_diffusion::constants;
+
+    fn snapshot_path(cache_dir: &Path, repo: &str, filepath: &str) -> Result<PathBuf> {
+        let repo_dir = cache_dir.join(format!("models--{}", repo.replace('/', "--")));
+        std::fs::create_dir_all(repo_dir.join("refs"))?;
+        std::fs::write(repo_dir.join("refs/main"), "0123abcd")?;
+        let path = repo_dir.join("snapshots/0123abcd").join(filepath);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        Ok(path)
+    }
+
+    #[test]
+    fn cache_list_and_prune() -> Result<()> {
+        let temp_dir = crate::test_utils::TempDir::new("cache")?;
+        let cache_dir = temp_dir.path();
+        std::fs::write(snapshot_path(cache_dir, constants::REPO_TOKENIZER, constants::MODELFILE_TOKENIZER)?, b"{}")?;
+        std::fs::write(snapshot_path(cache_dir, constants::REPO_1_5, constants::MODELFILE_UNET_FP16)?, b"unet 1.5")?;
+        std::fs::write(snapshot_path(cache_dir, constants::REPO_2_1, constants::MODELFILE_UNET_FP16)?, b"unet 2.1")?;
+        let source = ModelSource { cache_dir: Some(cache_dir.to_path_buf()), offline: true, ..Default::default() };
+
+        let registry = Registry::default();
+        let cached = cached_model_files(&source, &registry)?;
+        let v1_5: Vec<&CachedModelFile> = cached.iter().filter(|cached_file| cached_file.model == "v1-5").collect();
+        assert_eq!(v1_5.len(), 2);
+        assert_eq!(v1_5[0].component, "tokenizer");
+        assert!(v1_5[0].precision.is_none());
+        assert_eq!(v1_5[1].component, "unet");
+        assert_eq!(v1_5[1].precision.as_deref(), Some("fp16"));
+        assert_eq!(v1_5[1].size, 8);
+
+        // the tokenizer is shared with 2.1
+        let candidates = prune_candidates(&cached, &["v2-1".to_string()]);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].repo, constants::REPO_1_5);
+
+        remove_cached_file(&candidates[0].path)?;
+        let cached = cached_model_files(&source, &registry)?;
+        assert!(prune_candidates(&cached, &["v2-1".to_string()]).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cache_registered_models() -> Result<()> {
+        let temp_dir = crate::test_utils::TempDir::new("registered")?;
+        let cache_dir = temp_dir.path();
+        std::fs::write(snapshot_path(cache_dir, constants::REPO_TOKENIZER, constants::MODELFILE_TOKENIZER)?, b"{}")?;
+        std::fs::write(snapshot_path(cache_dir, "someone/whiskers-diffusion", "unet/whiskers.safetensors")?, b"whiskers")?;
+        std::fs::write(snapshot_path(cache_dir, constants::REPO_1_5, constants::MODELFILE_UNET_FP16)?, b"unet 1.5")?;
+        let source = ModelSource { cache_dir: Some(cache_dir.to_path_buf()), offline: true, ..Default::default() };
+        let registry: Registry = toml::from_str(r#"
+[model.whiskers]
+base = "v1-5"
+repo = "someone/whiskers-diffusion"
+
+[model.whiskers.unet]
+file = "unet/whiskers.safetensors"
+"#)?;
+
+        let cached = cached_model_files(&source, &registry)?;
+        let whiskers: Vec<&CachedModelFile> = cached.iter().filter(|cached_file| cached_file.model == "whiskers").collect();
+        assert_eq!(whiskers.len(), 2);
+        assert_eq!(whiskers[1].repo, "someone/whiskers-diffusion");
+
+        // keeping the registered model keeps the tokenizer of its base
+        let candidates = prune_candidates(&cached, &["whiskers".to_string()]);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].repo, constants::REPO_1_5);
+        let candidates = prune_candidates(&cached, &["v1-5".to_string()]);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].repo, "someone/whiskers-diffusion");
+
+        check_model_names(&["whiskers".to_string(), "xl".to_string()], &registry)?;
+        assert!(check_model_names(&["tabby".to_string()], &registry).is_err());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cache_remove_linked_blob() -> Result<()> {
+        let cache_dir = crate::test_utils::TempDir::new("blobs")?;
+        let repo_dir = cache_dir.join("models--org--cat");
+        let blob = repo_dir.join("blobs/5e4c");
+        std::fs::create_dir_all(blob.parent().unwrap())?;
+        std::fs::write(&blob, b"weights")?;
+        let main = repo_dir.join("snapshots/0123abcd/unet.safetensors");
+        let other = repo_dir.join("snapshots/4567ef01/unet.safetensors");
+        for path in [&main, &other] {
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::os::unix::fs::symlink(&blob, path)?;
+        }
+
+        remove_cached_file(&main)?;
+        assert!(!main.exists());
+        assert!(blob.exists());
+        remove_cached_file(&other)?;
+        assert!(!blob.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cache_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1_500), "1.50 kB");
+        assert_eq!(format_size(3_438_167_536), "3.44 GB");
+    }
+}
@@ -0,0 +1,308 @@
+pub mod euler_discrete;
+pub mod euler_ancestral_discrete;
+pub mod dpm_solver_multistep;
+
+use std::fmt::Display;
+use anyhow;
+use candle_core::Tensor;
+use candle_transformers::models::stable_diffusion::{self, ddim::DDIMSchedulerConfig};
+use candle_transformers::models::stable_diffusion::schedulers::{BetaSchedule, PredictionType, Scheduler, SchedulerConfig, TimestepSpacing};
+
+use crate::stable_diffusion::stable_diffusion_files::StableDiffusionVersion;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum, PartialEq, Eq)]
+pub enum SchedulerKind {
+    Ddim,
+    Euler,
+    EulerKarras,
+    EulerAncestral,
+    #[value(name = "dpmpp-2m")]
+    DpmPp2m,
+    #[value(name = "dpmpp-2m-karras")]
+    DpmPp2mKarras,
+}
+
+impl Display for SchedulerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            SchedulerKind::Ddim => write!(f, "ddim"),
+            SchedulerKind::Euler => write!(f, "euler"),
+            SchedulerKind::EulerKarras => write!(f, "euler-karras"),
+            SchedulerKind::EulerAncestral => write!(f, "euler-ancestral"),
+            SchedulerKind::DpmPp2m => write!(f, "dpmpp-2m"),
+            SchedulerKind::DpmPp2mKarras => write!(f, "dpmpp-2m-karras")
+        }
+    }
+}
+
+/// Noise schedule settings shared by the schedulers implemented in this crate
+#[derive(Debug, Clone, Copy)]
+pub struct SigmaSchedulerConfig {
+    pub beta_start: f64,
+    pub beta_end: f64,
+    pub beta_schedule: BetaSchedule,
+    pub train_timesteps: usize,
+    pub steps_offset: usize,
+    pub prediction_type: PredictionType,
+    pub timestep_spacing: TimestepSpacing,
+    /// Space the sigmas following Karras et al. (2022) instead of following the timesteps
+    pub use_karras_sigmas: bool,
+}
+
+impl Default for SigmaSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            beta_start: 0.00085,
+            beta_end: 0.012,
+            beta_schedule: BetaSchedule::ScaledLinear,
+            train_timesteps: 1000,
+            steps_offset: 1,
+            prediction_type: PredictionType::Epsilon,
+            timestep_spacing: TimestepSpacing::Leading,
+            use_karras_sigmas: false,
+        }
+    }
+}
+
+/// Timesteps and matching noise levels for an inference run, with a final sigma of 0
+#[derive(Debug, Clone)]
+pub struct SigmaSchedule {
+    pub timesteps: Vec<usize>,
+    pub sigmas: Vec<f64>,
+    pub init_noise_sigma: f64,
+    pub prediction_type: PredictionType,
+    /// index of the next step to run, used to disambiguate repeated timesteps
+    step_index: usize,
+}
+
+fn linspace(start: f64, stop: f64, steps: usize) -> Vec<f64> {
+    match steps {
+        0 => vec![],
+        1 => vec![start],
+        _ => {
+            let delta = (stop - start) / (steps - 1) as f64;
+            (0..steps).map(|i| start + i as f64 * delta).collect()
+        }
+    }
+}
+
+/// Piecewise linear interpolation of `ys` sampled at the increasing `xs`
+fn interp(x: f64, xs: &[f64], ys: &[f64]) -> f64 {
+    if x <= xs[0] {
+        return ys[0];
+    }
+    for i in 1..xs.len() {
+        if x <= xs[i] {
+            let ratio = (x - xs[i - 1]) / (xs[i] - xs[i - 1]);
+            return ys[i - 1] + ratio * (ys[i] - ys[i - 1]);
+        }
+    }
+    ys[ys.len() - 1]
+}
+
+impl SigmaSchedule {
+    pub fn new(config: &SigmaSchedulerConfig, inference_steps: usize) -> anyhow::Result<Self> {
+        if inference_steps == 0 || inference_steps > config.train_timesteps {
+            anyhow::bail!("Number of steps must be between 1 and {}, got {}", config.train_timesteps, inference_steps)
+        }
+        let n_train = config.train_timesteps;
+        let betas: Vec<f64> = match config.beta_schedule {
+            BetaSchedule::Linear => linspace(config.beta_start, config.beta_end, n_train),
+            BetaSchedule::ScaledLinear => linspace(config.beta_start.sqrt(), config.beta_end.sqrt(), n_train)
+                .into_iter()
+                .map(|beta| beta * beta)
+                .collect(),
+            _ => anyhow::bail!("Unsupported beta schedule {:?}", config.beta_schedule)
+        };
+        let mut alpha_cumprod = 1.0;
+        let train_sigmas: Vec<f64> = betas
+            .iter()
+            .map(|beta| {
+                alpha_cumprod *= 1.0 - beta;
+                ((1.0 - alpha_cumprod) / alpha_cumprod).sqrt()
+            })
+            .collect();
+
+        let timesteps: Vec<f64> = match config.timestep_spacing {
+            TimestepSpacing::Leading => {
+                let step_ratio = n_train / inference_steps;
+                (0..inference_steps).rev().map(|i| (i * step_ratio + config.steps_offset) as f64).collect()
+            },
+            TimestepSpacing::Linspace => linspace(0., (n_train - 1) as f64, inference_steps)
+                .into_iter()
+                .rev()
+                .map(|t| t.round())
+                .collect(),
+            TimestepSpacing::Trailing => {
+                let step_ratio = n_train as f64 / inference_steps as f64;
+                (0..inference_steps).map(|i| (n_train as f64 - i as f64 * step_ratio).round() - 1.).collect()
+            }
+        };
+        let timesteps: Vec<f64> = timesteps.into_iter().map(|t| t.clamp(0., (n_train - 1) as f64)).collect();
+
+        let train_timesteps: Vec<f64> = (0..n_train).map(|t| t as f64).collect();
+        let (timesteps, mut sigmas) = if config.use_karras_sigmas {
+            let sigma_min = train_sigmas[0];
+            let sigma_max = train_sigmas[n_train - 1];
+            let rho = 7.0;
+            let min_inv_rho = sigma_min.powf(1.0 / rho);
+            let max_inv_rho = sigma_max.powf(1.0 / rho);
+            let sigmas: Vec<f64> = linspace(0., 1., inference_steps)
+                .into_iter()
+                .map(|ramp| (max_inv_rho + ramp * (min_inv_rho - max_inv_rho)).powf(rho))
+                .collect();
+            // timestep matching each sigma, interpolated on the log of the training sigmas
+            let log_train_sigmas: Vec<f64> = train_sigmas.iter().map(|sigma| sigma.ln()).collect();
+            let timesteps = sigmas
+                .iter()
+                .map(|sigma| interp(sigma.ln(), &log_train_sigmas, &train_timesteps))
+                .collect::<Vec<_>>();
+            (timesteps, sigmas)
+        } else {
+            let sigmas = timesteps.iter().map(|t| interp(*t, &train_timesteps, &train_sigmas)).collect();
+            (timesteps, sigmas)
+        };
+        sigmas.push(0.0);
+
+        let max_sigma = sigmas.iter().cloned().fold(0., f64::max);
+        let init_noise_sigma = match config.timestep_spacing {
+            TimestepSpacing::Linspace | TimestepSpacing::Trailing => max_sigma,
+            TimestepSpacing::Leading => (max_sigma * max_sigma + 1.).sqrt()
+        };
+
+        Ok(Self {
+            timesteps: timesteps.into_iter().map(|t| t.round() as usize).collect(),
+            sigmas,
+            init_noise_sigma,
+            prediction_type: config.prediction_type,
+            step_index: 0,
+        })
+    }
+
+    /// Position of `timestep` in the schedule, looking from the next step to run
+    pub fn index_for_timestep(&self, timestep: usize) -> usize {
+        self.timesteps
+            .iter()
+            .enumerate()
+            .skip(self.step_index)
+            .find(|(_, t)| **t == timestep)
+            .map(|(index, _)| index)
+            .unwrap_or(self.step_index)
+    }
+
+    pub fn sigma(&self, timestep: usize) -> f64 {
+        self.sigmas[self.index_for_timestep(timestep)]
+    }
+
+    /// Marks the step at `index` as done
+    pub fn advance(&mut self, index: usize) {
+        self.step_index = index + 1;
+    }
+
+    pub fn scale_model_input(&self, sample: Tensor, timestep: usize) -> candle_core::Result<Tensor> {
+        let sigma = self.sigma(timestep);
+        sample / (sigma * sigma + 1.).sqrt()
+    }
+
+    pub fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> candle_core::Result<Tensor> {
+        original + (noise * self.sigma(timestep))?
+    }
+
+    /// Denoised sample predicted by the model at noise level `sigma`
+    pub fn pred_original_sample(&self, model_output: &Tensor, sigma: f64, sample: &Tensor) -> candle_core::Result<Tensor> {
+        match self.prediction_type {
+            PredictionType::Epsilon => sample - (model_output * sigma)?,
+            PredictionType::VPrediction => {
+                let scaled_output = (model_output * (-sigma / (sigma * sigma + 1.).sqrt()))?;
+                scaled_output + (sample / (sigma * sigma + 1.))?
+            },
+            PredictionType::Sample => Ok(model_output.clone())
+        }
+    }
+}
+
+fn version_prediction_type(sd_version: &StableDiffusionVersion) -> PredictionType {
+    match sd_version {
+        StableDiffusionVersion::V2_1 => PredictionType::VPrediction,
+        _ => PredictionType::Epsilon
+    }
+}
+
+fn version_timestep_spacing(sd_version: &StableDiffusionVersion) -> TimestepSpacing {
+    match sd_version {
+        StableDiffusionVersion::Turbo => TimestepSpacing::Trailing,
+        _ => TimestepSpacing::Leading
+    }
+}
+
+/// Builds the requested scheduler with the prediction type and timestep spacing of the version,
+/// or the default scheduler of the version when none is requested.
+/// `seeds` are the ones of the images of the batch, ancestral schedulers draw the noise of every image from its seed
+pub fn build_scheduler(scheduler_kind: Option<SchedulerKind>, stable_diffusion_config: &stable_diffusion::StableDiffusionConfig, sd_version: &StableDiffusionVersion, n_steps: usize, seeds: &[u64]) -> anyhow::Result<Box<dyn Scheduler>> {
+    let prediction_type = version_prediction_type(sd_version);
+    let timestep_spacing = version_timestep_spacing(sd_version);
+    let sigma_config = SigmaSchedulerConfig { prediction_type, timestep_spacing, ..Default::default() };
+
+    let scheduler: Box<dyn Scheduler> = match scheduler_kind {
+        // the default of candle for Turbo is an ancestral scheduler drawing its noise from the device
+        None if *sd_version == StableDiffusionVersion::Turbo => Box::new(euler_ancestral_discrete::EulerAncestralDiscreteScheduler::new(&sigma_config, n_steps, seeds)?),
+        None => stable_diffusion_config.build_scheduler(n_steps)?,
+        Some(SchedulerKind::Ddim) => DDIMSchedulerConfig { prediction_type, timestep_spacing, ..Default::default() }.build(n_steps)?,
+        Some(SchedulerKind::EulerAncestral) => Box::new(euler_ancestral_discrete::EulerAncestralDiscreteScheduler::new(&sigma_config, n_steps, seeds)?),
+        Some(SchedulerKind::Euler) => Box::new(euler_discrete::EulerDiscreteScheduler::new(&sigma_config, n_steps)?),
+        Some(SchedulerKind::EulerKarras) => {
+            let sigma_config = SigmaSchedulerConfig { use_karras_sigmas: true, ..sigma_config };
+            Box::new(euler_discrete::EulerDiscreteScheduler::new(&sigma_config, n_steps)?)
+        },
+        Some(SchedulerKind::DpmPp2m) => Box::new(dpm_solver_multistep::DpmSolverMultistepScheduler::new(&sigma_config, n_steps)?),
+        Some(SchedulerKind::DpmPp2mKarras) => {
+            let sigma_config = SigmaSchedulerConfig { use_karras_sigmas: true, ..sigma_config };
+            Box::new(dpm_solver_multistep::DpmSolverMultistepScheduler::new(&sigma_config, n_steps)?)
+        }
+    };
+    Ok(scheduler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedulers_leading_timesteps() -> anyhow::Result<()> {
+        let schedule = SigmaSchedule::new(&SigmaSchedulerConfig::default(), 5)?;
+
+        assert_eq!(schedule.timesteps, vec![801, 601, 401, 201, 1]);
+        assert_eq!(schedule.sigmas.len(), 6);
+        assert_eq!(*schedule.sigmas.last().unwrap(), 0.0);
+        assert!(schedule.sigmas.windows(2).all(|pair| pair[0] > pair[1]));
+        Ok(())
+    }
+
+    #[test]
+    fn schedulers_trailing_timesteps() -> anyhow::Result<()> {
+        let config = SigmaSchedulerConfig { timestep_spacing: TimestepSpacing::Trailing, ..Default::default() };
+        let schedule = SigmaSchedule::new(&config, 4)?;
+
+        assert_eq!(schedule.timesteps, vec![999, 749, 499, 249]);
+        assert_eq!(schedule.init_noise_sigma, schedule.sigmas[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn schedulers_karras_sigmas() -> anyhow::Result<()> {
+        let config = SigmaSchedulerConfig { use_karras_sigmas: true, ..Default::default() };
+        let schedule = SigmaSchedule::new(&config, 10)?;
+        let plain = SigmaSchedule::new(&SigmaSchedulerConfig { timestep_spacing: TimestepSpacing::Linspace, ..Default::default() }, 10)?;
+
+        assert_eq!(schedule.timesteps.len(), 10);
+        assert_eq!(schedule.timesteps[0], 999);
+        assert!((schedule.sigmas[0] - plain.sigmas[0]).abs() < 1e-6);
+        assert!(schedule.sigmas.windows(2).all(|pair| pair[0] > pair[1]));
+        Ok(())
+    }
+
+    #[test]
+    fn schedulers_invalid_steps() {
+        assert!(SigmaSchedule::new(&SigmaSchedulerConfig::default(), 0).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow;
+use candle_core::{Result, Tensor};
+use candle_transformers::models::stable_diffusion::schedulers::Scheduler;
+use rand_chacha::ChaCha8Rng;
+
+use super::{SigmaSchedule, SigmaSchedulerConfig};
+use crate::stable_diffusion::latents;
+
+/// Euler method with ancestral sampling, as in `sample_euler_ancestral` of k-diffusion.
+/// The noise added at every step is drawn on the host from the seed of each image,
+/// candle cannot seed the CPU device and would draw it from an unseeded RNG
+pub struct EulerAncestralDiscreteScheduler {
+    schedule: SigmaSchedule,
+    /// one RNG per image of the batch
+    rngs: Vec<ChaCha8Rng>,
+}
+
+impl EulerAncestralDiscreteScheduler {
+    pub fn new(config: &SigmaSchedulerConfig, inference_steps: usize, seeds: &[u64]) -> anyhow::Result<Self> {
+        let rngs = seeds.iter()
+            .map(|seed| latents::seeded_rng(*seed, latents::NoiseStream::Scheduler))
+            .collect();
+        Ok(Self { schedule: SigmaSchedule::new(config, inference_steps)?, rngs })
+    }
+
+    /// Noise for the next step of every image, each from its own RNG
+    fn step_noise(&mut self, sample: &Tensor) -> Result<Tensor> {
+        let (batch, c, h, w) = sample.dims4()?;
+        if batch != self.rngs.len() {
+            candle_core::bail!("Batch of {} latents stepped by a scheduler seeded for {} images", batch, self.rngs.len())
+        }
+        let noise = self.rngs
+            .iter_mut()
+            .map(|rng| latents::rng_noise(rng, (1, c, h, w), sample.device()).map_err(|error| candle_core::Error::Msg(error.to_string())))
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::cat(&noise, 0)?.to_dtype(sample.dtype())
+    }
+}
+
+impl Scheduler for EulerAncestralDiscreteScheduler {
+    fn timesteps(&self) -> &[usize] {
+        self.schedule.timesteps.as_slice()
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Result<Tensor> {
+        self.schedule.add_noise(original, noise, timestep)
+    }
+
+    fn init_noise_sigma(&self) -> f64 {
+        self.schedule.init_noise_sigma
+    }
+
+    fn scale_model_input(&self, sample: Tensor, timestep: usize) -> Result<Tensor> {
+        self.schedule.scale_model_input(sample, timestep)
+    }
+
+    fn step(&mut self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor> {
+        let step_index = self.schedule.index_for_timestep(timestep);
+        let sigma = self.schedule.sigmas[step_index];
+        let sigma_next = self.schedule.sigmas[step_index + 1];
+
+        // the step to sigma_next splits in a deterministic step down to sigma_down and fresh noise of sigma_up
+        let sigma_up = (sigma_next * sigma_next * (sigma * sigma - sigma_next * sigma_next) / (sigma * sigma)).sqrt();
+        let sigma_down = (sigma_next * sigma_next - sigma_up * sigma_up).sqrt();
+
+        let pred_original_sample = self.schedule.pred_original_sample(model_output, sigma, sample)?;
+        let derivative = ((sample - pred_original_sample)? / sigma)?;
+        let prev_sample = (sample + (derivative * (sigma_down - sigma))?)?;
+        let noise = self.step_noise(sample)?;
+        let prev_sample = (prev_sample + (noise * sigma_up)?)?;
+
+        self.schedule.advance(step_index);
+        Ok(prev_sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device};
+
+    fn run(seeds: &[u64]) -> anyhow::Result<Tensor> {
+        let mut scheduler = EulerAncestralDiscreteScheduler::new(&SigmaSchedulerConfig::default(), 4, seeds)?;
+        let mut sample = latents::batch_noise(seeds, (4, 4, 4), &Device::Cpu)?;
+        let model_output = Tensor::full(0.1f32, (seeds.len(), 4, 4, 4), &Device::Cpu)?;
+        for timestep in scheduler.timesteps().to_vec() {
+            sample = scheduler.step(&model_output, timestep, &sample)?;
+        }
+        Ok(sample)
+    }
+
+    #[test]
+    fn euler_ancestral_cpu_runs_are_reproducible() -> anyhow::Result<()> {
+        let first = run(&[42])?.flatten_all()?.to_vec1::<f32>()?;
+        let second = run(&[42])?.flatten_all()?.to_vec1::<f32>()?;
+        let other = run(&[43])?.flatten_all()?.to_vec1::<f32>()?;
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        Ok(())
+    }
+
+    #[test]
+    fn euler_ancestral_noise_ignores_batch_position() -> anyhow::Result<()> {
+        let batch = run(&[42, 43])?;
+        let single = run(&[43])?;
+
+        assert_eq!(batch.narrow(0, 1, 1)?.flatten_all()?.to_vec1::<f32>()?, single.flatten_all()?.to_vec1::<f32>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn euler_ancestral_rejects_unseeded_images() -> anyhow::Result<()> {
+        let mut scheduler = EulerAncestralDiscreteScheduler::new(&SigmaSchedulerConfig::default(), 4, &[1])?;
+        let timestep = scheduler.timesteps()[0];
+        let sample = Tensor::zeros((2, 4, 4, 4), DType::F32, &Device::Cpu)?;
+
+        assert!(scheduler.step(&sample, timestep, &sample).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use anyhow;
+use candle_core::{Result, Tensor};
+use candle_transformers::models::stable_diffusion::schedulers::Scheduler;
+
+use super::{SigmaSchedule, SigmaSchedulerConfig};
+
+/// Euler method on the probability flow ODE (Karras et al. 2022, algorithm 2 without churn)
+pub struct EulerDiscreteScheduler {
+    schedule: SigmaSchedule,
+}
+
+impl EulerDiscreteScheduler {
+    pub fn new(config: &SigmaSchedulerConfig, inference_steps: usize) -> anyhow::Result<Self> {
+        Ok(Self { schedule: SigmaSchedule::new(config, inference_steps)? })
+    }
+}
+
+impl Scheduler for EulerDiscreteScheduler {
+    fn timesteps(&self) -> &[usize] {
+        self.schedule.timesteps.as_slice()
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Result<Tensor> {
+        self.schedule.add_noise(original, noise, timestep)
+    }
+
+    fn init_noise_sigma(&self) -> f64 {
+        self.schedule.init_noise_sigma
+    }
+
+    fn scale_model_input(&self, sample: Tensor, timestep: usize) -> Result<Tensor> {
+        self.schedule.scale_model_input(sample, timestep)
+    }
+
+    fn step(&mut self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor> {
+        let step_index = self.schedule.index_for_timestep(timestep);
+        let sigma = self.schedule.sigmas[step_index];
+        let sigma_next = self.schedule.sigmas[step_index + 1];
+
+        let pred_original_sample = self.schedule.pred_original_sample(model_output, sigma, sample)?;
+        let derivative = ((sample - pred_original_sample)? / sigma)?;
+        let prev_sample = (sample + (derivative * (sigma_next - sigma))?)?;
+
+        self.schedule.advance(step_index);
+        Ok(prev_sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device};
+
+    #[test]
+    fn euler_last_step_returns_denoised_sample() -> anyhow::Result<()> {
+        let mut scheduler = EulerDiscreteScheduler::new(&SigmaSchedulerConfig::default(), 1)?;
+        let timestep = scheduler.timesteps()[0];
+        let sample = Tensor::ones((1, 4, 2, 2), DType::F32, &Device::Cpu)?;
+        let model_output = Tensor::zeros((1, 4, 2, 2), DType::F32, &Device::Cpu)?;
+
+        // with no predicted noise the sample is already clean, and the final sigma of 0 leaves it unchanged
+        let prev_sample = scheduler.step(&model_output, timestep, &sample)?;
+        assert_eq!(prev_sample.flatten_all()?.to_vec1::<f32>()?, vec![1f32; 16]);
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow;
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::Module;
+use candle_transformers::models::stable_diffusion::embeddings::{TimestepEmbedding, Timesteps};
+use candle_transformers::models::stable_diffusion::unet_2d_blocks::{
+    CrossAttnDownBlock2D, CrossAttnDownBlock2DConfig, CrossAttnUpBlock2D, CrossAttnUpBlock2DConfig, DownBlock2D, DownBlock2DConfig,
+    UNetMidBlock2DCrossAttn, UNetMidBlock2DCrossAttnConfig, UpBlock2D, UpBlock2DConfig,
+};
+
+/// Output channels, transformer layers (None for blocks without attention) and attention heads of every block,
+/// the same for SDXL, Turbo and the SDXL inpainting UNet
+const BLOCKS: [(usize, Option<usize>, usize); 3] = [(320, None, 5), (640, Some(2), 10), (1280, Some(10), 20)];
+const LAYERS_PER_BLOCK: usize = 2;
+const NORM_NUM_GROUPS: usize = 32;
+const NORM_EPS: f64 = 1e-5;
+const CROSS_ATTENTION_DIM: usize = 2048;
+/// Channels of the sinusoidal embedding of each added time id
+const ADDITION_TIME_EMBED_DIM: usize = 256;
+/// Original size, crop top left corner and target size
+const N_TIME_IDS: usize = 6;
+/// Features of the pooled embeddings of the second text encoder
+const POOLED_PROJECTION_DIM: usize = 1280;
+
+/// Conditioning SDXL adds to the timestep embedding, one row per latent of the batch
+pub struct AddedConditioning {
+    /// Pooled and projected embeddings of the second text encoder, (batch, 1280)
+    pub text_embeds: Tensor,
+    /// (batch, 6)
+    pub time_ids: Tensor,
+}
+
+/// Time ids of images generated at `height` x `width` without cropping: original size, crop top left and target size
+pub fn add_time_ids(height: usize, width: usize, batch_size: usize, dtype: DType, device: &Device) -> anyhow::Result<Tensor> {
+    let (height, width) = (height as f32, width as f32);
+    let time_ids = Tensor::new(&[[height, width, 0., 0., height, width]], device)?;
+    Ok(time_ids.repeat((batch_size, 1))?.to_dtype(dtype)?)
+}
+
+enum DownBlock {
+    Basic(DownBlock2D),
+    CrossAttn(CrossAttnDownBlock2D),
+}
+
+enum UpBlock {
+    Basic(UpBlock2D),
+    CrossAttn(CrossAttnUpBlock2D),
+}
+
+/// SDXL UNet, the one of candle with the `add_embedding` of the pooled text embeddings and the time ids.
+/// candle loads the same weights but leaves `add_embedding` out of the timestep embedding
+pub struct SdxlUNet {
+    conv_in: candle_nn::Conv2d,
+    time_proj: Timesteps,
+    time_embedding: TimestepEmbedding,
+    add_time_proj: Timesteps,
+    add_embedding: TimestepEmbedding,
+    down_blocks: Vec<DownBlock>,
+    mid_block: UNetMidBlock2DCrossAttn,
+    up_blocks: Vec<UpBlock>,
+    conv_norm_out: candle_nn::GroupNorm,
+    conv_out: candle_nn::Conv2d,
+}
+
+impl SdxlUNet {
+    pub fn new(vs: candle_nn::VarBuilder, in_channels: usize, out_channels: usize, use_flash_attn: bool) -> Result<Self> {
+        let n_blocks = BLOCKS.len();
+        let b_channels = BLOCKS[0].0;
+        let (bl_channels, bl_transformer_layers, bl_heads) = BLOCKS[n_blocks - 1];
+        let time_embed_dim = b_channels * 4;
+        let conv_config = candle_nn::Conv2dConfig { padding: 1, ..Default::default() };
+        let conv_in = candle_nn::conv2d(in_channels, b_channels, 3, conv_config, vs.pp("conv_in"))?;
+
+        let time_proj = Timesteps::new(b_channels, true, 0.);
+        let time_embedding = TimestepEmbedding::new(vs.pp("time_embedding"), b_channels, time_embed_dim)?;
+        let add_time_proj = Timesteps::new(ADDITION_TIME_EMBED_DIM, true, 0.);
+        let add_embedding = TimestepEmbedding::new(vs.pp("add_embedding"), POOLED_PROJECTION_DIM + N_TIME_IDS * ADDITION_TIME_EMBED_DIM, time_embed_dim)?;
+
+        let mut down_blocks = vec![];
+        for (index, (out_channels, transformer_layers, heads)) in BLOCKS.iter().copied().enumerate() {
+            let in_channels = if index > 0 { BLOCKS[index - 1].0 } else { b_channels };
+            let config = DownBlock2DConfig {
+                num_layers: LAYERS_PER_BLOCK,
+                resnet_eps: NORM_EPS,
+                resnet_groups: NORM_NUM_GROUPS,
+                add_downsample: index < n_blocks - 1,
+                downsample_padding: 1,
+                ..Default::default()
+            };
+            let vs = vs.pp(format!("down_blocks.{}", index));
+            let block = match transformer_layers {
+                Some(transformer_layers_per_block) => {
+                    let config = CrossAttnDownBlock2DConfig {
+                        downblock: config,
+                        attn_num_head_channels: heads,
+                        cross_attention_dim: CROSS_ATTENTION_DIM,
+                        sliced_attention_size: None,
+                        use_linear_projection: true,
+                        transformer_layers_per_block,
+                    };
+                    DownBlock::CrossAttn(CrossAttnDownBlock2D::new(vs, in_channels, out_channels, Some(time_embed_dim), use_flash_attn, config)?)
+                },
+                None => DownBlock::Basic(DownBlock2D::new(vs, in_channels, out_channels, Some(time_embed_dim), config)?)
+            };
+            down_blocks.push(block);
+        }
+
+        let mid_config = UNetMidBlock2DCrossAttnConfig {
+            resnet_eps: NORM_EPS,
+            output_scale_factor: 1.,
+            cross_attn_dim: CROSS_ATTENTION_DIM,
+            attn_num_head_channels: bl_heads,
+            resnet_groups: Some(NORM_NUM_GROUPS),
+            use_linear_projection: true,
+            transformer_layers_per_block: bl_transformer_layers.unwrap_or(1),
+            ..Default::default()
+        };
+        let mid_block = UNetMidBlock2DCrossAttn::new(vs.pp("mid_block"), bl_channels, Some(time_embed_dim), use_flash_attn, mid_config)?;
+
+        let mut up_blocks = vec![];
+        for index in 0..n_blocks {
+            let (out_channels, transformer_layers, heads) = BLOCKS[n_blocks - 1 - index];
+            let prev_out_channels = if index > 0 { BLOCKS[n_blocks - index].0 } else { bl_channels };
+            let in_channels = BLOCKS[if index == n_blocks - 1 { 0 } else { n_blocks - index - 2 }].0;
+            let config = UpBlock2DConfig {
+                num_layers: LAYERS_PER_BLOCK + 1,
+                resnet_eps: NORM_EPS,
+                resnet_groups: NORM_NUM_GROUPS,
+                add_upsample: index < n_blocks - 1,
+                ..Default::default()
+            };
+            let vs = vs.pp(format!("up_blocks.{}", index));
+            let block = match transformer_layers {
+                Some(transformer_layers_per_block) => {
+                    let config = CrossAttnUpBlock2DConfig {
+                        upblock: config,
+                        attn_num_head_channels: heads,
+                        cross_attention_dim: CROSS_ATTENTION_DIM,
+                        sliced_attention_size: None,
+                        use_linear_projection: true,
+                        transformer_layers_per_block,
+                    };
+                    UpBlock::CrossAttn(CrossAttnUpBlock2D::new(vs, in_channels, prev_out_channels, out_channels, Some(time_embed_dim), use_flash_attn, config)?)
+                },
+                None => UpBlock::Basic(UpBlock2D::new(vs, in_channels, prev_out_channels, out_channels, Some(time_embed_dim), config)?)
+            };
+            up_blocks.push(block);
+        }
+
+        let conv_norm_out = candle_nn::group_norm(NORM_NUM_GROUPS, b_channels, NORM_EPS, vs.pp("conv_norm_out"))?;
+        let conv_out = candle_nn::conv2d(b_channels, out_channels, 3, conv_config, vs.pp("conv_out"))?;
+
+        Ok(Self { conv_in, time_proj, time_embedding, add_time_proj, add_embedding, down_blocks, mid_block, up_blocks, conv_norm_out, conv_out })
+    }
+
+    /// Timestep embedding plus the embedding of the pooled text embeddings and the time ids
+    fn embedding(&self, batch_size: usize, timestep: f64, added: &AddedConditioning, dtype: DType, device: &Device) -> Result<Tensor> {
+        let emb = (Tensor::ones(batch_size, dtype, device)? * timestep)?;
+        let emb = self.time_embedding.forward(&self.time_proj.forward(&emb)?)?;
+
+        let time_embeds = self.add_time_proj
+            .forward(&added.time_ids.to_dtype(dtype)?.flatten_all()?)?
+            .reshape((batch_size, N_TIME_IDS * ADDITION_TIME_EMBED_DIM))?;
+        let add_embeds = Tensor::cat(&[&added.text_embeds.to_dtype(dtype)?, &time_embeds], 1)?;
+        emb + self.add_embedding.forward(&add_embeds)?
+    }
+
+    pub fn forward(&self, xs: &Tensor, timestep: f64, encoder_hidden_states: &Tensor, added: &AddedConditioning) -> Result<Tensor> {
+        let (batch_size, _, height, width) = xs.dims4()?;
+        let n_blocks = self.up_blocks.len();
+        let up_factor = 2usize.pow((n_blocks - 1) as u32);
+        let forward_upsample_size = height % up_factor != 0 || width % up_factor != 0;
+
+        let emb = self.embedding(batch_size, timestep, added, xs.dtype(), xs.device())?;
+        let xs = self.conv_in.forward(xs)?;
+
+        let mut down_block_res_xs = vec![xs.clone()];
+        let mut xs = xs;
+        for down_block in self.down_blocks.iter() {
+            let (block_xs, res_xs) = match down_block {
+                DownBlock::Basic(block) => block.forward(&xs, Some(&emb))?,
+                DownBlock::CrossAttn(block) => block.forward(&xs, Some(&emb), Some(encoder_hidden_states))?,
+            };
+            down_block_res_xs.extend(res_xs);
+            xs = block_xs;
+        }
+
+        let mut xs = self.mid_block.forward(&xs, Some(&emb), Some(encoder_hidden_states))?;
+
+        let mut upsample_size = None;
+        for (index, up_block) in self.up_blocks.iter().enumerate() {
+            // every up block has a resnet more than the down blocks, each taking a skip connection
+            let res_xs = down_block_res_xs.split_off(down_block_res_xs.len() - (LAYERS_PER_BLOCK + 1));
+            if index < n_blocks - 1 && forward_upsample_size {
+                let (_, _, h, w) = down_block_res_xs[down_block_res_xs.len() - 1].dims4()?;
+                upsample_size = Some((h, w));
+            }
+            xs = match up_block {
+                UpBlock::Basic(block) => block.forward(&xs, &res_xs, Some(&emb), upsample_size)?,
+                UpBlock::CrossAttn(block) => block.forward(&xs, &res_xs, Some(&emb), upsample_size, Some(encoder_hidden_states))?,
+            };
+        }
+
+        let xs = self.conv_norm_out.forward(&xs)?;
+        candle_nn::ops::silu(&xs)?.apply(&self.conv_out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sdxl_unet_add_time_ids() -> anyhow::Result<()> {
+        let time_ids = add_time_ids(768, 1024, 2, DType::F32, &Device::Cpu)?;
+
+        assert_eq!(time_ids.to_vec2::<f32>()?, vec![vec![768., 1024., 0., 0., 768., 1024.]; 2]);
+        Ok(())
+    }
+}
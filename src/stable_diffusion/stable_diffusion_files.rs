@@ -1,17 +1,120 @@
 use std;
 use candle_transformers::models::stable_diffusion;
-use hf_hub::api::sync::Api;
+use hf_hub::api::sync::ApiBuilder;
+use hf_hub::Cache;
 use anyhow::Result;
 
-use crate::stable_diffusion::constants;
+use crate::stable_diffusion::{constants, diffusers_folder};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StableDiffusionFiles{
 
     Tokenizer,
     Clip,
     Unet,
-    Vae
+    Vae,
+    Tokenizer2,
+    Clip2
+}
+
+impl std::fmt::Display for StableDiffusionFiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            StableDiffusionFiles::Tokenizer => write!(f, "tokenizer"),
+            StableDiffusionFiles::Clip => write!(f, "clip"),
+            StableDiffusionFiles::Unet => write!(f, "unet"),
+            StableDiffusionFiles::Vae => write!(f, "vae"),
+            StableDiffusionFiles::Tokenizer2 => write!(f, "tokenizer_2"),
+            StableDiffusionFiles::Clip2 => write!(f, "clip_2")
+        }
+    }
+}
+
+/// Where a model file was taken from: a hub repo and a file in it, or a local file when `repo` is None
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ResolvedModelFile {
+    pub component: String,
+    pub repo: Option<String>,
+    pub file: String,
+    pub path: std::path::PathBuf,
+}
+
+/// Local files replacing the ones of the model repositories
+#[derive(Debug, Clone, Default)]
+pub struct ModelFileOverrides {
+    pub tokenizer: Option<String>,
+    pub tokenizer2: Option<String>,
+    pub clip: Option<String>,
+    pub clip2: Option<String>,
+    pub unet: Option<String>,
+    pub vae: Option<String>,
+}
+
+impl ModelFileOverrides {
+    pub fn get(&self, sd_file: &StableDiffusionFiles) -> Option<String> {
+        match sd_file {
+            StableDiffusionFiles::Tokenizer => self.tokenizer.clone(),
+            StableDiffusionFiles::Tokenizer2 => self.tokenizer2.clone(),
+            StableDiffusionFiles::Clip => self.clip.clone(),
+            StableDiffusionFiles::Clip2 => self.clip2.clone(),
+            StableDiffusionFiles::Unet => self.unet.clone(),
+            StableDiffusionFiles::Vae => self.vae.clone(),
+        }
+    }
+}
+
+/// Where model files are looked up: overrides first, then `model_dir`, then the HF hub cache and the hub itself
+#[derive(Debug, Clone, Default)]
+pub struct ModelSource {
+    pub overrides: ModelFileOverrides,
+    /// Directory with the repository layout of the files, e.g. `<model_dir>/unet/diffusion_pytorch_model.safetensors`,
+    /// or a diffusers folder, see `diffusers_folder`
+    pub model_dir: Option<std::path::PathBuf>,
+    /// HF hub cache, `HF_HOME` or `~/.cache/huggingface` by default
+    pub cache_dir: Option<std::path::PathBuf>,
+    /// HF hub endpoint, `HF_ENDPOINT` or `https://huggingface.co` by default
+    pub endpoint: Option<String>,
+    /// Only use local and cached files, never the network
+    pub offline: bool,
+}
+
+impl ModelSource {
+    pub fn cache(&self) -> Cache {
+        match &self.cache_dir {
+            Some(cache_dir) => Cache::new(cache_dir.clone()),
+            None => Cache::from_env()
+        }
+    }
+
+    pub fn api_builder(&self) -> ApiBuilder {
+        let mut builder = ApiBuilder::from_env();
+        if let Some(cache_dir) = &self.cache_dir {
+            builder = builder.with_cache_dir(cache_dir.clone());
+        }
+        if let Some(endpoint) = &self.endpoint {
+            builder = builder.with_endpoint(endpoint.clone());
+        }
+        builder
+    }
+
+    pub fn cached_file(&self, repo: &str, filepath: &str) -> Option<std::path::PathBuf> {
+        self.cache().model(repo.to_string()).get(filepath)
+    }
+
+    /// Path of a file of a hub repository, from the cache only when offline
+    pub fn hub_file(&self, repo: &str, filepath: &str) -> Result<std::path::PathBuf> {
+        if self.offline {
+            return match self.cached_file(repo, filepath) {
+                Some(path) => Ok(path),
+                None => anyhow::bail!(
+                    "{} of {} is not in the cache {} and --offline forbids downloading it, \
+                     run the download command first or point at a local file",
+                    filepath, repo, self.cache().path().display()
+                )
+            };
+        }
+        Ok(self.api_builder().build()?.model(repo.to_string()).get(filepath)?)
+    }
 }
 
 #[derive(Debug, Clone, Copy, clap::ValueEnum, PartialEq, Eq)]
@@ -20,6 +123,39 @@ pub enum StableDiffusionVersion {
     V2_1,
     Xl,
     Turbo,
+    V1_5Inpaint,
+    V2Inpaint,
+    XlInpaint,
+}
+
+impl StableDiffusionVersion {
+    /// Dedicated inpainting UNets take the mask and the masked image latents as extra input channels
+    pub fn is_inpainting(&self) -> bool {
+        matches!(self, StableDiffusionVersion::V1_5Inpaint | StableDiffusionVersion::V2Inpaint | StableDiffusionVersion::XlInpaint)
+    }
+
+    /// SDXL based versions condition the UNet on the concatenated outputs of two CLIP text encoders
+    pub fn has_second_text_encoder(&self) -> bool {
+        matches!(self, StableDiffusionVersion::Xl | StableDiffusionVersion::Turbo | StableDiffusionVersion::XlInpaint)
+    }
+
+    /// Classifier free guidance scale used when none is given, turbo is distilled to run without
+    pub fn default_guidance_scale(&self) -> f64 {
+        match self {
+            StableDiffusionVersion::V1_5
+            | StableDiffusionVersion::V2_1
+            | StableDiffusionVersion::Xl
+            | StableDiffusionVersion::V1_5Inpaint
+            | StableDiffusionVersion::V2Inpaint
+            | StableDiffusionVersion::XlInpaint => 7.5,
+            StableDiffusionVersion::Turbo => 0.,
+        }
+    }
+
+    pub fn unet_in_channels(&self) -> usize {
+        // 4 latent channels, plus 1 for the mask and 4 for the masked image latents
+        if self.is_inpainting() { 9 } else { 4 }
+    }
 }
 
 pub trait ModelFileBuild {
@@ -35,6 +171,10 @@ pub trait ModelFileBuild {
         if use_f16 { constants::MODELFILE_CLIP_FP16 } else { constants::MODELFILE_CLIP }
     }
 
+    fn get_clip2_filepath(&self, use_f16: bool) -> &str {
+        if use_f16 { constants::MODELFILE_CLIP2_FP16 } else { constants::MODELFILE_CLIP2 }
+    }
+
     fn get_unet_filepath(&self, use_f16: bool) -> &str {
         if use_f16 { constants::MODELFILE_UNET_FP16 } else { constants::MODELFILE_UNET }
     }
@@ -42,39 +182,109 @@ pub trait ModelFileBuild {
     fn get_vae_filepath(&self, use_f16: bool) -> &str {
         if use_f16 { constants::MODELFILE_VAE_FP16 } else { constants::MODELFILE_VAE }
     }
+    fn get_filepath(&self, sd_file: &StableDiffusionFiles, use_f16: bool) -> &str {
+        match sd_file {
+            StableDiffusionFiles::Tokenizer | StableDiffusionFiles::Tokenizer2 => self.get_tokenizer_filepath(),
+            StableDiffusionFiles::Clip => {
+                self.get_clip_filepath(use_f16)
+            },
+            StableDiffusionFiles::Clip2 => {
+                self.get_clip2_filepath(use_f16)
+            },
+            StableDiffusionFiles::Unet => {
+                self.get_unet_filepath(use_f16)
+            },
+            StableDiffusionFiles::Vae => {
+                self.get_vae_filepath(use_f16)
+            }
+        }
+    }
+
     fn get(&self, sd_file: &StableDiffusionFiles, filename: Option<String>, use_f16: bool) -> Result<std::path::PathBuf> {
-        match filename {
-            Some(filename) => Ok(std::path::PathBuf::from(filename)),
-            None => {
-                let repo = self.get_repo_with_precision(sd_file, Some(use_f16));
-                let filepath = match sd_file {
-                    StableDiffusionFiles::Tokenizer => self.get_tokenizer_filepath(),
-                    StableDiffusionFiles::Clip => {
-                        self.get_clip_filepath(use_f16)
-                    },
-                    StableDiffusionFiles::Unet => {
-                        self.get_unet_filepath(use_f16)
-                    },
-                    StableDiffusionFiles::Vae => {
-                        self.get_vae_filepath(use_f16)
-                    }
-                };
-
-                let filename = Api::new()?.model(repo.to_string()).get(filepath)?;
-                Ok(filename)
+        Ok(self.resolve(sd_file, filename, use_f16)?.path)
+    }
+
+    /// Same as `get`, also telling where the file comes from
+    fn resolve(&self, sd_file: &StableDiffusionFiles, filename: Option<String>, use_f16: bool) -> Result<ResolvedModelFile> {
+        let source = ModelSource {
+            overrides: filename.map_or_else(ModelFileOverrides::default, |filename| overrides_for(sd_file, filename)),
+            ..Default::default()
+        };
+        self.resolve_from(sd_file, use_f16, &source)
+    }
+
+    /// The file given by the overrides or found in the model directory of `source`, if any
+    fn resolve_local(&self, sd_file: &StableDiffusionFiles, use_f16: bool, source: &ModelSource) -> Result<Option<ResolvedModelFile>> {
+        if let Some(filename) = source.overrides.get(sd_file) {
+            let path = std::path::PathBuf::from(&filename);
+            if !path.is_file() {
+                anyhow::bail!("The {} file {} does not exist", sd_file, filename)
+            }
+            return Ok(Some(ResolvedModelFile { component: sd_file.to_string(), repo: None, file: filename, path }));
+        }
+
+        if let Some(model_dir) = source.model_dir.as_ref().filter(|model_dir| diffusers_folder::is_diffusers_folder(model_dir)) {
+            return match diffusers_folder::component_file(model_dir, sd_file, use_f16) {
+                Some(file) => Ok(Some(ResolvedModelFile { component: sd_file.to_string(), repo: None, path: model_dir.join(&file), file })),
+                // a folder without any tokenizer files takes the ones of the hub, they fit
+                None if matches!(sd_file, StableDiffusionFiles::Tokenizer | StableDiffusionFiles::Tokenizer2) => Ok(None),
+                None => anyhow::bail!(
+                    "No {} weights in {}, expected {}/ with safetensors files, fp16 or not",
+                    sd_file, model_dir.display(), diffusers_folder::component_dir(sd_file)
+                )
+            };
+        }
+
+        let filepath = self.get_filepath(sd_file, use_f16);
+        if let Some(model_dir) = &source.model_dir {
+            let path = model_dir.join(filepath);
+            if path.is_file() {
+                return Ok(Some(ResolvedModelFile { component: sd_file.to_string(), repo: None, file: filepath.to_string(), path }));
             }
         }
+        Ok(None)
+    }
+
+    /// Same as `resolve`, looking the file up in `source`
+    fn resolve_from(&self, sd_file: &StableDiffusionFiles, use_f16: bool, source: &ModelSource) -> Result<ResolvedModelFile> {
+        if let Some(model_file) = self.resolve_local(sd_file, use_f16, source)? {
+            return Ok(model_file);
+        }
+
+        let repo = self.get_repo_with_precision(sd_file, Some(use_f16));
+        let filepath = self.get_filepath(sd_file, use_f16);
+        let path = source.hub_file(repo, filepath)?;
+        Ok(ResolvedModelFile {
+            component: sd_file.to_string(),
+            repo: Some(repo.to_string()),
+            file: filepath.to_string(),
+            path,
+        })
     }
 }
 
+fn overrides_for(sd_file: &StableDiffusionFiles, filename: String) -> ModelFileOverrides {
+    let mut overrides = ModelFileOverrides::default();
+    let file = match sd_file {
+        StableDiffusionFiles::Tokenizer => &mut overrides.tokenizer,
+        StableDiffusionFiles::Tokenizer2 => &mut overrides.tokenizer2,
+        StableDiffusionFiles::Clip => &mut overrides.clip,
+        StableDiffusionFiles::Clip2 => &mut overrides.clip2,
+        StableDiffusionFiles::Unet => &mut overrides.unet,
+        StableDiffusionFiles::Vae => &mut overrides.vae,
+    };
+    *file = Some(filename);
+    overrides
+}
+
 pub struct StableDiffusion1_5 {}
 
 
 impl ModelFileBuild for StableDiffusion1_5 {
     fn get_repo_with_precision(&self, sd_file: &StableDiffusionFiles, _use_f16: Option<bool>) -> &str {
         match sd_file {
-            StableDiffusionFiles::Tokenizer => constants::REPO_TOKENIZER,
-            StableDiffusionFiles::Clip|StableDiffusionFiles::Unet|StableDiffusionFiles::Vae => constants::REPO_1_5
+            StableDiffusionFiles::Tokenizer|StableDiffusionFiles::Tokenizer2 => constants::REPO_TOKENIZER,
+            StableDiffusionFiles::Clip|StableDiffusionFiles::Clip2|StableDiffusionFiles::Unet|StableDiffusionFiles::Vae => constants::REPO_1_5
         }
     }
 
@@ -86,8 +296,8 @@ pub struct StableDiffusion2_1{}
 impl ModelFileBuild for StableDiffusion2_1 {
     fn get_repo_with_precision(&self, sd_file: &StableDiffusionFiles, _use_f16: Option<bool>) -> &str {
         match sd_file {
-            StableDiffusionFiles::Tokenizer => constants::REPO_TOKENIZER,
-            StableDiffusionFiles::Clip|StableDiffusionFiles::Unet|StableDiffusionFiles::Vae => constants::REPO_2_1
+            StableDiffusionFiles::Tokenizer|StableDiffusionFiles::Tokenizer2 => constants::REPO_TOKENIZER,
+            StableDiffusionFiles::Clip|StableDiffusionFiles::Clip2|StableDiffusionFiles::Unet|StableDiffusionFiles::Vae => constants::REPO_2_1
         }
     }
 }
@@ -99,7 +309,8 @@ impl ModelFileBuild for StableDiffusionTurbo{
     fn get_repo_with_precision(&self, sd_file: &StableDiffusionFiles, _use_f16: Option<bool>) -> &str {
         match sd_file {
             StableDiffusionFiles::Tokenizer => constants::REPO_TOKENIZER_X1TURBO,
-            StableDiffusionFiles::Clip|StableDiffusionFiles::Unet => constants::REPO_TURBO,
+            StableDiffusionFiles::Tokenizer2 => constants::REPO_TOKENIZER2,
+            StableDiffusionFiles::Clip|StableDiffusionFiles::Clip2|StableDiffusionFiles::Unet => constants::REPO_TURBO,
             StableDiffusionFiles::Vae => if _use_f16.unwrap_or(false) {constants::REPO_VAE_X1TURBO_FP16} else {constants::REPO_TURBO}
         }
     }
@@ -115,7 +326,8 @@ impl ModelFileBuild for StableDiffusionX1{
     fn get_repo_with_precision(&self, sd_file: &StableDiffusionFiles, _use_f16: Option<bool>) -> &str {
         match sd_file {
             StableDiffusionFiles::Tokenizer => constants::REPO_TOKENIZER_X1TURBO,
-            StableDiffusionFiles::Clip|StableDiffusionFiles::Unet => constants::REPO_X1,
+            StableDiffusionFiles::Tokenizer2 => constants::REPO_TOKENIZER2,
+            StableDiffusionFiles::Clip|StableDiffusionFiles::Clip2|StableDiffusionFiles::Unet => constants::REPO_X1,
             StableDiffusionFiles::Vae => if _use_f16.unwrap_or(false) {constants::REPO_VAE_X1TURBO_FP16} else {constants::REPO_TURBO}
         }
     }
@@ -125,21 +337,116 @@ impl ModelFileBuild for StableDiffusionX1{
     }
 }
 
+pub struct StableDiffusion1_5Inpaint{}
+
+impl ModelFileBuild for StableDiffusion1_5Inpaint {
+    fn get_repo_with_precision(&self, sd_file: &StableDiffusionFiles, _use_f16: Option<bool>) -> &str {
+        match sd_file {
+            StableDiffusionFiles::Tokenizer|StableDiffusionFiles::Tokenizer2 => constants::REPO_TOKENIZER,
+            StableDiffusionFiles::Clip|StableDiffusionFiles::Clip2|StableDiffusionFiles::Unet|StableDiffusionFiles::Vae => constants::REPO_1_5_INPAINT
+        }
+    }
+}
+
+pub struct StableDiffusion2Inpaint{}
+
+impl ModelFileBuild for StableDiffusion2Inpaint {
+    fn get_repo_with_precision(&self, sd_file: &StableDiffusionFiles, _use_f16: Option<bool>) -> &str {
+        match sd_file {
+            StableDiffusionFiles::Tokenizer|StableDiffusionFiles::Tokenizer2 => constants::REPO_TOKENIZER,
+            StableDiffusionFiles::Clip|StableDiffusionFiles::Clip2|StableDiffusionFiles::Unet|StableDiffusionFiles::Vae => constants::REPO_2_INPAINT
+        }
+    }
+}
+
+pub struct StableDiffusionX1Inpaint{}
+
+impl ModelFileBuild for StableDiffusionX1Inpaint{
+    fn get_repo_with_precision(&self, sd_file: &StableDiffusionFiles, _use_f16: Option<bool>) -> &str {
+        match sd_file {
+            StableDiffusionFiles::Tokenizer => constants::REPO_TOKENIZER_X1TURBO,
+            StableDiffusionFiles::Tokenizer2 => constants::REPO_TOKENIZER2,
+            StableDiffusionFiles::Clip|StableDiffusionFiles::Clip2|StableDiffusionFiles::Unet => constants::REPO_X1_INPAINT,
+            StableDiffusionFiles::Vae => if _use_f16.unwrap_or(false) {constants::REPO_VAE_X1TURBO_FP16} else {constants::REPO_X1_INPAINT}
+        }
+    }
+
+    fn get_vae_filepath(&self, use_f16: bool) -> &str {
+        if use_f16 { constants::MODELFILE_VAE_X1TURBO_FP16 } else { constants::MODELFILE_VAE }
+    }
+}
+
 pub fn create_sd_from_version(sd_version: &StableDiffusionVersion) -> Box<dyn ModelFileBuild>{
     match sd_version {
         StableDiffusionVersion::V1_5 => Box::new(StableDiffusion1_5{}),
         StableDiffusionVersion::V2_1 =>  Box::new(StableDiffusion2_1{}),
         StableDiffusionVersion::Turbo =>  Box::new(StableDiffusionTurbo{}),
-        StableDiffusionVersion::Xl =>  Box::new(StableDiffusionX1{})
+        StableDiffusionVersion::Xl =>  Box::new(StableDiffusionX1{}),
+        StableDiffusionVersion::V1_5Inpaint => Box::new(StableDiffusion1_5Inpaint{}),
+        StableDiffusionVersion::V2Inpaint => Box::new(StableDiffusion2Inpaint{}),
+        StableDiffusionVersion::XlInpaint => Box::new(StableDiffusionX1Inpaint{})
     }
 }
 
+/// Components a generation with `sd_version` loads
+pub fn model_sd_files(sd_version: &StableDiffusionVersion) -> Vec<StableDiffusionFiles> {
+    let mut sd_files = vec![StableDiffusionFiles::Tokenizer, StableDiffusionFiles::Clip];
+    if sd_version.has_second_text_encoder() {
+        sd_files.extend([StableDiffusionFiles::Tokenizer2, StableDiffusionFiles::Clip2]);
+    }
+    sd_files.extend([StableDiffusionFiles::Unet, StableDiffusionFiles::Vae]);
+    sd_files
+}
+
+/// Precision a component is fetched with, tokenizers are always fetched with use_f16, see clip_embeddings::get_tokenizer
+pub fn sd_file_use_f16(sd_file: &StableDiffusionFiles, use_f16: bool) -> bool {
+    use_f16 || matches!(sd_file, StableDiffusionFiles::Tokenizer | StableDiffusionFiles::Tokenizer2)
+}
+
+/// Path of a component in the diffusers layout of a model repository
+pub fn diffusers_filepath(sd_file: &StableDiffusionFiles, use_f16: bool) -> &'static str {
+    match (sd_file, use_f16) {
+        (StableDiffusionFiles::Tokenizer | StableDiffusionFiles::Tokenizer2, _) => constants::MODELFILE_TOKENIZER,
+        (StableDiffusionFiles::Clip, true) => constants::MODELFILE_CLIP_FP16,
+        (StableDiffusionFiles::Clip, false) => constants::MODELFILE_CLIP,
+        (StableDiffusionFiles::Clip2, true) => constants::MODELFILE_CLIP2_FP16,
+        (StableDiffusionFiles::Clip2, false) => constants::MODELFILE_CLIP2,
+        (StableDiffusionFiles::Unet, true) => constants::MODELFILE_UNET_FP16,
+        (StableDiffusionFiles::Unet, false) => constants::MODELFILE_UNET,
+        (StableDiffusionFiles::Vae, true) => constants::MODELFILE_VAE_FP16,
+        (StableDiffusionFiles::Vae, false) => constants::MODELFILE_VAE,
+    }
+}
+
+/// Every model file a generation with `sd_version` loads, as resolved by `ModelFileBuild::resolve_from`
+pub fn resolve_model_files(sd_version: &StableDiffusionVersion, use_f16: bool, source: &ModelSource) -> Result<Vec<ResolvedModelFile>> {
+    resolve_files(create_sd_from_version(sd_version).as_ref(), sd_version, use_f16, source)
+}
+
+/// Same as `resolve_model_files` for the files of `sd`, `sd_version` being the architecture they follow
+pub fn resolve_files(sd: &dyn ModelFileBuild, sd_version: &StableDiffusionVersion, use_f16: bool, source: &ModelSource) -> Result<Vec<ResolvedModelFile>> {
+    model_sd_files(sd_version).iter()
+        .map(|sd_file| sd.resolve_from(sd_file, sd_file_use_f16(sd_file, use_f16), source))
+        .collect()
+}
+
+/// Path of the file of a component among resolved files, to hand over to its loader
+pub fn model_file_path(model_files: &[ResolvedModelFile], sd_file: &StableDiffusionFiles) -> Option<String> {
+    let component = sd_file.to_string();
+    model_files.iter()
+        .find(|model_file| model_file.component == component)
+        .map(|model_file| model_file.path.to_string_lossy().to_string())
+}
+
 pub fn get_sd_config_from_version(sd_version: &StableDiffusionVersion, sliced_attention_size: Option<usize>, height: Option<usize>, width: Option<usize>) -> stable_diffusion::StableDiffusionConfig {
     match sd_version {
         StableDiffusionVersion::V1_5 => stable_diffusion::StableDiffusionConfig::v1_5(sliced_attention_size, height, width),
         StableDiffusionVersion::V2_1 => stable_diffusion::StableDiffusionConfig::v2_1(sliced_attention_size, height, width),
         StableDiffusionVersion::Turbo => stable_diffusion::StableDiffusionConfig::sdxl_turbo(sliced_attention_size, height, width),
-        StableDiffusionVersion::Xl => stable_diffusion::StableDiffusionConfig::sdxl(sliced_attention_size, height, width)
+        StableDiffusionVersion::Xl => stable_diffusion::StableDiffusionConfig::sdxl(sliced_attention_size, height, width),
+        StableDiffusionVersion::V1_5Inpaint => stable_diffusion::StableDiffusionConfig::v1_5(sliced_attention_size, height, width),
+        StableDiffusionVersion::V2Inpaint => stable_diffusion::StableDiffusionConfig::v2_1_inpaint(sliced_attention_size, height, width),
+        StableDiffusionVersion::XlInpaint => stable_diffusion::StableDiffusionConfig::sdxl_inpaint(sliced_attention_size, height, width)
     }
 }
 
@@ -188,4 +495,82 @@ mod tests {
         assert_eq!(encoder_repo, "stable-diffusion-v1-5/stable-diffusion-v1-5");
         assert_eq!(encoder_path, "text_encoder/model.fp16.safetensors");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sd_files_inpaint() {
+        let model_file = StableDiffusionFiles::Unet;
+        let sd_version = StableDiffusion2Inpaint{};
+        let unet_repo = sd_version.get_repo(&model_file);
+
+        assert_eq!(unet_repo, "stabilityai/stable-diffusion-2-inpainting");
+        assert!(StableDiffusionVersion::XlInpaint.is_inpainting());
+        assert!(!StableDiffusionVersion::Xl.is_inpainting());
+        assert_eq!(StableDiffusionVersion::V1_5Inpaint.unet_in_channels(), 9);
+        assert_eq!(StableDiffusionVersion::V1_5.unet_in_channels(), 4);
+    }
+
+    #[test]
+    fn sd_files_second_encoder() {
+        let sd_version = StableDiffusionX1{};
+        let tokenizer_repo = sd_version.get_repo(&StableDiffusionFiles::Tokenizer2);
+        let encoder_repo = sd_version.get_repo(&StableDiffusionFiles::Clip2);
+        let encoder_path = sd_version.get_clip2_filepath(false);
+
+        assert_eq!(tokenizer_repo, "laion/CLIP-ViT-bigG-14-laion2B-39B-b160k");
+        assert_eq!(encoder_repo, "stabilityai/stable-diffusion-xl-base-1.0");
+        assert_eq!(encoder_path, "text_encoder_2/model.safetensors");
+        assert!(StableDiffusionVersion::Turbo.has_second_text_encoder());
+        assert!(!StableDiffusionVersion::V2_1.has_second_text_encoder());
+    }
+
+    #[test]
+    fn sd_files_resolve_offline() -> Result<()> {
+        let root = crate::test_utils::TempDir::new("models")?;
+        let model_dir = root.join("model");
+        std::fs::create_dir_all(model_dir.join("unet"))?;
+        std::fs::write(model_dir.join("unet/diffusion_pytorch_model.safetensors"), b"unet")?;
+        let vae = root.join("vae.safetensors");
+        std::fs::write(&vae, b"vae")?;
+
+        let source = ModelSource {
+            overrides: ModelFileOverrides { vae: Some(vae.to_string_lossy().to_string()), ..Default::default() },
+            model_dir: Some(model_dir.clone()),
+            cache_dir: Some(root.join("cache")),
+            offline: true,
+            ..Default::default()
+        };
+        let sd = StableDiffusion1_5{};
+
+        let unet = sd.resolve_from(&StableDiffusionFiles::Unet, false, &source)?;
+        assert_eq!(unet.path, model_dir.join("unet/diffusion_pytorch_model.safetensors"));
+        assert!(unet.repo.is_none());
+        assert_eq!(sd.resolve_from(&StableDiffusionFiles::Vae, false, &source)?.path, vae);
+
+        // neither local nor cached, offline never reaches the hub
+        let clip = sd.resolve_from(&StableDiffusionFiles::Clip, false, &source);
+        assert!(clip.unwrap_err().to_string().contains("--offline"));
+
+        let missing = ModelSource { overrides: ModelFileOverrides { unet: Some("missing.safetensors".to_string()), ..Default::default() }, ..source };
+        assert!(sd.resolve_from(&StableDiffusionFiles::Unet, false, &missing).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sd_files_resolve_diffusers_folder() -> Result<()> {
+        let root = crate::test_utils::TempDir::new("diffusers-files")?;
+        let model_dir = root.join("model");
+        std::fs::create_dir_all(model_dir.join("unet"))?;
+        std::fs::write(model_dir.join(diffusers_folder::MODEL_INDEX_FILE), b"{\"_class_name\": \"StableDiffusionPipeline\"}")?;
+        std::fs::write(model_dir.join("unet/diffusion_pytorch_model.fp16.safetensors"), b"unet")?;
+
+        let source = ModelSource { model_dir: Some(model_dir.clone()), cache_dir: Some(root.join("cache")), offline: true, ..Default::default() };
+        let sd = StableDiffusion1_5{};
+
+        let unet = sd.resolve_from(&StableDiffusionFiles::Unet, false, &source)?;
+        assert_eq!(unet.path, model_dir.join("unet/diffusion_pytorch_model.fp16.safetensors"));
+        // missing weights never come from the hub, tokenizers do
+        assert!(sd.resolve_from(&StableDiffusionFiles::Clip, false, &source).unwrap_err().to_string().contains("text_encoder/"));
+        assert!(sd.resolve_from(&StableDiffusionFiles::Tokenizer, true, &source).unwrap_err().to_string().contains("--offline"));
+        Ok(())
+    }
+}
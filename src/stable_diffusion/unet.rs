@@ -1,19 +1,41 @@
 use candle_transformers::models::stable_diffusion::{self, unet_2d::UNet2DConditionModel};
-use candle_core::{Device, DType};
+use candle_core::{Device, DType, Tensor};
 use anyhow;
 
-use crate::stable_diffusion::stable_diffusion_files;
+use crate::stable_diffusion::{sdxl_unet, stable_diffusion_files};
 
-pub fn get_unet(unet_file: Option<String>, sd_version: &stable_diffusion_files::StableDiffusionVersion, stable_diffusion_config: &stable_diffusion::StableDiffusionConfig, device: &Device, dtype: DType, use_flash_attn: bool) -> anyhow::Result<UNet2DConditionModel>{
+/// UNet of a version, the SDXL based ones are also conditioned on the pooled text embeddings and the time ids
+pub enum Unet {
+    Plain(UNet2DConditionModel),
+    Sdxl(sdxl_unet::SdxlUNet),
+}
+
+impl Unet {
+    /// `added` is required by the SDXL UNet and ignored by the others
+    pub fn forward(&self, xs: &Tensor, timestep: f64, encoder_hidden_states: &Tensor, added: Option<&sdxl_unet::AddedConditioning>) -> anyhow::Result<Tensor> {
+        match (self, added) {
+            (Unet::Plain(unet), _) => Ok(unet.forward(xs, timestep, encoder_hidden_states)?),
+            (Unet::Sdxl(unet), Some(added)) => Ok(unet.forward(xs, timestep, encoder_hidden_states, added)?),
+            (Unet::Sdxl(_), None) => anyhow::bail!("The SDXL UNet requires the pooled text embeddings and the time ids")
+        }
+    }
+}
+
+pub fn get_unet(unet_file: Option<String>, sd_version: &stable_diffusion_files::StableDiffusionVersion, stable_diffusion_config: &stable_diffusion::StableDiffusionConfig, device: &Device, dtype: DType, use_flash_attn: bool) -> anyhow::Result<Unet>{
 
     let unet = stable_diffusion_files::StableDiffusionFiles::Unet;
     
     let sd = stable_diffusion_files::create_sd_from_version(sd_version);
-    let unet_weights_file = sd.get(&unet, unet_file, true)?;
+    let unet_weights_file = sd.get(&unet, unet_file, dtype == DType::F16)?;
 
-    let unet = stable_diffusion_config.build_unet(unet_weights_file, device, 4, use_flash_attn, dtype)?;
+    if sd_version.has_second_text_encoder() {
+        let vs = unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[unet_weights_file], dtype, device)? };
+        let unet = sdxl_unet::SdxlUNet::new(vs, sd_version.unet_in_channels(), 4, use_flash_attn)?;
+        return Ok(Unet::Sdxl(unet));
+    }
+    let unet = stable_diffusion_config.build_unet(unet_weights_file, device, sd_version.unet_in_channels(), use_flash_attn, dtype)?;
 
-    Ok(unet)
+    Ok(Unet::Plain(unet))
 }
 
 
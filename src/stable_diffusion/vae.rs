@@ -1,14 +1,15 @@
 use anyhow;
-use candle_transformers::models::stable_diffusion::{self, vae::AutoEncoderKL};
-use candle_core::{Device, DType};
+use candle_transformers::models::stable_diffusion::{self, vae::{AutoEncoderKL, AutoEncoderKLConfig}};
+use candle_transformers::models::stable_diffusion::unet_2d_blocks::{DownEncoderBlock2D, DownEncoderBlock2DConfig, UNetMidBlock2D, UNetMidBlock2DConfig};
+use candle_core::{Device, DType, Tensor};
 
-use crate::stable_diffusion::stable_diffusion_files;
+use crate::stable_diffusion::{latents, stable_diffusion_files};
 
 pub fn get_vae(vae_file: Option<String>, sd_version: &stable_diffusion_files::StableDiffusionVersion, stable_diffusion_config: &stable_diffusion::StableDiffusionConfig, device: &Device, dtype: DType) -> anyhow::Result<AutoEncoderKL>{
 
     let vae = stable_diffusion_files::StableDiffusionFiles::Vae;
     let sd = stable_diffusion_files::create_sd_from_version(sd_version);
-    let vae_weights_file = sd.get(&vae, vae_file, true)?;
+    let vae_weights_file = sd.get(&vae, vae_file, dtype == DType::F16)?;
 
     let vae = stable_diffusion_config.build_vae(vae_weights_file, &device, dtype)?;
 
@@ -16,12 +17,110 @@ pub fn get_vae(vae_file: Option<String>, sd_version: &stable_diffusion_files::St
 
 }
 
+/// Encoder half of the VAE, built from the same blocks as the one of candle.
+/// candle keeps the mean and deviation of the posterior private and samples it from the device RNG,
+/// which cannot be seeded on CPU, so img2img encodes its init image with this one instead
+pub struct VaeEncoder {
+    conv_in: candle_nn::Conv2d,
+    down_blocks: Vec<DownEncoderBlock2D>,
+    mid_block: UNetMidBlock2D,
+    conv_norm_out: candle_nn::GroupNorm,
+    conv_out: candle_nn::Conv2d,
+    quant_conv: candle_nn::Conv2d,
+}
+
+/// Diagonal gaussian the VAE encodes an image to
+pub struct Posterior {
+    pub mean: Tensor,
+    pub std: Tensor,
+}
+
+impl VaeEncoder {
+    /// Same shape as the encoder of the AutoEncoderKL candle builds with `config`
+    pub fn new(vs: candle_nn::VarBuilder, config: &AutoEncoderKLConfig) -> candle_core::Result<Self> {
+        let block_out_channels = &config.block_out_channels;
+        let conv_config = candle_nn::Conv2dConfig { padding: 1, ..Default::default() };
+        let encoder = vs.pp("encoder");
+        let conv_in = candle_nn::conv2d(3, block_out_channels[0], 3, conv_config, encoder.pp("conv_in"))?;
+        let mut down_blocks = vec![];
+        for (index, out_channels) in block_out_channels.iter().enumerate() {
+            let in_channels = block_out_channels[index.saturating_sub(1)];
+            let block_config = DownEncoderBlock2DConfig {
+                num_layers: config.layers_per_block,
+                resnet_eps: 1e-6,
+                resnet_groups: config.norm_num_groups,
+                add_downsample: index + 1 < block_out_channels.len(),
+                downsample_padding: 0,
+                ..Default::default()
+            };
+            down_blocks.push(DownEncoderBlock2D::new(encoder.pp(format!("down_blocks.{}", index)), in_channels, *out_channels, block_config)?);
+        }
+        let channels = block_out_channels[block_out_channels.len() - 1];
+        let mid_config = UNetMidBlock2DConfig {
+            resnet_eps: 1e-6,
+            output_scale_factor: 1.,
+            attn_num_head_channels: None,
+            resnet_groups: Some(config.norm_num_groups),
+            ..Default::default()
+        };
+        let mid_block = UNetMidBlock2D::new(encoder.pp("mid_block"), channels, None, mid_config)?;
+        let conv_norm_out = candle_nn::group_norm(config.norm_num_groups, channels, 1e-6, encoder.pp("conv_norm_out"))?;
+        // the mean and the log variance
+        let latent_channels = config.latent_channels;
+        let conv_out = candle_nn::conv2d(channels, 2 * latent_channels, 3, conv_config, encoder.pp("conv_out"))?;
+        let quant_conv = candle_nn::conv2d(2 * latent_channels, 2 * latent_channels, 1, Default::default(), vs.pp("quant_conv"))?;
+        Ok(Self { conv_in, down_blocks, mid_block, conv_norm_out, conv_out, quant_conv })
+    }
+
+    pub fn encode(&self, image: &Tensor) -> candle_core::Result<Posterior> {
+        let mut xs = image.apply(&self.conv_in)?;
+        for down_block in self.down_blocks.iter() {
+            xs = xs.apply(down_block)?;
+        }
+        let xs = self.mid_block.forward(&xs, None)?.apply(&self.conv_norm_out)?;
+        let parameters = candle_nn::ops::silu(&xs)?.apply(&self.conv_out)?.apply(&self.quant_conv)?;
+        let parameters = parameters.chunk(2, 1)?;
+        let std = (&parameters[1] * 0.5)?.exp()?;
+        Ok(Posterior { mean: parameters[0].clone(), std })
+    }
+}
+
+impl Posterior {
+    /// One sample per seed, each drawn from the posterior noise stream of the seed
+    pub fn sample(&self, seeds: &[u64]) -> anyhow::Result<Tensor> {
+        let (_, c, h, w) = self.mean.dims4()?;
+        let samples = seeds
+            .iter()
+            .map(|seed| {
+                let noise = latents::rng_noise(&mut latents::seeded_rng(*seed, latents::NoiseStream::Posterior), (1, c, h, w), self.mean.device())?
+                    .to_dtype(self.mean.dtype())?;
+                Ok((&self.mean + (&self.std * noise)?)?)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Tensor::cat(&samples, 0)?)
+    }
+}
+
+/// Encoder of the weights of `vae`, only img2img and inpainting need it
+pub fn get_vae_encoder(vae_file: Option<String>, sd_version: &stable_diffusion_files::StableDiffusionVersion, vae: &AutoEncoderKL, device: &Device, dtype: DType) -> anyhow::Result<VaeEncoder> {
+    let vae = stable_diffusion_files::StableDiffusionFiles::Vae;
+    let sd = stable_diffusion_files::create_sd_from_version(sd_version);
+    let vae_weights_file = sd.get(&vae, vae_file, dtype == DType::F16)?;
+
+    let vs = unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[vae_weights_file], dtype, device)? };
+    Ok(VaeEncoder::new(vs, &vae.config)?)
+}
+
 pub fn get_vae_scale(sd_version: &stable_diffusion_files::StableDiffusionVersion) -> f64{
     match sd_version {
         stable_diffusion_files::StableDiffusionVersion::V1_5
          | stable_diffusion_files::StableDiffusionVersion::V2_1
-         | stable_diffusion_files::StableDiffusionVersion::Xl => 0.18215,
-         stable_diffusion_files::StableDiffusionVersion::Turbo => 0.13025,
+         | stable_diffusion_files::StableDiffusionVersion::V1_5Inpaint
+         | stable_diffusion_files::StableDiffusionVersion::V2Inpaint => 0.18215,
+         // the scaling_factor of the vae/config.json of the SDXL repositories
+         stable_diffusion_files::StableDiffusionVersion::Xl
+         | stable_diffusion_files::StableDiffusionVersion::Turbo
+         | stable_diffusion_files::StableDiffusionVersion::XlInpaint => 0.13025,
     }
 }
 
@@ -40,4 +139,25 @@ mod tests {
         assert!(vae.is_ok());
 
     }
+
+    #[test]
+    fn vae_scale() {
+        assert_eq!(get_vae_scale(&stable_diffusion_files::StableDiffusionVersion::V1_5Inpaint), 0.18215);
+        assert_eq!(get_vae_scale(&stable_diffusion_files::StableDiffusionVersion::XlInpaint), get_vae_scale(&stable_diffusion_files::StableDiffusionVersion::Xl));
+    }
+
+    #[test]
+    fn vae_posterior_sample_is_seeded() -> anyhow::Result<()> {
+        let posterior = Posterior {
+            mean: Tensor::zeros((1, 4, 2, 2), DType::F32, &Device::Cpu)?,
+            std: Tensor::ones((1, 4, 2, 2), DType::F32, &Device::Cpu)?,
+        };
+        let first = posterior.sample(&[5, 6])?;
+        let second = posterior.sample(&[6])?;
+
+        assert_eq!(first.dims(), &[2, 4, 2, 2]);
+        assert_eq!(first.narrow(0, 1, 1)?.flatten_all()?.to_vec1::<f32>()?, second.flatten_all()?.to_vec1::<f32>()?);
+        assert_ne!(first.narrow(0, 0, 1)?.flatten_all()?.to_vec1::<f32>()?, second.flatten_all()?.to_vec1::<f32>()?);
+        Ok(())
+    }
 }
\ No newline at end of file
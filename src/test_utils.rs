@@ -0,0 +1,33 @@
+use std::path::{Path, PathBuf};
+
+/// Directory of a test in the temporary directory, removed with its content when dropped,
+/// so that a failing assert leaves nothing behind. Named after the process to keep parallel runs apart
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("fantacat-{}-{}", std::process::id(), name));
+        // left over by a killed run that had the same pid
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}